@@ -0,0 +1,213 @@
+// NSW index shared by threads which search and insert concurrently.
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::error::NNSearchError;
+use crate::filter::IdFilter;
+use crate::graph::{GraphOperator, Neighbor, SearchParams, VectorNode};
+use crate::index::{validate_dim, NSWIndex, VectorIndexOperator};
+use crate::metadata::Metadata;
+
+/// Wraps `NSWIndex` to be used through `&self` from many threads.
+///
+/// Searches share a read lock. An insertion searches the neighbors of the new node under the read lock
+/// and takes the write lock only to connect the node, so searches are blocked only for a short time.
+///
+/// A panic while modifying the index may leave the graph half-linked,
+/// so every operation after such a panic fails with `Poisoned`.
+pub struct ConcurrentNSWIndex {
+    index: RwLock<NSWIndex>,
+    next_id: AtomicUsize,
+}
+
+impl ConcurrentNSWIndex {
+    pub fn new(index: NSWIndex) -> Self {
+        let next_id = AtomicUsize::new(index.next_id);
+        ConcurrentNSWIndex {
+            index: RwLock::new(index),
+            next_id,
+        }
+    }
+
+    pub fn into_inner(self) -> Result<NSWIndex, NNSearchError> {
+        self.index.into_inner().map_err(|_| NNSearchError::Poisoned)
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, NSWIndex>, NNSearchError> {
+        self.index.read().map_err(|_| NNSearchError::Poisoned)
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, NSWIndex>, NNSearchError> {
+        self.index.write().map_err(|_| NNSearchError::Poisoned)
+    }
+
+    /// Adds the vector and returns the id assigned to it.
+    pub fn add(&self, data: Vec<f32>) -> Result<usize, NNSearchError> {
+        self.add_with_metadata(data, Metadata::new())
+    }
+
+    /// Adds the vector with the metadata and returns the id assigned to it.
+    /// Fails with `NotTrained` if the index is quantized and not trained, so train it before sharing.
+    pub fn add_with_metadata(&self, data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError> {
+        let node = {
+            let index = self.read()?;
+            validate_dim(index.dim(), &data)?;
+            index.graph.validate_trained()?;
+            index.graph.validate_encodable(&data)?;
+            VectorNode::with_metadata(self.next_id.fetch_add(1, Ordering::SeqCst), data, metadata)
+        };
+        let nn_ids = self.search_nn_ids(&*self.read()?, &node)?;
+        let mut index = self.write()?;
+        let min_degree = index.graph.min_degree;
+        // NOTE: neighbors may have been removed, or the graph may have had too few nodes, while searching.
+        let mut nn_ids: Vec<usize> = nn_ids.into_iter().filter(|id| index.graph.get_node(id).is_some()).collect();
+        if nn_ids.len() < min_degree {
+            nn_ids = self.search_nn_ids(&index, &node)?;
+        }
+        let id = node.id;
+        index.graph.link_node(node, nn_ids)?;
+        index.next_id = index.next_id.max(id + 1);
+        Ok(id)
+    }
+
+    fn search_nn_ids(&self, index: &NSWIndex, node: &VectorNode) -> Result<Vec<usize>, NNSearchError> {
+        Ok(index.graph.search_nearest_neighbor(node, index.graph.min_degree)?
+            .iter()
+            .map(|nn| nn.id)
+            .collect())
+    }
+
+    pub fn remove(&self, id: usize) -> Result<(), NNSearchError> {
+        self.write()?.remove(id)
+    }
+
+    pub fn compact(&self) -> Result<(), NNSearchError> {
+        self.write()?.compact();
+        Ok(())
+    }
+
+    pub fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.read()?.search(query, k)
+    }
+
+    pub fn search_with_params(&self, query: Vec<f32>, k: usize, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.read()?.search_with_params(query, k, params)
+    }
+
+    pub fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.read()?.search_filtered(query, k, filter)
+    }
+
+    pub fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.read()?.search_radius(query, radius)
+    }
+
+    /// Returns a copy of the vector since the index may be modified after returning.
+    pub fn get_vector(&self, id: usize) -> Result<Option<Vec<f32>>, NNSearchError> {
+        Ok(self.read()?.get_vector(id).map(|vec| vec.to_vec()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), NNSearchError> {
+        self.read()?.save(path)
+    }
+
+    pub fn dim(&self) -> Result<usize, NNSearchError> {
+        Ok(self.read()?.dim())
+    }
+
+    pub fn len(&self) -> Result<usize, NNSearchError> {
+        Ok(self.read()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, NNSearchError> {
+        Ok(self.len()? == 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::NaiveKnnIndex;
+    use crate::linalg::distance::{Euclidean, PairwiseDistance};
+    use crate::linalg::utils::generate_matrix;
+
+    fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+
+    #[test]
+    fn test_trait_objects_are_send_and_sync() {
+        assert_send_sync::<dyn VectorIndexOperator>();
+        assert_send_sync::<dyn GraphOperator>();
+        assert_send_sync::<dyn PairwiseDistance<f32, f32>>();
+        assert_send_sync::<ConcurrentNSWIndex>();
+    }
+
+    #[test]
+    fn test_concurrent_add_and_search() {
+        let mat = generate_matrix(1000, 4);
+        let index = ConcurrentNSWIndex::new(NSWIndex::new(4, Box::new(Euclidean{}), 3, 8));
+        std::thread::scope(|scope| {
+            for chunk in mat.chunks(250) {
+                let index = &index;
+                scope.spawn(move || {
+                    for vec in chunk {
+                        index.add(vec.clone()).unwrap();
+                    }
+                });
+            }
+            for query in mat.iter().take(4) {
+                let index = &index;
+                scope.spawn(move || {
+                    for _ in 0..50 {
+                        let k = index.len().unwrap().min(5);
+                        if k > 0 {
+                            assert_eq!(index.search(query.clone(), k).unwrap().len(), k);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(index.len().unwrap(), 1000);
+        assert_eq!(index.add(vec![0.1]).unwrap_err(), NNSearchError::DimensionMismatch {expected: 4, actual: 1});
+
+        // every vector is stored once with a unique id
+        let mut stored: Vec<Vec<f32>> = (0..1000).map(|id| index.get_vector(id).unwrap().unwrap()).collect();
+        let mut expected = mat.clone();
+        stored.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        expected.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(stored, expected);
+
+        let mut naive = NaiveKnnIndex::new(4, Box::new(Euclidean{}));
+        naive.add_batch(stored).unwrap();
+        let mut num_hit = 0;
+        for query in mat.iter().take(50) {
+            let expected: Vec<Vec<f32>> = naive.search(query.clone(), 10).unwrap().iter().map(|nn| naive.get_vector(nn.id).unwrap().to_vec()).collect();
+            let result = index.search(query.clone(), 10).unwrap();
+            num_hit += result.iter().filter(|nn| expected.contains(&index.get_vector(nn.id).unwrap().unwrap())).count();
+        }
+        let recall = num_hit as f32 / 500.0;
+        assert!(recall > 0.8, "recall={}", recall);
+
+        index.remove(3).unwrap();
+        assert_eq!(index.add(vec![0.1, 0.2, 0.3, 0.4]).unwrap(), 1000);
+        assert_eq!(index.into_inner().unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn test_poisoned_index() {
+        let index = ConcurrentNSWIndex::new(NSWIndex::new(2, Box::new(Euclidean{}), 3, 4));
+        index.add(vec![0.1, 0.2]).unwrap();
+        std::thread::scope(|scope| {
+            let index = &index;
+            let writer = scope.spawn(move || {
+                let _guard = index.index.write().unwrap();
+                panic!("writer panicked");
+            });
+            assert!(writer.join().is_err());
+        });
+        assert_eq!(index.search(vec![0.1, 0.2], 1).unwrap_err(), NNSearchError::Poisoned);
+        assert_eq!(index.add(vec![0.1, 0.2]).unwrap_err(), NNSearchError::Poisoned);
+        assert_eq!(index.len().unwrap_err(), NNSearchError::Poisoned);
+        assert_eq!(index.into_inner().err(), Some(NNSearchError::Poisoned));
+    }
+}
@@ -1,4 +1,3 @@
-
 use thiserror::Error;
 
 
@@ -6,4 +5,26 @@ use thiserror::Error;
 pub enum NNSearchError {
     #[error("ValueError: {0}")]
     ValueError(String),
+    #[error("IoError: {0}")]
+    IoError(String),
+    #[error("DimensionMismatch: expected {expected}, actual {actual}")]
+    DimensionMismatch { expected: usize, actual: usize },
+    #[error("DuplicateId: {0}")]
+    DuplicateId(usize),
+    #[error("DuplicateKey: {0}")]
+    DuplicateKey(String),
+    #[error("EmptyIndex: no items are indexed")]
+    EmptyIndex,
+    #[error("NotFound: {0}")]
+    NotFound(usize),
+    #[error("KeyNotFound: {0}")]
+    KeyNotFound(String),
+    #[error("KTooLarge: k={k} exceeds the number of indexed items {len}")]
+    KTooLarge { k: usize, len: usize },
+    #[error("ReadOnly: the index cannot be modified")]
+    ReadOnly,
+    #[error("NotTrained: the index must be trained before adding items")]
+    NotTrained,
+    #[error("Poisoned: the index may be inconsistent after a panic in another thread")]
+    Poisoned,
 }
@@ -0,0 +1,91 @@
+// Filters restricting the ids returned by the search.
+
+/// Decides whether an id is allowed to be returned by the search.
+pub trait IdFilter {
+    fn allows(&self, id: usize) -> bool;
+}
+
+impl<F: Fn(usize) -> bool> IdFilter for F {
+    fn allows(&self, id: usize) -> bool {
+        self(id)
+    }
+}
+
+/// Set of allowed ids stored as bits.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IdBitSet {
+    words: Vec<u64>,
+}
+
+impl IdBitSet {
+    pub fn new() -> Self {
+        IdBitSet {words: vec![]}
+    }
+
+    pub fn insert(&mut self, id: usize) {
+        let (word, bit) = (id / 64, id % 64);
+        if self.words.len() <= word {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << bit;
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        if let Some(word) = self.words.get_mut(id / 64) {
+            *word &= !(1 << (id % 64));
+        }
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.words.get(id / 64).is_some_and(|word| word & (1 << (id % 64)) != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
+}
+
+impl std::iter::FromIterator<usize> for IdBitSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut bitset = IdBitSet::new();
+        for id in iter {
+            bitset.insert(id);
+        }
+        bitset
+    }
+}
+
+impl IdFilter for IdBitSet {
+    fn allows(&self, id: usize) -> bool {
+        self.contains(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_bitset() {
+        let mut bitset: IdBitSet = vec![0, 3, 64, 200].into_iter().collect();
+        assert_eq!(bitset.len(), 4);
+        assert!(bitset.allows(3) && bitset.allows(64) && bitset.allows(200));
+        assert!(!bitset.allows(1) && !bitset.allows(65) && !bitset.allows(10000));
+        bitset.remove(3);
+        bitset.remove(10000);
+        assert!(!bitset.contains(3));
+        assert_eq!(bitset.len(), 3);
+        assert!(IdBitSet::new().is_empty());
+    }
+
+    #[test]
+    fn test_predicate_filter() {
+        let filter = |id: usize| id < 5;
+        assert!(filter.allows(4));
+        assert!(!filter.allows(5));
+    }
+}
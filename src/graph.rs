@@ -1,46 +1,97 @@
+use crate::error::NNSearchError;
+use crate::filter::IdFilter;
 use crate::linalg::distance::{PairwiseDistance};
 use crate::linalg::utils::get_rng;
-use rand::seq::SliceRandom;
+use crate::metadata::Metadata;
+use crate::quantizer::ScalarQuantizedVectors;
+use rand::Rng;
+use rand::rngs::SmallRng;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet, BTreeSet};
+use std::sync::Mutex;
 
 #[derive(Debug)]
 pub struct VectorNode {
     pub id: usize,
     pub vec: Vec<f32>,
+    pub metadata: Metadata,
 }
 
-#[derive(Debug)]
+impl VectorNode {
+    pub fn new(id: usize, vec: Vec<f32>) -> Self {
+        VectorNode {id, vec, metadata: Metadata::new()}
+    }
+
+    pub fn with_metadata(id: usize, vec: Vec<f32>, metadata: Metadata) -> Self {
+        VectorNode {id, vec, metadata}
+    }
+}
+
+/// An item found by the search with its distance to the query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Neighbor {
+    pub id: usize,
+    pub distance: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
 struct CostedItem {
     pub id: usize,
     pub cost: f32,
 }
 
 impl Ord for CostedItem {
+    // NOTE: tie-break by id so that items with the same cost are not merged in BTreeSet.
+    // The total order puts NaN costs after all the others instead of failing to compare them.
     fn cmp(&self, other: &Self) -> Ordering {
-        match self.partial_cmp(&other) {
-            Some(order) => order,
-            None => {
-                panic!("Fail to compare item: self={:?}, other={:?}", self, other);
-            }
-        }
+        self.cost.total_cmp(&other.cost).then(self.id.cmp(&other.id))
     }
 }
 
 impl PartialOrd for CostedItem {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.cost.partial_cmp(&other.cost)
+        Some(self.cmp(other))
     }
 }
 
 impl PartialEq for CostedItem {
     fn eq(&self, other: &Self) -> bool {
-        self.cost == other.cost && self.id == other.id
+        self.cmp(other) == Ordering::Equal
     }
 }
 
 impl Eq for CostedItem {}
 
+/// Parameters of a single search to trade recall for latency.
+/// `None` falls back to the setting of the index, so `SearchParams::default()` searches the same way as `search`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SearchParams {
+    /// Number of searches from random entry points on NSW (`trial` of the index by default). Not used by HNSW.
+    pub restarts: Option<usize>,
+    /// Size of the candidate list (k on NSW and `ef_search` on HNSW by default). Values smaller than k are raised to k.
+    pub ef: Option<usize>,
+    /// Seed of the random entry points on NSW.
+    pub seed: u64,
+    /// Maximum number of distance evaluations, after which the best neighbors found so far are returned.
+    /// Only the evaluations on the bottom layer are counted on HNSW.
+    pub max_distance_evals: Option<usize>,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        SearchParams {restarts: None, ef: None, seed: 46, max_distance_evals: None}
+    }
+}
+
+impl From<CostedItem> for Neighbor {
+    fn from(item: CostedItem) -> Self {
+        Neighbor {id: item.id, distance: item.cost}
+    }
+}
+
 #[derive(Debug)]
 struct DistanceCache {
     pub cache: HashMap<usize, f32>
@@ -53,9 +104,13 @@ impl DistanceCache {
         }
     }
 
-    #[allow(clippy::borrowed_box)] fn get_distance(&mut self, distance: &Box<dyn PairwiseDistance<f32, f32>>, query: &VectorNode, target: &VectorNode) -> f32 {
-        self.cache.entry(target.id).or_insert_with(|| distance.compute(&query.vec, &target.vec).unwrap());
-        *self.cache.get(&target.id).unwrap()
+    fn get_distance<G: NSWGraphView + ?Sized>(&mut self, graph: &G, query: &[f32], target_id: usize) -> Result<f32, NNSearchError> {
+        if let Some(cost) = self.cache.get(&target_id) {
+            return Ok(*cost)
+        }
+        let cost = graph.cost(query, target_id)?;
+        self.cache.insert(target_id, cost);
+        Ok(cost)
     }
 }
 
@@ -66,114 +121,1190 @@ pub struct NavigableSmallWorldGraph {
     pub id2adjacency_ids: HashMap<usize, Vec<usize>>,
     pub id2node: HashMap<usize, VectorNode>,
     pub distance: Box<dyn PairwiseDistance<f32, f32>>,
+    /// Removed nodes which are kept to be traversed until `compact` is called.
+    pub tombstones: HashSet<usize>,
+    /// Quantized codes of the nodes, on which distances are computed if given.
+    /// The vectors of the nodes are left empty unless the full precision vectors are kept for reranking.
+    pub quantized: Option<ScalarQuantizedVectors>,
+    /// Ids of the nodes except the removed ones, from which entry points of search are sampled.
+    live_ids: Vec<usize>,
+    /// Positions of the ids in `live_ids` to remove them by swap-remove.
+    live_id2pos: HashMap<usize, usize>,
 }
 
-
 impl NavigableSmallWorldGraph {
-    fn approx_knn_search(&self, query: &VectorNode, k: usize) -> Vec<usize> {
-        if self.id2node.len() <= k {
-            // FIXME: notify that returned result is not satisfied with size k.
-            let mut incomplete_result: Vec<usize> = self.id2node.keys().cloned().collect();
-            incomplete_result.sort_by(
-                |&a, &b|
-                self.distance.compute(&query.vec, &self.get_node(&a).unwrap().vec).unwrap()
-                    .partial_cmp(&self.distance.compute(&query.vec, &self.get_node(&b).unwrap().vec).unwrap()).unwrap());
-            return incomplete_result
-        }
-        // The algorithm here is based on https://publications.hse.ru/mirror/pubs/share/folder/x5p6h7thif/direct/128296059
-        let mut rng = get_rng(46);
-        let mut candidates: BTreeSet<CostedItem> = BTreeSet::new();
-        let mut visited = HashSet::new();
-        let mut result: BTreeSet<CostedItem> = BTreeSet::new();
-        let mut dist_cache = DistanceCache::new();
-        let ids: Vec<&usize> = self.id2node.iter().map(|(k, _v)| k).collect();
-        for _i in 0..self.trial {
-            let entry_id = &(*ids.choose(&mut rng).unwrap()).clone();
-            candidates.insert(CostedItem {id: *entry_id, cost: dist_cache.get_distance(&self.distance, &query, self.get_node(&entry_id).unwrap())});
-            let mut temp_res = HashSet::new();
-            loop {
-                let c = candidates.pop_first();
-                if c.is_none() {
-                    break
-                }
-                let c = c.unwrap();
-                if result.len() >= k {
-                    let kth_dist = dist_cache.get_distance(&self.distance, &query, self.get_node(&result.iter().nth(k-1).unwrap().id).unwrap());
-                    if kth_dist <= c.cost {
-                        break
-                    }
+    pub fn new(distance: Box<dyn PairwiseDistance<f32, f32>>, trial: usize, min_degree: usize) -> Self {
+        NavigableSmallWorldGraph {
+            trial,
+            min_degree,
+            id2adjacency_ids: HashMap::new(),
+            id2node: HashMap::new(),
+            distance,
+            tombstones: HashSet::new(),
+            quantized: None,
+            live_ids: vec![],
+            live_id2pos: HashMap::new(),
+        }
+    }
+
+    fn insert_live_id(&mut self, id: usize) {
+        if !self.live_id2pos.contains_key(&id) {
+            self.live_id2pos.insert(id, self.live_ids.len());
+            self.live_ids.push(id);
+        }
+    }
+
+    fn remove_live_id(&mut self, id: usize) {
+        if let Some(pos) = self.live_id2pos.remove(&id) {
+            self.live_ids.swap_remove(pos);
+            if let Some(&moved_id) = self.live_ids.get(pos) {
+                self.live_id2pos.insert(moved_id, pos);
+            }
+        }
+    }
+
+    /// Rebuilds the ids of the live nodes after `id2node` or `tombstones` is modified directly.
+    pub(crate) fn reset_live_ids(&mut self) {
+        let tombstones = &self.tombstones;
+        self.live_ids = self.id2node.keys().cloned().filter(|id| !tombstones.contains(id)).collect();
+        self.live_id2pos = self.live_ids.iter().enumerate().map(|(pos, &id)| (id, pos)).collect();
+    }
+
+    /// Returns the vector of the node, which is decoded from its code if only the code is stored.
+    pub fn vector(&self, id: usize) -> Option<Cow<'_, [f32]>> {
+        let node = self.id2node.get(&id)?;
+        match &self.quantized {
+            Some(quantized) if node.vec.is_empty() => quantized.decode(id).map(Cow::Owned),
+            _ => Some(Cow::Borrowed(node.vec.as_slice())),
+        }
+    }
+
+    /// Returns the dimension of the vectors, which is unknown for the empty graph without quantization.
+    pub fn dim(&self) -> Option<usize> {
+        match &self.quantized {
+            Some(quantized) => Some(quantized.quantizer.dim()),
+            None => self.id2node.values().next().map(|node| node.vec.len()),
+        }
+    }
+
+    fn validate_dim(&self, expected: Option<usize>, node: &VectorNode) -> Result<(), NNSearchError> {
+        match expected {
+            Some(expected) if expected != node.vec.len() => Err(NNSearchError::DimensionMismatch {expected, actual: node.vec.len()}),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the distance from `query` to the node on its full precision vector if it is kept, and on its code otherwise.
+    pub(crate) fn exact_cost(&self, query: &[f32], id: usize) -> Result<f32, NNSearchError> {
+        match &self.quantized {
+            Some(quantized) if !quantized.keeps_vectors() => quantized.compute(&*self.distance, query, id),
+            _ => self.distance.compute(query, &self.id2node[&id].vec),
+        }
+    }
+
+    pub(crate) fn validate_trained(&self) -> Result<(), NNSearchError> {
+        match &self.quantized {
+            Some(quantized) if !quantized.quantizer.is_trained() => Err(NNSearchError::NotTrained),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that the quantizer can encode `vec`, so that a node is rejected before it is linked to the graph.
+    pub(crate) fn validate_encodable(&self, vec: &[f32]) -> Result<(), NNSearchError> {
+        match &self.quantized {
+            Some(quantized) => quantized.quantizer.validate(vec),
+            None => Ok(()),
+        }
+    }
+
+    /// Stores the node, encoding its vector and dropping the full precision one unless it is kept.
+    fn store_node(&mut self, mut node: VectorNode) -> Result<(), NNSearchError> {
+        if let Some(quantized) = &mut self.quantized {
+            quantized.insert(node.id, &node.vec)?;
+            if !quantized.keeps_vectors() {
+                node.vec = vec![];
+            }
+        }
+        if !self.tombstones.contains(&node.id) {
+            self.insert_live_id(node.id);
+        }
+        self.id2node.insert(node.id, node);
+        Ok(())
+    }
+
+    /// Replaces the vector of the node and reconnects it to the nearest nodes to the new vector.
+    /// A removed node is restored. Fails with `NotFound` if the node does not exist.
+    pub fn update_node(&mut self, node: VectorNode) -> Result<(), NNSearchError> {
+        if !self.id2node.contains_key(&node.id) {
+            return Err(NNSearchError::NotFound(node.id))
+        }
+        self.validate_dim(self.dim(), &node)?;
+        self.validate_encodable(&node.vec)?;
+        let id = node.id;
+        if !self.tombstones.contains(&id) {
+            // detach the node from its neighbors as if it was removed
+            self.remove_node(id)?;
+        }
+        // NOTE: the node stays tombstoned while searching the new neighbors so that it is not connected to itself.
+        self.id2adjacency_ids.remove(&id);
+        self.connect_node(node)?;
+        self.tombstones.remove(&id);
+        self.insert_live_id(id);
+        Ok(())
+    }
+
+    /// Adds the nodes in bulk, failing without adding any node if one of them is invalid.
+    /// After the graph gets enough nodes by sequential insertion, the remaining nodes are inserted concurrently
+    /// on threads with the `parallel` feature.
+    pub fn add_nodes(&mut self, nodes: Vec<VectorNode>) -> Result<(), NNSearchError> {
+        self.validate_trained()?;
+        let dim = self.dim().or_else(|| nodes.first().map(|node| node.vec.len()));
+        let mut new_ids = HashSet::new();
+        for node in &nodes {
+            if self.id2node.contains_key(&node.id) || !new_ids.insert(node.id) {
+                return Err(NNSearchError::DuplicateId(node.id))
+            }
+            self.validate_dim(dim, node)?;
+            self.validate_encodable(&node.vec)?;
+        }
+        let mut nodes = nodes.into_iter();
+        while self.len() < NUM_SEQUENTIAL_NODES.max(self.min_degree + 1) {
+            match nodes.next() {
+                Some(node) => self.connect_node(node)?,
+                None => return Ok(()),
+            }
+        }
+        let nodes: Vec<VectorNode> = nodes.collect();
+        if nodes.is_empty() {
+            return Ok(())
+        }
+
+        let mut ids: Vec<usize> = self.id2node.keys().cloned().collect();
+        ids.sort_unstable();
+        let num_entry_nodes = ids.len();
+        ids.extend(nodes.iter().map(|node| node.id));
+        let id2pos: HashMap<usize, usize> = ids.iter().enumerate().map(|(pos, &id)| (id, pos)).collect();
+        // NOTE: the new nodes are encoded in advance so that they can be reached as neighbors of each other.
+        if let Some(quantized) = &mut self.quantized {
+            for node in &nodes {
+                quantized.insert(node.id, &node.vec)?;
+            }
+        }
+        let graph = LockedNSWGraph {
+            trial: self.trial,
+            distance: &*self.distance,
+            quantized: self.quantized.as_ref(),
+            ids: &ids,
+            vecs: ids[..num_entry_nodes]
+                .iter()
+                .map(|id| self.id2node[id].vec.as_slice())
+                .chain(nodes.iter().map(|node| node.vec.as_slice()))
+                .collect(),
+            adjacency_positions: ids
+                .iter()
+                .map(|id| Mutex::new(self.adjacency_ids(*id).iter().map(|adjacency_id| id2pos[adjacency_id]).collect()))
+                .collect(),
+            removed: ids.iter().map(|id| self.is_removed(*id)).collect(),
+            num_entry_nodes,
+            num_live_entry_nodes: self.len(),
+        };
+        #[cfg(feature = "parallel")]
+        let positions = (num_entry_nodes..ids.len()).into_par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let mut positions = num_entry_nodes..ids.len();
+        positions.try_for_each(|pos| graph.insert(pos, self.min_degree))?;
+
+        let LockedNSWGraph {adjacency_positions, ..} = graph;
+        for (pos, adjacency_positions) in adjacency_positions.into_iter().enumerate() {
+            let adjacency_positions = adjacency_positions.into_inner().unwrap();
+            if !adjacency_positions.is_empty() {
+                self.id2adjacency_ids.insert(ids[pos], adjacency_positions.into_iter().map(|adjacency_pos| ids[adjacency_pos]).collect());
+            }
+        }
+        for node in nodes {
+            self.store_node(node)?;
+        }
+        Ok(())
+    }
+
+    /// Connects the node to its nearest nodes in both directions and stores it.
+    fn connect_node(&mut self, node: VectorNode) -> Result<(), NNSearchError> {
+        let nn_ids: Vec<usize> = self.search_nearest_neighbor(&node, self.min_degree)?
+            .iter()
+            .map(|nn| nn.id)
+            .collect();
+        self.link_node(node, nn_ids)
+    }
+
+    /// Connects the node to `nn_ids` found in advance in both directions and stores it.
+    pub(crate) fn link_node(&mut self, node: VectorNode, nn_ids: Vec<usize>) -> Result<(), NNSearchError> {
+        if nn_ids.is_empty() {
+            return self.store_node(node)
+        }
+        // connect node -> nn
+        self.id2adjacency_ids.insert(node.id, nn_ids.clone());
+        // connect nn -> node
+        nn_ids.iter().for_each(|nn_id|
+            {
+                if !self.id2adjacency_ids.contains_key(nn_id) {
+                    self.id2adjacency_ids.insert(*nn_id, vec![]);
                 }
-                for &id in self.id2adjacency_ids.get(&c.id).unwrap_or(&vec![]) {
-                    if !visited.contains(&id) {
-                        visited.insert(id);
-                        candidates.insert(CostedItem {id, cost: dist_cache.get_distance(&self.distance, &query, self.get_node(&id).unwrap())});
-                        temp_res.insert(id);
-                    }
+                self.id2adjacency_ids.get_mut(nn_id).unwrap().push(node.id)
+            }
+        );
+        self.store_node(node)
+    }
+}
+
+/// Read-only access to a navigable small world graph.
+/// This is shared by the in-memory graph and the memory-mapped one to run the same search algorithm.
+pub(crate) trait NSWGraphView {
+    fn trial(&self) -> usize;
+    fn distance(&self) -> &dyn PairwiseDistance<f32, f32>;
+    fn num_nodes(&self) -> usize;
+    /// Returns the ids of the nodes including the removed ones.
+    fn node_ids(&self) -> Box<dyn Iterator<Item = usize> + '_>;
+    /// Returns a node chosen uniformly at random as an entry point, which is called only when `num_nodes` is positive.
+    fn random_entry(&self, rng: &mut SmallRng) -> usize;
+    fn node_vec(&self, id: usize) -> &[f32];
+    /// Returns the distance from `query` to the node.
+    fn cost(&self, query: &[f32], id: usize) -> Result<f32, NNSearchError> {
+        self.distance().compute(query, self.node_vec(id))
+    }
+    fn adjacency_ids(&self, id: usize) -> Cow<'_, [usize]>;
+    /// Removed nodes are traversed but not returned.
+    fn is_removed(&self, _id: usize) -> bool {
+        false
+    }
+}
+
+impl NSWGraphView for NavigableSmallWorldGraph {
+    fn trial(&self) -> usize {
+        self.trial
+    }
+    fn distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &*self.distance
+    }
+    fn num_nodes(&self) -> usize {
+        self.id2node.len() - self.tombstones.len()
+    }
+    fn node_ids(&self) -> Box<dyn Iterator<Item = usize> + '_> {
+        Box::new(self.id2node.keys().cloned())
+    }
+    // NOTE: this walks the map without allocating, as a map has no random access.
+    fn random_entry(&self, rng: &mut SmallRng) -> usize {
+        self.live_ids[rng.gen_range(0..self.live_ids.len())]
+    }
+    fn node_vec(&self, id: usize) -> &[f32] {
+        &self.id2node[&id].vec
+    }
+    /// Computes the distance on the code of the node if quantized.
+    fn cost(&self, query: &[f32], id: usize) -> Result<f32, NNSearchError> {
+        match &self.quantized {
+            Some(quantized) => quantized.compute(&*self.distance, query, id),
+            None => self.distance.compute(query, &self.id2node[&id].vec),
+        }
+    }
+    fn adjacency_ids(&self, id: usize) -> Cow<'_, [usize]> {
+        Cow::Borrowed(self.id2adjacency_ids.get(&id).map(|ids| ids.as_slice()).unwrap_or(&[]))
+    }
+    fn is_removed(&self, id: usize) -> bool {
+        self.tombstones.contains(&id)
+    }
+}
+
+/// Returns at most `k` neighbors of `query`. Fewer neighbors are returned only when the graph has less than `k` nodes
+/// or less than `k` nodes are allowed by `filter`.
+/// Nodes not allowed by `filter` are traversed but not returned.
+pub(crate) fn approx_knn_search<G: NSWGraphView + ?Sized>(graph: &G, query: &[f32], k: usize, filter: Option<&dyn IdFilter>, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+    let admits = |id: usize| !graph.is_removed(id) && filter.is_none_or(|filter| filter.allows(id));
+    let ef = params.ef.unwrap_or(k).max(k);
+    if graph.num_nodes() <= ef {
+        let mut incomplete_result = graph.node_ids()
+            .filter(|&id| admits(id))
+            .map(|id| Ok(CostedItem {id, cost: graph.cost(query, id)?}))
+            .collect::<Result<Vec<_>, NNSearchError>>()?;
+        incomplete_result.sort();
+        return Ok(incomplete_result.into_iter().take(k).map(Neighbor::from).collect())
+    }
+    // The algorithm here is based on https://publications.hse.ru/mirror/pubs/share/folder/x5p6h7thif/direct/128296059
+    let mut rng = get_rng(params.seed);
+    let mut candidates: BTreeSet<CostedItem> = BTreeSet::new();
+    let mut visited = HashSet::new();
+    let mut result: BTreeSet<CostedItem> = BTreeSet::new();
+    let mut dist_cache = DistanceCache::new();
+    // NOTE: every evaluated node is cached, so the size of the cache is the number of distance evaluations.
+    let exhausted = |dist_cache: &DistanceCache| params.max_distance_evals.is_some_and(|max| dist_cache.cache.len() >= max);
+    for _i in 0..params.restarts.unwrap_or_else(|| graph.trial()) {
+        if exhausted(&dist_cache) {
+            break
+        }
+        let entry_id = graph.random_entry(&mut rng);
+        candidates.insert(CostedItem {id: entry_id, cost: dist_cache.get_distance(graph, query, entry_id)?});
+        let mut temp_res = HashSet::new();
+        loop {
+            let c = candidates.pop_first();
+            if c.is_none() {
+                break
+            }
+            let c = c.unwrap();
+            if result.len() >= ef {
+                let kth_id = result.iter().nth(ef-1).unwrap().id;
+                let kth_dist = dist_cache.get_distance(graph, query, kth_id)?;
+                if kth_dist <= c.cost {
+                    break
                 }
-                if !visited.contains(&c.id) {
-                    visited.insert(c.id);
-                    temp_res.insert(c.id);
+            }
+            for &id in graph.adjacency_ids(c.id).iter() {
+                if exhausted(&dist_cache) {
+                    break
                 }
-                for &id in &temp_res {
-                    result.insert(CostedItem {id, cost: dist_cache.get_distance(&self.distance, &query, self.get_node(&id).unwrap())});
+                if !visited.contains(&id) {
+                    visited.insert(id);
+                    candidates.insert(CostedItem {id, cost: dist_cache.get_distance(graph, query, id)?});
+                    temp_res.insert(id);
                 }
             }
+            if !visited.contains(&c.id) {
+                visited.insert(c.id);
+                temp_res.insert(c.id);
+            }
+            for &id in temp_res.iter().filter(|&&id| admits(id)) {
+                result.insert(CostedItem {id, cost: dist_cache.get_distance(graph, query, id)?});
+            }
+            if exhausted(&dist_cache) {
+                break
+            }
+        }
+    }
+    Ok(result.into_iter().take(k).map(Neighbor::from).collect())
+}
+
+/// Expands the graph best-first from `entry_points` through the nodes within `radius`,
+/// and returns the nodes within `radius` except the removed ones in ascending order of the distance.
+fn expand_within_radius<'a, A, C, R>(entry_points: &[CostedItem], radius: f32, adjacency_ids: A, cost: C, is_removed: R) -> Result<Vec<Neighbor>, NNSearchError>
+where
+    A: Fn(usize) -> Cow<'a, [usize]>,
+    C: Fn(usize) -> Result<f32, NNSearchError>,
+    R: Fn(usize) -> bool,
+{
+    let mut visited: HashSet<usize> = entry_points.iter().map(|item| item.id).collect();
+    let mut candidates: BTreeSet<CostedItem> = entry_points.iter().filter(|item| item.cost <= radius).cloned().collect();
+    let mut result: Vec<CostedItem> = candidates.iter().cloned().collect();
+    while let Some(c) = candidates.pop_first() {
+        for &id in adjacency_ids(c.id).iter() {
+            if !visited.insert(id) {
+                continue
+            }
+            let item = CostedItem {id, cost: cost(id)?};
+            if item.cost <= radius {
+                candidates.insert(item);
+                result.push(item);
+            }
         }
-        result.iter().map(|item| item.id).collect::<Vec<_>>()[..k].to_vec()
     }
+    result.sort();
+    Ok(result.into_iter().filter(|item| !is_removed(item.id)).map(Neighbor::from).collect())
 }
 
+/// Returns the nodes within `radius` from `query` by `cost` in ascending order of it.
+/// The search starts from the approximate nearest neighbors and expands through the nodes within `radius`.
+pub(crate) fn approx_radius_search<G, C>(graph: &G, query: &[f32], radius: f32, cost: C) -> Result<Vec<Neighbor>, NNSearchError>
+where
+    G: NSWGraphView + ?Sized,
+    C: Fn(usize) -> Result<f32, NNSearchError>,
+{
+    let entry_points = approx_knn_search(graph, query, graph.trial().clamp(1, graph.num_nodes().max(1)), None, &SearchParams::default())?
+        .into_iter()
+        .map(|nn| Ok(CostedItem {id: nn.id, cost: cost(nn.id)?}))
+        .collect::<Result<Vec<_>, NNSearchError>>()?;
+    expand_within_radius(
+        &entry_points,
+        radius,
+        |id| graph.adjacency_ids(id),
+        cost,
+        |id| graph.is_removed(id),
+    )
+}
 
-pub trait GraphOperator {
-    #[allow(clippy::result_unit_err)] fn add_node(&mut self, node: VectorNode) -> Result<(), ()>;
+
+pub trait GraphOperator: Send + Sync {
+    /// Adds a node, failing with `DuplicateId` if the id exists and `DimensionMismatch`
+    /// if the length of the vector differs from those of the existing nodes.
+    fn add_node(&mut self, node: VectorNode) -> Result<(), NNSearchError>;
     fn get_node(&self, id: &usize) -> Option<&VectorNode>;
-    fn search_nearest_neighbor(&self, query: &VectorNode, k: usize) -> Vec<usize>;
+    /// Returns at most `k` neighbors of `query` in ascending order of the distance.
+    fn search_nearest_neighbor(&self, query: &VectorNode, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_with_params(query, k, &SearchParams::default())
+    }
+    /// Same as `search_nearest_neighbor` with the restarts, the candidate list size, the seed and
+    /// the limit of distance evaluations given by `params`.
+    fn search_with_params(&self, query: &VectorNode, k: usize, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError>;
+    /// Returns at most `k` neighbors of `query` allowed by `filter` in ascending order of the distance.
+    /// Nodes not allowed by `filter` are still traversed so that enough neighbors are found under selective filters.
+    fn search_filtered(&self, query: &VectorNode, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError>;
+    /// Returns the nodes within `radius` from `query` in ascending order of the distance.
+    fn search_radius(&self, query: &VectorNode, radius: f32) -> Result<Vec<Neighbor>, NNSearchError>;
+    /// Marks the node as removed and repairs the edges of its neighbors, failing with `NotFound` if the node does not exist.
+    /// The removed node is excluded from the search results but kept in the graph until `compact` is called.
+    fn remove_node(&mut self, id: usize) -> Result<(), NNSearchError>;
+    /// Drops the removed nodes from the graph.
+    fn compact(&mut self);
+    /// Returns the number of the nodes except the removed ones.
     fn len(&self) -> usize;
     fn is_empty(&self) -> bool;
 }
 
+fn validate_node(id2node: &HashMap<usize, VectorNode>, node: &VectorNode) -> Result<(), NNSearchError> {
+    if id2node.contains_key(&node.id) {
+        return Err(NNSearchError::DuplicateId(node.id))
+    }
+    match id2node.values().next() {
+        Some(other) if other.vec.len() != node.vec.len() => {
+            Err(NNSearchError::DimensionMismatch {expected: other.vec.len(), actual: node.vec.len()})
+        }
+        _ => Ok(())
+    }
+}
+
 impl GraphOperator for NavigableSmallWorldGraph {
-    fn add_node(&mut self, node: VectorNode) -> Result<(), ()> {
-        if self.id2node.is_empty() {
-            self.id2node.insert(node.id, node);
-            return Ok(())
+    /// Fails with `NotTrained` if the quantizer is not trained.
+    fn add_node(&mut self, node: VectorNode) -> Result<(), NNSearchError> {
+        if self.id2node.contains_key(&node.id) {
+            return Err(NNSearchError::DuplicateId(node.id))
         }
-        // FIXME: handling the case where node.id is duplicated.
-        let nn_ids = self.search_nearest_neighbor(&node, self.min_degree);
-        // connect node -> nn
-        self.id2adjacency_ids.insert(node.id, nn_ids.clone());
+        self.validate_dim(self.dim(), &node)?;
+        self.validate_trained()?;
+        self.validate_encodable(&node.vec)?;
+        self.connect_node(node)
+    }
+    fn get_node(&self, id: &usize) -> Option<&VectorNode> {
+        self.id2node.get(id).filter(|_| !self.tombstones.contains(id))
+    }
+    fn search_with_params(&self, query: &VectorNode, k: usize, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        approx_knn_search(self, &query.vec, k, None, params)
+    }
+    fn search_filtered(&self, query: &VectorNode, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        approx_knn_search(self, &query.vec, k, Some(filter), &SearchParams::default())
+    }
+    fn search_radius(&self, query: &VectorNode, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        // NOTE: the graph is expanded by the exact distances so that no node within `radius` is cut off by the error of its code.
+        approx_radius_search(self, &query.vec, radius, |id| self.exact_cost(&query.vec, id))
+    }
+    fn remove_node(&mut self, id: usize) -> Result<(), NNSearchError> {
+        if self.get_node(&id).is_none() {
+            return Err(NNSearchError::NotFound(id))
+        }
+        self.tombstones.insert(id);
+        self.remove_live_id(id);
+        // NOTE: edges are bidirectional, so the neighbors are the nodes which have an edge to the removed node.
+        let nn_ids: Vec<usize> = self.adjacency_ids(id).iter().cloned().filter(|nn_id| !self.is_removed(*nn_id)).collect();
+        for &nn_id in &nn_ids {
+            self.id2adjacency_ids.get_mut(&nn_id).unwrap().retain(|&adjacency_id| adjacency_id != id);
+        }
+        // bypass the removed node by connecting each neighbor to the nearest other neighbor
+        for &nn_id in &nn_ids {
+            let vec = self.vector(nn_id).unwrap();
+            let adjacency_ids = self.adjacency_ids(nn_id);
+            let mut nearest: Option<CostedItem> = None;
+            for &other_id in nn_ids.iter().filter(|&&other_id| other_id != nn_id && !adjacency_ids.contains(&other_id)) {
+                let item = CostedItem {id: other_id, cost: self.cost(&vec, other_id)?};
+                if nearest.is_none_or(|nearest| item < nearest) {
+                    nearest = Some(item);
+                }
+            }
+            if let Some(nearest) = nearest {
+                self.id2adjacency_ids.get_mut(&nn_id).unwrap().push(nearest.id);
+                self.id2adjacency_ids.get_mut(&nearest.id).unwrap().push(nn_id);
+            }
+        }
+        Ok(())
+    }
+    fn compact(&mut self) {
+        for id in self.tombstones.drain() {
+            self.id2node.remove(&id);
+            self.id2adjacency_ids.remove(&id);
+        }
+        let id2node = &self.id2node;
+        for adjacency_ids in self.id2adjacency_ids.values_mut() {
+            adjacency_ids.retain(|adjacency_id| id2node.contains_key(adjacency_id));
+        }
+        if let Some(quantized) = &mut self.quantized {
+            quantized.retain(|id| id2node.contains_key(&id));
+        }
+    }
+    fn len(&self) -> usize {
+        self.num_nodes()
+    }
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+}
+
+
+// NOTE: nodes are inserted one by one until the graph has this number of nodes
+// so that the graph is navigable from its entry points before the concurrent insertion.
+const NUM_SEQUENTIAL_NODES: usize = 256;
+
+/// Navigable small world graph under concurrent insertion, where nodes are addressed by their positions.
+/// Each adjacency list has its own lock, so insertions contend only when they touch the same node.
+struct LockedNSWGraph<'a> {
+    trial: usize,
+    distance: &'a dyn PairwiseDistance<f32, f32>,
+    quantized: Option<&'a ScalarQuantizedVectors>,
+    ids: &'a [usize],
+    vecs: Vec<&'a [f32]>,
+    adjacency_positions: Vec<Mutex<Vec<usize>>>,
+    removed: Vec<bool>,
+    num_entry_nodes: usize,
+    num_live_entry_nodes: usize,
+}
+
+impl LockedNSWGraph<'_> {
+    fn insert(&self, pos: usize, min_degree: usize) -> Result<(), NNSearchError> {
+        let nn_positions: Vec<usize> = approx_knn_search(self, self.vecs[pos], min_degree, None, &SearchParams::default())?
+            .iter()
+            .map(|nn| nn.id)
+            .collect();
+        // NOTE: at most one lock is held at a time to avoid deadlocks.
+        // Nodes inserted concurrently may have found each other, so edges which already exist are skipped.
         // connect nn -> node
-        nn_ids.iter().for_each(|nn_id|
-            {
-                if !self.id2adjacency_ids.contains_key(nn_id) {
-                    self.id2adjacency_ids.insert(*nn_id, vec![]);
+        for &nn_pos in &nn_positions {
+            let mut adjacency_positions = self.adjacency_positions[nn_pos].lock().unwrap();
+            if !adjacency_positions.contains(&pos) {
+                adjacency_positions.push(pos);
+            }
+        }
+        // connect node -> nn
+        let mut adjacency_positions = self.adjacency_positions[pos].lock().unwrap();
+        for nn_pos in nn_positions {
+            if !adjacency_positions.contains(&nn_pos) {
+                adjacency_positions.push(nn_pos);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NSWGraphView for LockedNSWGraph<'_> {
+    fn trial(&self) -> usize {
+        self.trial
+    }
+    fn distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        self.distance
+    }
+    fn num_nodes(&self) -> usize {
+        self.num_live_entry_nodes
+    }
+    // NOTE: only the nodes existing before the concurrent insertion are used as entry points.
+    fn node_ids(&self) -> Box<dyn Iterator<Item = usize> + '_> {
+        Box::new(0..self.num_entry_nodes)
+    }
+    fn random_entry(&self, rng: &mut SmallRng) -> usize {
+        rng.gen_range(0..self.num_entry_nodes)
+    }
+    fn node_vec(&self, id: usize) -> &[f32] {
+        self.vecs[id]
+    }
+    fn cost(&self, query: &[f32], id: usize) -> Result<f32, NNSearchError> {
+        match self.quantized {
+            Some(quantized) => quantized.compute(self.distance, query, self.ids[id]),
+            None => self.distance.compute(query, self.vecs[id]),
+        }
+    }
+    fn adjacency_ids(&self, id: usize) -> Cow<'_, [usize]> {
+        Cow::Owned(self.adjacency_positions[id].lock().unwrap().clone())
+    }
+    fn is_removed(&self, id: usize) -> bool {
+        self.removed[id]
+    }
+}
+
+/// Connections of a node being added on a layer.
+struct LayerLinks {
+    layer: usize,
+    nn_ids: Vec<usize>,
+    // neighbors whose connections are reselected to keep the degree bounded
+    shrunk: Vec<(usize, Vec<usize>)>,
+}
+
+/// Hierarchical Navigable Small World graph.
+// The algorithm here is based on https://arxiv.org/abs/1603.09320
+#[derive(Debug)]
+pub struct HierarchicalNavigableSmallWorldGraph {
+    /// Maximum number of connections of a node on the upper layers (M).
+    pub max_degree: usize,
+    /// Maximum number of connections of a node on the bottom layer (M0).
+    pub max_degree0: usize,
+    /// Size of the dynamic candidate list when inserting a node.
+    pub ef_construction: usize,
+    /// Size of the dynamic candidate list when searching.
+    pub ef_search: usize,
+    /// Normalization factor for the level assignment (mL).
+    pub level_mult: f64,
+    /// Adjacency lists of each layer. `layers[0]` is the bottom layer which contains all nodes.
+    pub layers: Vec<HashMap<usize, Vec<usize>>>,
+    pub id2node: HashMap<usize, VectorNode>,
+    pub entry_point: Option<usize>,
+    pub distance: Box<dyn PairwiseDistance<f32, f32>>,
+    /// Removed nodes which are kept to be traversed until `compact` is called.
+    pub tombstones: HashSet<usize>,
+    rng: SmallRng,
+}
+
+impl HierarchicalNavigableSmallWorldGraph {
+    pub fn new(distance: Box<dyn PairwiseDistance<f32, f32>>, max_degree: usize, ef_construction: usize, ef_search: usize) -> Self {
+        HierarchicalNavigableSmallWorldGraph {
+            max_degree,
+            max_degree0: max_degree * 2,
+            ef_construction,
+            ef_search,
+            level_mult: 1.0 / (max_degree.max(2) as f64).ln(),
+            layers: vec![],
+            id2node: HashMap::new(),
+            entry_point: None,
+            distance,
+            tombstones: HashSet::new(),
+            rng: get_rng(46),
+        }
+    }
+
+    fn random_level(&mut self) -> usize {
+        // NOTE: 1.0 - [0, 1) to avoid ln(0)
+        let uniform: f64 = 1.0 - self.rng.gen::<f64>();
+        (-uniform.ln() * self.level_mult).floor() as usize
+    }
+
+    fn max_degree_of(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.max_degree0
+        } else {
+            self.max_degree
+        }
+    }
+
+    fn cost_between(&self, vec: &[f32], id: usize) -> Result<f32, NNSearchError> {
+        self.distance.compute(vec, &self.id2node[&id].vec)
+    }
+
+    /// Returns the vector of `id`, which is `pending` if it is the node being added.
+    fn vec_of<'a>(&'a self, id: usize, pending: Option<&'a VectorNode>) -> &'a [f32] {
+        match pending {
+            Some(node) if node.id == id => &node.vec,
+            _ => &self.id2node[&id].vec,
+        }
+    }
+
+    /// Returns at most `ef` nearest items to `query` on `layer` in ascending order of the cost.
+    /// Removed nodes and nodes not allowed by `filter` are traversed but not returned, so the result can be empty.
+    /// The search stops once `max_evals` nodes are evaluated if it is given.
+    fn search_layer(&self, query: &[f32], entry_points: &[CostedItem], ef: usize, layer: usize, filter: Option<&dyn IdFilter>, max_evals: Option<usize>) -> Result<Vec<CostedItem>, NNSearchError> {
+        let admits = |id: usize| !self.tombstones.contains(&id) && filter.is_none_or(|filter| filter.allows(id));
+        let mut visited: HashSet<usize> = entry_points.iter().map(|item| item.id).collect();
+        let mut candidates: BTreeSet<CostedItem> = entry_points.iter().cloned().collect();
+        let mut result: BTreeSet<CostedItem> = entry_points.iter().filter(|item| admits(item.id)).cloned().collect();
+        let all_admitted = self.tombstones.is_empty() && filter.is_none();
+        let exhausted = |visited: &HashSet<usize>| max_evals.is_some_and(|max| visited.len() >= max);
+        while let Some(c) = candidates.pop_first() {
+            if exhausted(&visited) {
+                break
+            }
+            // NOTE: keep searching until ef admitted nodes are found if some nodes are removed or filtered out.
+            let furthest = result.last().map_or(f32::INFINITY, |item| item.cost);
+            if c.cost > furthest && (result.len() >= ef || all_admitted) {
+                break
+            }
+            if let Some(adjacency_ids) = self.layers[layer].get(&c.id) {
+                for &id in adjacency_ids {
+                    if exhausted(&visited) {
+                        break
+                    }
+                    if !visited.insert(id) {
+                        continue
+                    }
+                    let cost = self.cost_between(query, id)?;
+                    if result.len() < ef || cost < result.last().unwrap().cost {
+                        candidates.insert(CostedItem {id, cost});
+                        if !admits(id) {
+                            continue
+                        }
+                        result.insert(CostedItem {id, cost});
+                        if result.len() > ef {
+                            result.pop_last();
+                        }
+                    }
                 }
-                self.id2adjacency_ids.get_mut(nn_id).unwrap().push(node.id)
             }
-        );
-        self.id2node.insert(node.id, node);
+        }
+        Ok(result.into_iter().collect())
+    }
+
+    /// Selects at most `m` neighbors from `candidates` sorted in ascending order of the cost.
+    /// Candidates closer to an already selected neighbor than to the base node are skipped first
+    /// to keep the graph navigable, and used to fill up the remaining connections.
+    /// `pending` is the node being added, which can be a candidate before it is stored.
+    fn select_neighbors(&self, candidates: &[CostedItem], m: usize, pending: Option<&VectorNode>) -> Result<Vec<usize>, NNSearchError> {
+        let mut selected: Vec<CostedItem> = vec![];
+        let mut pruned: Vec<CostedItem> = vec![];
+        'candidate: for c in candidates {
+            if selected.len() >= m {
+                break
+            }
+            let vec = self.vec_of(c.id, pending);
+            for s in &selected {
+                if self.distance.compute(vec, self.vec_of(s.id, pending))? <= c.cost {
+                    pruned.push(*c);
+                    continue 'candidate
+                }
+            }
+            selected.push(*c);
+        }
+        selected.extend(pruned.into_iter().take(m - selected.len()));
+        Ok(selected.iter().map(|item| item.id).collect())
+    }
+
+    /// Returns the connections of `id` on `layer` reselected from its current ones and `pending` linking to it.
+    fn shrunk_connections(&self, id: usize, layer: usize, pending: &VectorNode) -> Result<Vec<usize>, NNSearchError> {
+        let vec = &self.id2node[&id].vec;
+        let mut candidates = self.layers[layer][&id]
+            .iter()
+            .map(|&nn_id| Ok(CostedItem {id: nn_id, cost: self.cost_between(vec, nn_id)?}))
+            .collect::<Result<Vec<_>, NNSearchError>>()?;
+        candidates.push(CostedItem {id: pending.id, cost: self.distance.compute(vec, &pending.vec)?});
+        candidates.sort();
+        self.select_neighbors(&candidates, self.max_degree_of(layer), Some(pending))
+    }
+
+    /// Descends to the bottom layer and returns at most `k` neighbors of `query` allowed by `filter`.
+    /// `params.max_distance_evals` limits the evaluations on the bottom layer.
+    fn search_bottom_layer(&self, query: &[f32], k: usize, filter: Option<&dyn IdFilter>, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        let entry_id = match self.entry_point {
+            Some(entry_id) => entry_id,
+            None => return Ok(vec![]),
+        };
+        let entry_points = vec![CostedItem {id: entry_id, cost: self.cost_between(query, entry_id)?}];
+        let entry_points = self.greedy_descent(query, entry_points, self.layers.len() - 1, 0)?;
+        let ef = params.ef.unwrap_or(self.ef_search).max(k);
+        Ok(self.search_layer(query, &entry_points, ef, 0, filter, params.max_distance_evals)?
+            .into_iter()
+            .take(k)
+            .map(Neighbor::from)
+            .collect())
+    }
+
+    /// Descends from `top_layer` to `bottom_layer` (exclusive) with ef = 1.
+    fn greedy_descent(&self, query: &[f32], mut entry_points: Vec<CostedItem>, top_layer: usize, bottom_layer: usize) -> Result<Vec<CostedItem>, NNSearchError> {
+        for layer in (bottom_layer + 1..=top_layer).rev() {
+            let found = self.search_layer(query, &entry_points, 1, layer, None, None)?;
+            // NOTE: keep the current entry points if only removed nodes are reachable.
+            if !found.is_empty() {
+                entry_points = found;
+            }
+        }
+        Ok(entry_points)
+    }
+}
+
+impl GraphOperator for HierarchicalNavigableSmallWorldGraph {
+    fn add_node(&mut self, node: VectorNode) -> Result<(), NNSearchError> {
+        validate_node(&self.id2node, &node)?;
+        let id = node.id;
+        let level = self.random_level();
+        // NOTE: the connections are computed before the graph is modified, so that a failure leaves it unchanged.
+        let mut links = vec![];
+        if let Some(entry_id) = self.entry_point {
+            let top_layer = self.layers.len() - 1;
+            let entry_points = vec![CostedItem {id: entry_id, cost: self.cost_between(&node.vec, entry_id)?}];
+            // greedy descent to the level of the new node
+            let mut entry_points = self.greedy_descent(&node.vec, entry_points, top_layer, level)?;
+            for layer in (0..=level.min(top_layer)).rev() {
+                let found = self.search_layer(&node.vec, &entry_points, self.ef_construction, layer, None, None)?;
+                let nn_ids = self.select_neighbors(&found, self.max_degree, None)?;
+                let mut shrunk = vec![];
+                for &nn_id in &nn_ids {
+                    if self.layers[layer][&nn_id].len() >= self.max_degree_of(layer) {
+                        shrunk.push((nn_id, self.shrunk_connections(nn_id, layer, &node)?));
+                    }
+                }
+                links.push(LayerLinks {layer, nn_ids, shrunk});
+                if !found.is_empty() {
+                    entry_points = found;
+                }
+            }
+        }
+
+        if level >= self.layers.len() {
+            self.entry_point = Some(id);
+        }
+        while self.layers.len() <= level {
+            self.layers.push(HashMap::new());
+        }
+        for layer in 0..=level {
+            self.layers[layer].insert(id, vec![]);
+        }
+        for LayerLinks {layer, nn_ids, shrunk} in links {
+            // connect nn -> node
+            for &nn_id in &nn_ids {
+                self.layers[layer].get_mut(&nn_id).unwrap().push(id);
+            }
+            for (nn_id, adjacency_ids) in shrunk {
+                self.layers[layer].insert(nn_id, adjacency_ids);
+            }
+            // connect node -> nn
+            self.layers[layer].insert(id, nn_ids);
+        }
+        self.id2node.insert(id, node);
         Ok(())
     }
     fn get_node(&self, id: &usize) -> Option<&VectorNode> {
-        self.id2node.get(id)
+        self.id2node.get(id).filter(|_| !self.tombstones.contains(id))
+    }
+    fn search_with_params(&self, query: &VectorNode, k: usize, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_bottom_layer(&query.vec, k, None, params)
+    }
+    fn search_filtered(&self, query: &VectorNode, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_bottom_layer(&query.vec, k, Some(filter), &SearchParams::default())
     }
-    fn search_nearest_neighbor(&self, query: &VectorNode, k: usize) -> Vec<usize> {
-        self.approx_knn_search(query, k)
+    fn search_radius(&self, query: &VectorNode, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        let entry_id = match self.entry_point {
+            Some(entry_id) => entry_id,
+            None => return Ok(vec![]),
+        };
+        let entry_points = vec![CostedItem {id: entry_id, cost: self.cost_between(&query.vec, entry_id)?}];
+        let entry_points = self.greedy_descent(&query.vec, entry_points, self.layers.len() - 1, 0)?;
+        let entry_points = self.search_layer(&query.vec, &entry_points, self.ef_search, 0, None, None)?;
+        expand_within_radius(
+            &entry_points,
+            radius,
+            |id| Cow::Borrowed(self.layers[0].get(&id).map(|adjacency_ids| adjacency_ids.as_slice()).unwrap_or(&[])),
+            |id| self.cost_between(&query.vec, id),
+            |id| self.tombstones.contains(&id),
+        )
+    }
+    fn remove_node(&mut self, id: usize) -> Result<(), NNSearchError> {
+        if self.get_node(&id).is_none() {
+            return Err(NNSearchError::NotFound(id))
+        }
+        self.tombstones.insert(id);
+        for layer in 0..self.layers.len() {
+            let removed_adjacency_ids = match self.layers[layer].get(&id) {
+                Some(adjacency_ids) => adjacency_ids.clone(),
+                None => break,
+            };
+            // NOTE: edges can be unidirectional after shrinking, so only the neighbors linking back to the removed node
+            // are reconnected. The other nodes linking to it still reach its neighbors through it until `compact`.
+            let nn_ids: Vec<usize> = removed_adjacency_ids
+                .iter()
+                .filter(|&nn_id| !self.tombstones.contains(nn_id) && self.layers[layer][nn_id].contains(&id))
+                .cloned()
+                .collect();
+            // reselect the connections of each neighbor from its neighbors and those of the removed node
+            for nn_id in nn_ids {
+                let vec = &self.id2node[&nn_id].vec;
+                let candidate_ids: HashSet<usize> = self.layers[layer][&nn_id]
+                    .iter()
+                    .chain(removed_adjacency_ids.iter())
+                    .filter(|&&candidate_id| candidate_id != nn_id && !self.tombstones.contains(&candidate_id))
+                    .cloned()
+                    .collect();
+                let mut candidates = candidate_ids
+                    .into_iter()
+                    .map(|candidate_id| Ok(CostedItem {id: candidate_id, cost: self.cost_between(vec, candidate_id)?}))
+                    .collect::<Result<Vec<_>, NNSearchError>>()?;
+                candidates.sort();
+                let selected = self.select_neighbors(&candidates, self.max_degree_of(layer), None)?;
+                self.layers[layer].insert(nn_id, selected);
+            }
+        }
+        Ok(())
+    }
+    fn compact(&mut self) {
+        for id in self.tombstones.drain() {
+            self.id2node.remove(&id);
+            for id2adjacency_ids in self.layers.iter_mut() {
+                id2adjacency_ids.remove(&id);
+            }
+        }
+        let id2node = &self.id2node;
+        for id2adjacency_ids in self.layers.iter_mut() {
+            for adjacency_ids in id2adjacency_ids.values_mut() {
+                adjacency_ids.retain(|adjacency_id| id2node.contains_key(adjacency_id));
+            }
+        }
+        while self.layers.last().is_some_and(|id2adjacency_ids| id2adjacency_ids.is_empty()) {
+            self.layers.pop();
+        }
+        if self.entry_point.is_some_and(|entry_id| !self.id2node.contains_key(&entry_id)) {
+            // NOTE: the smallest id on the top layer is chosen to be deterministic.
+            self.entry_point = self.layers.last().and_then(|id2adjacency_ids| id2adjacency_ids.keys().min().cloned());
+        }
     }
     fn len(&self) -> usize {
-        self.id2node.len()
+        self.id2node.len() - self.tombstones.len()
     }
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
-
 }
 
 
+#[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::linalg::distance::Euclidean;
+    use crate::linalg::utils::generate_matrix;
+
     #[test]
     fn test_nsw() {
         // pass 
     }
 
+    #[test]
+    fn test_hnsw_recall() {
+        let mat = generate_matrix(500, 8);
+        let mut graph = HierarchicalNavigableSmallWorldGraph::new(Box::new(Euclidean{}), 8, 64, 32);
+        for (id, vec) in mat.iter().enumerate() {
+            graph.add_node(VectorNode::new(id, vec.clone())).unwrap();
+        }
+        assert_eq!(graph.len(), 500);
+        assert!(graph.layers.len() > 1);
+
+        let k = 10;
+        let mut num_hit = 0;
+        for query in mat.iter().take(50) {
+            let mut expected: Vec<usize> = (0..mat.len()).collect();
+            expected.sort_by(|&a, &b| graph.cost_between(query, a).unwrap().partial_cmp(&graph.cost_between(query, b).unwrap()).unwrap());
+            let result = graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, query.clone()), k).unwrap();
+            assert_eq!(result.len(), k);
+            assert!(result.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+            num_hit += result.iter().filter(|nn| expected[..k].contains(&nn.id)).count();
+        }
+        let recall = num_hit as f32 / (50 * k) as f32;
+        assert!(recall > 0.9, "recall={}", recall);
+    }
+
+    #[test]
+    fn test_hnsw_degree_is_bounded() {
+        let mat = generate_matrix(300, 4);
+        let mut graph = HierarchicalNavigableSmallWorldGraph::new(Box::new(Euclidean{}), 4, 32, 16);
+        for (id, vec) in mat.into_iter().enumerate() {
+            graph.add_node(VectorNode::new(id, vec)).unwrap();
+        }
+        for (layer, id2adjacency_ids) in graph.layers.iter().enumerate() {
+            for adjacency_ids in id2adjacency_ids.values() {
+                assert!(adjacency_ids.len() <= graph.max_degree_of(layer));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hnsw_empty() {
+        let graph = HierarchicalNavigableSmallWorldGraph::new(Box::new(Euclidean{}), 4, 32, 16);
+        assert!(graph.is_empty());
+        assert!(graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, vec![0.1, 0.2]), 3).unwrap().is_empty());
+    }
+
+    fn recall_after_removal(graph: &mut dyn GraphOperator, mat: &[Vec<f32>], k: usize) -> f32 {
+        for id in (0..mat.len()).step_by(3) {
+            graph.remove_node(id).unwrap();
+        }
+        let live_ids: Vec<usize> = (0..mat.len()).filter(|id| id % 3 != 0).collect();
+        let mut num_hit = 0;
+        for query in mat.iter().take(50) {
+            let mut expected = live_ids.clone();
+            let cost = |id: usize| Euclidean{}.compute(query, &mat[id]).unwrap();
+            expected.sort_by(|&a, &b| cost(a).partial_cmp(&cost(b)).unwrap());
+            let result = graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, query.clone()), k).unwrap();
+            assert_eq!(result.len(), k);
+            assert!(result.iter().all(|nn| nn.id % 3 != 0));
+            num_hit += result.iter().filter(|nn| expected[..k].contains(&nn.id)).count();
+        }
+        num_hit as f32 / (50 * k) as f32
+    }
+
+    #[test]
+    fn test_remove_node() {
+        let mat = generate_matrix(300, 4);
+        let graphs: Vec<Box<dyn GraphOperator>> = vec![
+            Box::new(NavigableSmallWorldGraph::new(Box::new(Euclidean{}), 5, 8)),
+            Box::new(HierarchicalNavigableSmallWorldGraph::new(Box::new(Euclidean{}), 8, 64, 32)),
+        ];
+        for mut graph in graphs {
+            for (id, vec) in mat.iter().enumerate() {
+                graph.add_node(VectorNode::new(id, vec.clone())).unwrap();
+            }
+            let recall = recall_after_removal(&mut *graph, &mat, 10);
+            assert!(recall > 0.8, "recall={}", recall);
+            assert_eq!(graph.len(), 200);
+            assert!(graph.get_node(&0).is_none());
+            assert_eq!(graph.remove_node(0).unwrap_err(), NNSearchError::NotFound(0));
+            assert_eq!(graph.remove_node(300).unwrap_err(), NNSearchError::NotFound(300));
+            // removed ids cannot be added again until compaction
+            assert_eq!(graph.add_node(VectorNode::new(0, mat[0].clone())).unwrap_err(), NNSearchError::DuplicateId(0));
+
+            graph.compact();
+            assert_eq!(graph.len(), 200);
+            let result = graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, mat[1].clone()), 5).unwrap();
+            assert_eq!(result[0].id, 1);
+            graph.add_node(VectorNode::new(0, mat[0].clone())).unwrap();
+            let result = graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, mat[0].clone()), 5).unwrap();
+            assert_eq!(result[0].id, 0);
+        }
+    }
+
+    #[test]
+    fn test_remove_all_nodes() {
+        let mut graph = HierarchicalNavigableSmallWorldGraph::new(Box::new(Euclidean{}), 4, 32, 16);
+        for (id, vec) in generate_matrix(20, 2).into_iter().enumerate() {
+            graph.add_node(VectorNode::new(id, vec)).unwrap();
+        }
+        for id in 0..20 {
+            graph.remove_node(id).unwrap();
+        }
+        assert!(graph.is_empty());
+        assert!(graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, vec![0.1, 0.2]), 3).unwrap().is_empty());
+        graph.compact();
+        assert!(graph.layers.is_empty());
+        assert_eq!(graph.entry_point, None);
+        graph.add_node(VectorNode::new(0, vec![0.1, 0.2])).unwrap();
+        assert_eq!(graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, vec![0.1, 0.2]), 1).unwrap()[0].id, 0);
+    }
+
+    #[derive(Debug)]
+    struct NegativeFailingEuclidean;
+
+    impl PairwiseDistance<f32, f32> for NegativeFailingEuclidean {
+        fn compute(&self, p1: &[f32], p2: &[f32]) -> Result<f32, NNSearchError> {
+            if p1.iter().chain(p2).any(|&x| x < 0.0) {
+                return Err(NNSearchError::ValueError("Negative value".to_string()))
+            }
+            Euclidean{}.compute(p1, p2)
+        }
+        fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
+            Euclidean{}.compute_innter(p1, p2)
+        }
+    }
+
+    #[test]
+    fn test_hnsw_add_and_remove_node_links() {
+        let mut graph = HierarchicalNavigableSmallWorldGraph::new(Box::new(NegativeFailingEuclidean{}), 4, 32, 16);
+        let mat = generate_matrix(100, 2);
+        for (id, vec) in mat.iter().enumerate() {
+            graph.add_node(VectorNode::new(id, vec.clone())).unwrap();
+        }
+        // a failed insertion leaves the graph unchanged
+        let layers = graph.layers.clone();
+        let entry_point = graph.entry_point;
+        for _ in 0..10 {
+            assert!(graph.add_node(VectorNode::new(100, vec![-0.1, 0.2])).is_err());
+        }
+        assert_eq!(graph.layers, layers);
+        assert_eq!(graph.entry_point, entry_point);
+        assert!(graph.get_node(&100).is_none());
+        graph.add_node(VectorNode::new(100, vec![0.1, 0.2])).unwrap();
+        assert_eq!(graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, vec![0.1, 0.2]), 1).unwrap()[0].id, 100);
+
+        // the neighbors of a removed node no longer link to it
+        graph.remove_node(100).unwrap();
+        for id2adjacency_ids in &graph.layers {
+            if let Some(removed_adjacency_ids) = id2adjacency_ids.get(&100) {
+                assert!(removed_adjacency_ids.iter().all(|nn_id| !id2adjacency_ids[nn_id].contains(&100)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_nsw_random_entry() {
+        let mat = generate_matrix(50, 2);
+        let mut graph = NavigableSmallWorldGraph::new(Box::new(Euclidean{}), 3, 4);
+        for (id, vec) in mat.iter().enumerate() {
+            graph.add_node(VectorNode::new(id, vec.clone())).unwrap();
+        }
+        for id in (0..50).step_by(2) {
+            graph.remove_node(id).unwrap();
+        }
+        graph.update_node(VectorNode::new(4, mat[4].clone())).unwrap();
+        graph.update_node(VectorNode::new(5, mat[5].clone())).unwrap();
+        let mut expected: Vec<usize> = (0..50).filter(|id| id % 2 == 1 || *id == 4).collect();
+        let mut live_ids = graph.live_ids.clone();
+        live_ids.sort_unstable();
+        assert_eq!(live_ids, expected);
+        assert!(graph.live_ids.iter().enumerate().all(|(pos, id)| graph.live_id2pos[id] == pos));
+
+        let mut rng = get_rng(0);
+        let mut entry_ids: Vec<usize> = (0..1000).map(|_| graph.random_entry(&mut rng)).collect();
+        entry_ids.sort_unstable();
+        entry_ids.dedup();
+        assert_eq!(entry_ids, expected);
+
+        graph.compact();
+        graph.add_node(VectorNode::new(0, mat[0].clone())).unwrap();
+        expected.insert(0, 0);
+        let mut live_ids = graph.live_ids.clone();
+        live_ids.sort_unstable();
+        assert_eq!(live_ids, expected);
+    }
+
+    fn nsw_recall(graph: &NavigableSmallWorldGraph, mat: &[Vec<f32>], k: usize) -> f32 {
+        let mut num_hit = 0;
+        for query in mat.iter().take(50) {
+            let cost = |id: usize| Euclidean{}.compute(query, &mat[id]).unwrap();
+            let mut expected: Vec<usize> = (0..mat.len()).collect();
+            expected.sort_by(|&a, &b| cost(a).partial_cmp(&cost(b)).unwrap());
+            let result = graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, query.clone()), k).unwrap();
+            num_hit += result.iter().filter(|nn| expected[..k].contains(&nn.id)).count();
+        }
+        num_hit as f32 / (50 * k) as f32
+    }
+
+    #[test]
+    fn test_nsw_add_nodes() {
+        let mat = generate_matrix(1500, 8);
+        let mut sequential = NavigableSmallWorldGraph::new(Box::new(Euclidean{}), 5, 8);
+        for (id, vec) in mat.iter().enumerate() {
+            sequential.add_node(VectorNode::new(id, vec.clone())).unwrap();
+        }
+        let mut bulk = NavigableSmallWorldGraph::new(Box::new(Euclidean{}), 5, 8);
+        bulk.add_nodes(mat[..1000].iter().enumerate().map(|(id, vec)| VectorNode::new(id, vec.clone())).collect()).unwrap();
+        bulk.remove_node(0).unwrap();
+        bulk.add_nodes(mat[1000..].iter().enumerate().map(|(id, vec)| VectorNode::new(1000 + id, vec.clone())).collect()).unwrap();
+        assert_eq!(bulk.len(), 1499);
+        for (id, adjacency_ids) in bulk.id2adjacency_ids.iter().filter(|(id, _)| !bulk.is_removed(**id)) {
+            assert!(!adjacency_ids.contains(id));
+            let unique_ids: HashSet<&usize> = adjacency_ids.iter().collect();
+            assert_eq!(unique_ids.len(), adjacency_ids.len());
+            // edges between live nodes are bidirectional
+            assert!(adjacency_ids.iter().all(|adjacency_id| bulk.id2adjacency_ids[adjacency_id].contains(id)));
+        }
+        let recall_sequential = nsw_recall(&sequential, &mat, 10);
+        let recall_bulk = nsw_recall(&bulk, &mat, 10);
+        assert!(recall_bulk > recall_sequential - 0.05, "sequential={}, bulk={}", recall_sequential, recall_bulk);
+
+        let nodes = vec![VectorNode::new(2000, vec![0.0; 8]), VectorNode::new(2000, vec![0.0; 8])];
+        assert_eq!(bulk.add_nodes(nodes).unwrap_err(), NNSearchError::DuplicateId(2000));
+        let nodes = vec![VectorNode::new(2000, vec![0.0; 8]), VectorNode::new(2001, vec![0.0; 7])];
+        assert_eq!(bulk.add_nodes(nodes).unwrap_err(), NNSearchError::DimensionMismatch {expected: 8, actual: 7});
+        assert_eq!(bulk.len(), 1499);
+    }
+
+    #[test]
+    fn test_add_invalid_node() {
+        let graphs: Vec<Box<dyn GraphOperator>> = vec![
+            Box::new(NavigableSmallWorldGraph::new(Box::new(Euclidean{}), 3, 2)),
+            Box::new(HierarchicalNavigableSmallWorldGraph::new(Box::new(Euclidean{}), 4, 32, 16)),
+        ];
+        for mut graph in graphs {
+            graph.add_node(VectorNode::new(0, vec![0.1, 0.2])).unwrap();
+            assert_eq!(graph.add_node(VectorNode::new(0, vec![0.3, 0.4])).unwrap_err(), NNSearchError::DuplicateId(0));
+            assert_eq!(graph.add_node(VectorNode::new(1, vec![0.3])).unwrap_err(), NNSearchError::DimensionMismatch {expected: 2, actual: 1});
+            assert_eq!(graph.len(), 1);
+            // NaN costs are ordered last instead of panicking
+            for id in 1..10 {
+                graph.add_node(VectorNode::new(id, vec![id as f32, f32::NAN])).unwrap();
+            }
+            let neighbors = graph.search_nearest_neighbor(&VectorNode::new(usize::MAX, vec![0.1, 0.2]), 10).unwrap();
+            assert_eq!(neighbors[0].id, 0);
+        }
+    }
 }
@@ -1,5 +1,6 @@
 // Implementations of hashers are based on `lsh-rs` crate
 // https://github.com/ritchie46/lsh-rs/blob/9e81c018872868b319e5fe4d23495ee031117e91/lsh-rs/src/hash.rs#L1
+use crate::error::NNSearchError;
 use crate::type_utils::{FloatScalar, SetItem};
 use crate::linalg::utils::{get_rng, to_lowest_b_bit_vector};
 use ndarray::prelude::*;
@@ -10,7 +11,7 @@ use num::Zero;
 
 pub trait Hasher<T, U> {
     // used Vec to returnd the sized type
-    fn to_hash(&self, input: &[T]) -> Vec<U>;
+    fn to_hash(&self, input: &[T]) -> Result<Vec<U>, NNSearchError>;
 }
 
 #[derive(Debug)]
@@ -28,8 +29,11 @@ impl RandomProjection<f32> {
 }
 
 impl Hasher<f32, f32> for RandomProjection<f32> {
-    fn to_hash(&self, input: &[f32]) -> Vec<f32> {
-        aview1(input).dot(&self.rand_mat).to_vec()
+    fn to_hash(&self, input: &[f32]) -> Result<Vec<f32>, NNSearchError> {
+        if input.len() != self.rand_mat.nrows() {
+            return Err(NNSearchError::DimensionMismatch {expected: self.rand_mat.nrows(), actual: input.len()})
+        }
+        Ok(aview1(input).dot(&self.rand_mat).to_vec())
     }
 }
 
@@ -58,13 +62,26 @@ impl MinHash {
             dim,
         }
     }
+
+    /// Returns the number of hash values in a signature.
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// Returns the number of possible items, which must be less than this.
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
 }
 
 impl Hasher<SetItem, SetItem> for MinHash {
-    fn to_hash(&self, input: &[SetItem]) -> Vec<SetItem> {
+    fn to_hash(&self, input: &[SetItem]) -> Result<Vec<SetItem>, NNSearchError> {
         let mut vec: Array1<i32> = Array::zeros(self.dim);
-        for idx in 0..input.len() {
-            vec[input[idx]] = 1;
+        for &item in input {
+            if item >= self.dim {
+                return Err(NNSearchError::ValueError(format!("Item out of range: {} >= {}", item, self.dim)))
+            }
+            vec[item] = 1;
         }
         let permutated = &self.pi_mat * &vec;
         let hash = permutated.map_axis(Axis(1), |view| {
@@ -81,7 +98,7 @@ impl Hasher<SetItem, SetItem> for MinHash {
                 }
             })
         });
-        hash.to_vec()
+        Ok(hash.to_vec())
     }
 }
 
@@ -99,26 +116,28 @@ impl BBitMinHash {
 }
 
 impl Hasher<SetItem, bool> for BBitMinHash {
-    fn to_hash(&self, input: &[SetItem]) -> Vec<bool> {
+    fn to_hash(&self, input: &[SetItem]) -> Result<Vec<bool>, NNSearchError> {
         // FIXME: more effective?
-        let hash = self.minhash.to_hash(input);
-        hash.into_iter().fold(vec![], |mut acc, v| {
+        let hash = self.minhash.to_hash(input)?;
+        Ok(hash.into_iter().fold(vec![], |mut acc, v| {
             acc.extend(to_lowest_b_bit_vector(v, self.b));
             acc
-        })
+        }))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::linalg::distance::{Jaccard, PairwiseDistance};
 
     #[test]
     fn test_rp() {
         let rp = RandomProjection::new(5, 3);
         let v = vec![1.,2.,3.,4.,5.];
-        let hashed_v = rp.to_hash(&v);
+        let hashed_v = rp.to_hash(&v).unwrap();
         assert_eq!(hashed_v.len(), 3);
+        assert_eq!(rp.to_hash(&v[..4]).unwrap_err(), NNSearchError::DimensionMismatch {expected: 5, actual: 4});
     }
 
     #[test]
@@ -127,8 +146,9 @@ mod test {
         let dim =5;
         let minhash = MinHash::new(k, dim);
         let v1 = vec![1, 2, 4];
-        let hashed_v1 = minhash.to_hash(&v1);
+        let hashed_v1 = minhash.to_hash(&v1).unwrap();
         assert_eq!(hashed_v1.len(), k);
+        assert_eq!(minhash.to_hash(&[1, 5]).unwrap_err(), NNSearchError::ValueError("Item out of range: 5 >= 5".to_string()));
     }
 
     #[test]
@@ -138,10 +158,23 @@ mod test {
         let b = 2;
         let minhash = BBitMinHash::new(k, dim, b);
         let v1 = vec![1, 2, 4];
-        let hashed_v1 = minhash.to_hash(&v1);
+        let hashed_v1 = minhash.to_hash(&v1).unwrap();
         assert_eq!(hashed_v1.len(), k*b);
     }
 
+    #[test]
+    fn test_minhash_close_to_exact_jaccard() {
+        let minhash = MinHash::new(2000, 20);
+        let v1 = vec![1, 2, 4, 8, 10, 15];
+        let v2 = vec![1, 3, 4, 10, 19];
+        let hashed_v1 = minhash.to_hash(&v1).unwrap();
+        let hashed_v2 = minhash.to_hash(&v2).unwrap();
+        let num_match = hashed_v1.iter().zip(hashed_v2.iter()).filter(|(h1, h2)| h1 == h2).count();
+        let approx_jaccard = num_match as f32 / hashed_v1.len() as f32;
+        let exact_jaccard = 1.0 - Jaccard{}.compute(&v1, &v2).unwrap();
+        assert!((approx_jaccard - exact_jaccard).abs() < 0.05, "approx={}, exact={}", approx_jaccard, exact_jaccard);
+    }
+
     #[test]
     #[ignore]
     fn test_approx_jaccard() {
@@ -150,8 +183,8 @@ mod test {
         let minhash = MinHash::new(large_k, dim);
         let v1 = vec![1, 2, 4];
         let v2 = vec![1, 3];
-        let hashed_v1 = minhash.to_hash(&v1);
-        let hashed_v2 = minhash.to_hash(&v2);
+        let hashed_v1 = minhash.to_hash(&v1).unwrap();
+        let hashed_v2 = minhash.to_hash(&v2).unwrap();
         assert_eq!(hashed_v1.len(), large_k);
         assert_eq!(hashed_v2.len(), large_k);
 
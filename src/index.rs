@@ -1,76 +1,722 @@
-use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
 
-use crate::linalg::distance::PairwiseDistance;
-use crate::graph::{GraphOperator, NavigableSmallWorldGraph, VectorNode};
+use ndarray::Array2;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 
-trait VectorIndexOperator {
-    fn add(&mut self, data: Vec<f32>) -> Result<(), ()>;
-    fn add_batch(&mut self, data_batch: Vec<Vec<f32>>) -> Result<(), ()> {
+use crate::error::NNSearchError;
+use crate::filter::IdFilter;
+use crate::io::{read_bytes, read_f32s, read_index_file, read_u64, read_u8, write_bytes, write_f32s, write_index_file, write_u64, write_u8, MAX_RESERVED_ITEMS};
+use crate::ivf::{IVFFlatIndex, IVFPQIndex};
+use crate::lsh::SimHashLSHIndex;
+use crate::linalg::distance::{DistanceFactory, DistanceType, PairwiseDistance};
+use crate::metadata::{read_metadata, write_metadata, AttributeFilter, Metadata, EMPTY_METADATA};
+use crate::quantizer::{ScalarQuantizedVectors, ScalarQuantizerType};
+#[cfg(all(target_endian = "little", target_pointer_width = "64"))]
+use crate::mmap::MmapNSWIndex;
+use crate::graph::{GraphOperator, HierarchicalNavigableSmallWorldGraph, NavigableSmallWorldGraph, Neighbor, SearchParams, VectorNode};
+
+// tags written at the head of saved index files
+const NAIVE_INDEX_TAG: u8 = 0;
+const NSW_INDEX_TAG: u8 = 1;
+pub(crate) const MMAP_NSW_INDEX_TAG: u8 = 2;
+pub(crate) const IVF_FLAT_INDEX_TAG: u8 = 3;
+pub(crate) const IVF_PQ_INDEX_TAG: u8 = 4;
+pub(crate) const SIMHASH_LSH_INDEX_TAG: u8 = 5;
+
+pub trait VectorIndexOperator: Send + Sync {
+    /// Adds the vector and returns the id assigned to it.
+    fn add(&mut self, data: Vec<f32>) -> Result<usize, NNSearchError> {
+        self.add_with_metadata(data, Metadata::new())
+    }
+    /// Adds the vector with the metadata and returns the id assigned to it.
+    fn add_with_metadata(&mut self, data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError>;
+    fn add_batch(&mut self, data_batch: Vec<Vec<f32>>) -> Result<(), NNSearchError> {
         for data in data_batch {
-            self.add(data).unwrap();
+            self.add(data)?;
         }
         Ok(())
     }
-    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<usize>, ()>;
+    /// Returns `k` neighbors of `query` in ascending order of the distance.
+    /// Fails with `EmptyIndex` if nothing is indexed and `KTooLarge` if `k` exceeds the number of items.
+    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError>;
+    /// Same as `search` with the search parameters of graph indexes given by `params`.
+    /// The other indexes ignore `params`.
+    fn search_with_params(&self, query: Vec<f32>, k: usize, _params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search(query, k)
+    }
+    /// Returns `k` neighbors of each query. Queries are searched in parallel with the `parallel` feature.
+    fn search_batch(&self, queries: &[Vec<f32>], k: usize) -> Result<Vec<Vec<Neighbor>>, NNSearchError> {
+        #[cfg(feature = "parallel")]
+        let queries = queries.par_iter();
+        #[cfg(not(feature = "parallel"))]
+        let queries = queries.iter();
+        queries.map(|query| self.search(query.clone(), k)).collect()
+    }
+    /// Same as `search_batch` for queries given as rows of a matrix.
+    fn search_batch_array(&self, queries: &Array2<f32>, k: usize) -> Result<Vec<Vec<Neighbor>>, NNSearchError> {
+        let queries: Vec<Vec<f32>> = queries.outer_iter().map(|row| row.to_vec()).collect();
+        self.search_batch(&queries, k)
+    }
+    /// Returns `k` neighbors of `query` allowed by `filter` in ascending order of the distance.
+    /// Fewer neighbors are returned only when less than `k` items are allowed.
+    fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError>;
+    /// Returns all items within `radius` from `query` in ascending order of the distance.
+    /// Graph indexes expand the graph from the nearest neighbors, so items not reachable within `radius` may be missed.
+    fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError>;
+    /// Removes the item so that it is no longer returned, failing with `NotFound` if the id does not exist.
+    /// Ids of the other items are kept.
+    fn remove(&mut self, id: usize) -> Result<(), NNSearchError>;
+    /// Releases the memory held by the removed items.
+    fn compact(&mut self) {}
+    fn get_vector(&self, id: usize) -> Option<&[f32]>;
+    fn get_metadata(&self, id: usize) -> Option<&Metadata>;
+    /// Returns `k` neighbors of `query` matching `filter` along with their metadata.
+    /// The filter is evaluated during the search, so `k` neighbors are returned unless less than `k` items match.
+    fn search_with_metadata(&self, query: Vec<f32>, k: usize, filter: Option<&AttributeFilter>) -> Result<Vec<(Neighbor, &Metadata)>, NNSearchError> {
+        let neighbors = match filter {
+            Some(filter) => self.search_filtered(query, k, &|id: usize| self.get_metadata(id).is_some_and(|metadata| filter.matches(metadata)))?,
+            None => self.search(query, k)?,
+        };
+        Ok(neighbors.into_iter().map(|nn| (nn, self.get_metadata(nn.id).unwrap_or(&EMPTY_METADATA))).collect())
+    }
+    fn get_distance(&self) -> &dyn PairwiseDistance<f32, f32>;
+    fn dim(&self) -> usize;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub(crate) fn validate_dim(dim: usize, data: &[f32]) -> Result<(), NNSearchError> {
+    if data.len() != dim {
+        return Err(NNSearchError::DimensionMismatch {expected: dim, actual: data.len()})
+    }
+    if data.iter().any(|x| !x.is_finite()) {
+        return Err(NNSearchError::ValueError("Vector must not contain NaN or infinite values".to_string()))
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_radius_query(index: &dyn VectorIndexOperator, query: &[f32], radius: f32) -> Result<(), NNSearchError> {
+    validate_dim(index.dim(), query)?;
+    if radius.is_nan() {
+        return Err(NNSearchError::ValueError("Radius must not be NaN".to_string()))
+    }
+    Ok(())
+}
+
+pub(crate) fn validate_query(index: &dyn VectorIndexOperator, query: &[f32], k: usize) -> Result<(), NNSearchError> {
+    if index.is_empty() {
+        return Err(NNSearchError::EmptyIndex)
+    }
+    validate_dim(index.dim(), query)?;
+    if k > index.len() {
+        return Err(NNSearchError::KTooLarge {k, len: index.len()})
+    }
+    Ok(())
+}
+
+/// Sorts `neighbors` in ascending order of the distance and returns the top `k` of them.
+/// If `rerank` is positive, the top `rerank` candidates are reranked by the `exact` distances before taking the top `k`.
+pub(crate) fn rerank_neighbors<F>(mut neighbors: Vec<Neighbor>, k: usize, rerank: usize, exact: F) -> Result<Vec<Neighbor>, NNSearchError>
+where
+    F: Fn(usize) -> Result<f32, NNSearchError>,
+{
+    neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    if rerank > 0 {
+        neighbors.truncate(rerank.max(k));
+        for nn in neighbors.iter_mut() {
+            nn.distance = exact(nn.id)?;
+        }
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    }
+    neighbors.truncate(k);
+    Ok(neighbors)
+}
+
+/// Loads an index saved by `NaiveKnnIndex::save`, `NSWIndex::save`, `NSWIndex::save_mmap`,
+/// `IVFFlatIndex::save`, `IVFPQIndex::save` or `SimHashLSHIndex::save`.
+pub fn load_index(path: &Path) -> Result<Box<dyn VectorIndexOperator>, NNSearchError> {
+    #[cfg(all(target_endian = "little", target_pointer_width = "64"))]
+    {
+        // NOTE: the file is not verified so that opening it does not read all the pages.
+        if crate::io::read_index_tag(path)? == MMAP_NSW_INDEX_TAG {
+            return Ok(Box::new(MmapNSWIndex::open(path)?))
+        }
+    }
+    let (tag, body) = read_index_file(path)?;
+    match tag {
+        NAIVE_INDEX_TAG => Ok(Box::new(NaiveKnnIndex::read_from(&mut body.as_slice())?)),
+        NSW_INDEX_TAG => Ok(Box::new(NSWIndex::read_from(&mut body.as_slice())?)),
+        IVF_FLAT_INDEX_TAG => Ok(Box::new(IVFFlatIndex::read_from(&mut body.as_slice())?)),
+        IVF_PQ_INDEX_TAG => Ok(Box::new(IVFPQIndex::read_from(&mut body.as_slice())?)),
+        SIMHASH_LSH_INDEX_TAG => Ok(Box::new(SimHashLSHIndex::read_from(&mut body.as_slice())?)),
+        tag => Err(NNSearchError::ValueError(format!("Unknown index tag: {}", tag))),
+    }
+}
+
+pub(crate) fn read_index_body(path: &Path, expected_tag: u8) -> Result<Vec<u8>, NNSearchError> {
+    let (tag, body) = read_index_file(path)?;
+    if tag != expected_tag {
+        return Err(NNSearchError::ValueError(format!("Unexpected index tag: {} != {}", tag, expected_tag)))
+    }
+    Ok(body)
+}
+
+// NOTE: the distance takes at most 5 bytes, which are the code and the optional parameter.
+pub(crate) fn write_distance<W: Write>(writer: &mut W, distance: &dyn PairwiseDistance<f32, f32>) -> Result<(), NNSearchError> {
+    let distance_type = match distance.distance_type() {
+        Some(distance_type) => distance_type,
+        None => return Err(NNSearchError::ValueError(format!("Distance without type cannot be saved: {:?}", distance))),
+    };
+    write_u8(writer, distance_type.to_code())?;
+    if let Some(param) = distance_type.param() {
+        write_f32s(writer, &[param])?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_distance<R: Read>(reader: &mut R) -> Result<Box<dyn PairwiseDistance<f32, f32>>, NNSearchError> {
+    let code = read_u8(reader)?;
+    let param = if DistanceType::has_param(code) {
+        Some(read_f32s(reader, 1)?[0])
+    } else {
+        None
+    };
+    DistanceType::from_code(code, param)?.build()
 }
 
 #[derive(Debug)]
 pub struct NaiveKnnIndex {
     dim: usize,
     distance: Box<dyn PairwiseDistance<f32, f32>>,
-    points: Vec<Vec<f32>>,
+    // NOTE: removed points are left as None to keep the ids,
+    // and vectors are left empty if only their quantized codes are stored.
+    points: Vec<Option<Vec<f32>>>,
+    metadata: Vec<Metadata>,
+    quantized: Option<ScalarQuantizedVectors>,
+}
+
+impl NaiveKnnIndex {
+    pub fn new(dim: usize, distance: Box<dyn PairwiseDistance<f32, f32>>) -> Self {
+        NaiveKnnIndex {
+            dim,
+            distance,
+            points: vec![],
+            metadata: vec![],
+            quantized: None,
+        }
+    }
+
+    /// Creates an index which stores the vectors quantized by `quantizer_type` and computes distances on the codes.
+    /// If `rerank` is positive, the full precision vectors are also kept to rerank the top `rerank` candidates.
+    pub fn new_quantized(dim: usize, distance: Box<dyn PairwiseDistance<f32, f32>>, quantizer_type: ScalarQuantizerType, rerank: usize) -> Self {
+        NaiveKnnIndex {
+            quantized: Some(ScalarQuantizedVectors::new(quantizer_type, dim, rerank)),
+            ..NaiveKnnIndex::new(dim, distance)
+        }
+    }
+
+    /// Trains the quantizer on `data`, which is done on the first batch given to `add_batch` if the index is empty.
+    /// Fails with `ValueError` if items are already indexed. Does nothing for the index without quantization.
+    pub fn train(&mut self, data: &[Vec<f32>]) -> Result<(), NNSearchError> {
+        if !self.points.is_empty() {
+            return Err(NNSearchError::ValueError("Index with items cannot be trained".to_string()))
+        }
+        match &mut self.quantized {
+            Some(quantized) => quantized.quantizer.train(data),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the vector of the item, which is decoded from its code if the full precision vector is not kept.
+    pub fn reconstruct(&self, id: usize) -> Option<Vec<f32>> {
+        match self.points.get(id)? {
+            Some(vec) if vec.is_empty() => self.quantized.as_ref().and_then(|quantized| quantized.decode(id)),
+            point => point.clone(),
+        }
+    }
+
+    fn cost(&self, query: &[f32], id: usize, vec: &[f32]) -> Result<f32, NNSearchError> {
+        match &self.quantized {
+            Some(quantized) => quantized.compute(&*self.distance, query, id),
+            None => self.distance.compute(query, vec),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), NNSearchError> {
+        let mut body = vec![];
+        write_distance(&mut body, &*self.distance)?;
+        write_u64(&mut body, self.dim as u64)?;
+        write_quantized(&mut body, &self.quantized)?;
+        write_u64(&mut body, self.points.len() as u64)?;
+        for (id, (point, metadata)) in self.points.iter().zip(&self.metadata).enumerate() {
+            match point {
+                Some(vec) => {
+                    write_u8(&mut body, 0)?;
+                    write_vector(&mut body, &self.quantized, id, vec)?;
+                    write_metadata(&mut body, metadata)?;
+                }
+                None => write_u8(&mut body, 1)?,
+            }
+        }
+        write_index_file(path, NAIVE_INDEX_TAG, &body)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, NNSearchError> {
+        Self::read_from(&mut read_index_body(path, NAIVE_INDEX_TAG)?.as_slice())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self, NNSearchError> {
+        let distance = read_distance(reader)?;
+        let dim = read_u64(reader)? as usize;
+        let mut quantized = read_quantized(reader, dim)?;
+        let num_points = read_u64(reader)? as usize;
+        let mut points = Vec::with_capacity(num_points.min(MAX_RESERVED_ITEMS));
+        let mut metadata = Vec::with_capacity(num_points.min(MAX_RESERVED_ITEMS));
+        for id in 0..num_points {
+            if read_u8(reader)? != 0 {
+                points.push(None);
+                metadata.push(Metadata::new());
+            } else {
+                points.push(Some(read_vector(reader, &mut quantized, id, dim)?));
+                metadata.push(read_metadata(reader)?);
+            }
+        }
+        Ok(NaiveKnnIndex {dim, distance, points, metadata, quantized})
+    }
+}
+
+// NOTE: the quantizer is stored after a flag of its existence.
+pub(crate) fn write_quantized<W: Write>(writer: &mut W, quantized: &Option<ScalarQuantizedVectors>) -> Result<(), NNSearchError> {
+    write_u8(writer, quantized.is_some() as u8)?;
+    match quantized {
+        Some(quantized) => quantized.write_to(writer),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn read_quantized<R: Read>(reader: &mut R, dim: usize) -> Result<Option<ScalarQuantizedVectors>, NNSearchError> {
+    if read_u8(reader)? == 0 {
+        return Ok(None)
+    }
+    let quantized = ScalarQuantizedVectors::read_from(reader)?;
+    if quantized.quantizer.dim() != dim {
+        return Err(NNSearchError::ValueError("Inconsistent dimension of the quantizer".to_string()))
+    }
+    Ok(Some(quantized))
+}
+
+// NOTE: the full precision vector is stored unless only the code is kept, followed by the code if quantized.
+fn write_vector<W: Write>(writer: &mut W, quantized: &Option<ScalarQuantizedVectors>, id: usize, vec: &[f32]) -> Result<(), NNSearchError> {
+    match quantized {
+        Some(quantized) => {
+            if quantized.keeps_vectors() {
+                write_f32s(writer, vec)?;
+            }
+            write_bytes(writer, quantized.code(id).unwrap())
+        }
+        None => write_f32s(writer, vec),
+    }
+}
+
+fn read_vector<R: Read>(reader: &mut R, quantized: &mut Option<ScalarQuantizedVectors>, id: usize, dim: usize) -> Result<Vec<f32>, NNSearchError> {
+    match quantized {
+        Some(quantized) => {
+            let vec = if quantized.keeps_vectors() { read_f32s(reader, dim)? } else { vec![] };
+            quantized.insert_code(id, &read_bytes(reader, quantized.quantizer.code_size())?);
+            Ok(vec)
+        }
+        None => read_f32s(reader, dim),
+    }
 }
 
 impl VectorIndexOperator for NaiveKnnIndex {
-    fn add(&mut self, data: Vec<f32>) -> Result<(), ()> {
-        self.points.push(data);
+    /// Adds the vectors, training the quantizer on them first if the index is empty and not trained.
+    fn add_batch(&mut self, data_batch: Vec<Vec<f32>>) -> Result<(), NNSearchError> {
+        for data in &data_batch {
+            validate_dim(self.dim, data)?;
+        }
+        if self.quantized.as_ref().is_some_and(|quantized| !quantized.quantizer.is_trained()) {
+            self.train(&data_batch)?;
+        }
+        for data in data_batch {
+            self.add(data)?;
+        }
         Ok(())
     }
-    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<usize>, ()> {
-        let scores = self.points
+    /// Fails with `NotTrained` if the quantizer is not trained.
+    fn add_with_metadata(&mut self, mut data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError> {
+        validate_dim(self.dim, &data)?;
+        let id = self.points.len();
+        if let Some(quantized) = &mut self.quantized {
+            quantized.insert(id, &data)?;
+            if !quantized.keeps_vectors() {
+                data = vec![];
+            }
+        }
+        self.points.push(Some(data));
+        self.metadata.push(metadata);
+        Ok(id)
+    }
+    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_filtered(query, k, &|_| true)
+    }
+    fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        let neighbors = self.points
             .iter()
-            .map(|vec| self.distance.compute(&query, &vec).unwrap())
-            .collect::<Vec<_>>();
-        let mut idx = (0..scores.len()).collect::<Vec<usize>>();
-        idx.sort_by(|&i, &j| scores[i].partial_cmp(&scores[j]).unwrap());
-        Ok(idx[..k].to_vec())
+            .enumerate()
+            .filter(|(id, _)| filter.allows(*id))
+            .filter_map(|(id, point)| point.as_ref().map(|vec| (id, vec)))
+            .map(|(id, vec)| Ok(Neighbor {id, distance: self.cost(&query, id, vec)?}))
+            .collect::<Result<Vec<_>, NNSearchError>>()?;
+        let rerank = self.quantized.as_ref().map_or(0, |quantized| quantized.rerank);
+        rerank_neighbors(neighbors, k, rerank, |id| self.distance.compute(&query, self.points[id].as_ref().unwrap()))
+    }
+    fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_radius_query(self, &query, radius)?;
+        let mut neighbors = vec![];
+        for (id, point) in self.points.iter().enumerate() {
+            if let Some(vec) = point {
+                // NOTE: distances are computed on the full precision vectors if they are kept.
+                let distance = if vec.is_empty() { self.cost(&query, id, vec)? } else { self.distance.compute(&query, vec)? };
+                if distance <= radius {
+                    neighbors.push(Neighbor {id, distance});
+                }
+            }
+        }
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        Ok(neighbors)
+    }
+    fn remove(&mut self, id: usize) -> Result<(), NNSearchError> {
+        match self.points.get_mut(id) {
+            Some(point) if point.is_some() => {
+                *point = None;
+                self.metadata[id] = Metadata::new();
+                if let Some(quantized) = &mut self.quantized {
+                    quantized.remove(id);
+                }
+                Ok(())
+            }
+            _ => Err(NNSearchError::NotFound(id)),
+        }
+    }
+    /// Returns `None` for the items whose full precision vectors are not kept.
+    fn get_vector(&self, id: usize) -> Option<&[f32]> {
+        self.points.get(id).and_then(|point| point.as_deref()).filter(|vec| !vec.is_empty())
+    }
+    fn get_metadata(&self, id: usize) -> Option<&Metadata> {
+        self.points.get(id).and_then(|point| point.as_ref()).map(|_| &self.metadata[id])
+    }
+    fn get_distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &*self.distance
+    }
+    fn dim(&self) -> usize {
+        self.dim
+    }
+    fn len(&self) -> usize {
+        self.points.iter().filter(|point| point.is_some()).count()
     }
 }
 
 //#[derive(Debug)]
 pub struct NSWIndex {
-    dim: usize,
-    graph: Box<dyn GraphOperator>,
+    pub(crate) dim: usize,
+    pub(crate) graph: NavigableSmallWorldGraph,
+    // NOTE: ids are not reused after removal.
+    pub(crate) next_id: usize,
 }
 
 impl NSWIndex {
     pub fn new(dim: usize, distance: Box<dyn PairwiseDistance<f32, f32>>, trial: usize, min_degree: usize) -> Self {
         NSWIndex{
             dim,
-            graph: Box::new(NavigableSmallWorldGraph{
-                trial,
-                min_degree,
-                id2adjacency_ids: HashMap::new(),
-                id2node: HashMap::new(),
-                distance,
-            }),
+            graph: NavigableSmallWorldGraph::new(distance, trial, min_degree),
+            next_id: 0,
+        }
+    }
+
+    /// Creates an index whose graph stores the vectors quantized by `quantizer_type` and computes distances on the codes.
+    /// If `rerank` is positive, the full precision vectors are also kept to rerank the top `rerank` candidates.
+    pub fn new_quantized(dim: usize, distance: Box<dyn PairwiseDistance<f32, f32>>, trial: usize, min_degree: usize, quantizer_type: ScalarQuantizerType, rerank: usize) -> Self {
+        let mut index = NSWIndex::new(dim, distance, trial, min_degree);
+        index.graph.quantized = Some(ScalarQuantizedVectors::new(quantizer_type, dim, rerank));
+        index
+    }
+
+    /// Trains the quantizer on `data`, which is done on the first batch given to `add_batch` if the index is empty.
+    /// Fails with `ValueError` if items are already indexed. Does nothing for the index without quantization.
+    pub fn train(&mut self, data: &[Vec<f32>]) -> Result<(), NNSearchError> {
+        if !self.graph.id2node.is_empty() {
+            return Err(NNSearchError::ValueError("Index with items cannot be trained".to_string()))
+        }
+        match &mut self.graph.quantized {
+            Some(quantized) => quantized.quantizer.train(data),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the vector of the item, which is decoded from its code if the full precision vector is not kept.
+    pub fn reconstruct(&self, id: usize) -> Option<Vec<f32>> {
+        self.graph.get_node(&id)?;
+        self.graph.vector(id).map(|vec| vec.into_owned())
+    }
+
+    fn rerank(&self) -> usize {
+        self.graph.quantized.as_ref().map_or(0, |quantized| quantized.rerank)
+    }
+
+    // NOTE: the graph is searched for the candidates to be reranked, which are never more than the items.
+    fn num_candidates(&self, k: usize) -> usize {
+        k.max(self.rerank()).min(self.len())
+    }
+
+    fn rerank_neighbors(&self, query: &[f32], neighbors: Vec<Neighbor>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        rerank_neighbors(neighbors, k, self.rerank(), |id| self.graph.exact_cost(query, id))
+    }
+
+    /// Replaces the vector of the item and reconnects its edges, failing with `NotFound` if the id does not exist.
+    pub fn update(&mut self, id: usize, data: Vec<f32>) -> Result<(), NNSearchError> {
+        validate_dim(self.dim, &data)?;
+        let metadata = match self.graph.get_node(&id) {
+            Some(node) => node.metadata.clone(),
+            None => return Err(NNSearchError::NotFound(id)),
+        };
+        self.graph.update_node(VectorNode::with_metadata(id, data, metadata))
+    }
+
+    /// Replaces the vector of the item if the id exists, and adds it with the id otherwise.
+    /// A removed item is added again with the id. The metadata of the existing item is kept.
+    pub fn upsert(&mut self, id: usize, data: Vec<f32>) -> Result<(), NNSearchError> {
+        validate_dim(self.dim, &data)?;
+        if let Some(node) = self.graph.id2node.get(&id) {
+            let metadata = node.metadata.clone();
+            return self.graph.update_node(VectorNode::with_metadata(id, data, metadata))
+        }
+        self.graph.add_node(VectorNode::new(id, data))?;
+        self.next_id = self.next_id.max(id + 1);
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), NNSearchError> {
+        let mut body = vec![];
+        write_distance(&mut body, &*self.graph.distance)?;
+        write_u64(&mut body, self.dim as u64)?;
+        write_quantized(&mut body, &self.graph.quantized)?;
+        write_u64(&mut body, self.graph.trial as u64)?;
+        write_u64(&mut body, self.graph.min_degree as u64)?;
+        write_u64(&mut body, self.next_id as u64)?;
+        write_u64(&mut body, self.graph.id2node.len() as u64)?;
+        let mut ids: Vec<&usize> = self.graph.id2node.keys().collect();
+        ids.sort();
+        for id in ids {
+            write_u64(&mut body, *id as u64)?;
+            write_u8(&mut body, self.graph.tombstones.contains(id) as u8)?;
+            write_vector(&mut body, &self.graph.quantized, *id, &self.graph.id2node[id].vec)?;
+            write_metadata(&mut body, &self.graph.id2node[id].metadata)?;
+            let adjacency_ids = self.graph.id2adjacency_ids.get(id).map(|ids| ids.as_slice()).unwrap_or(&[]);
+            write_u64(&mut body, adjacency_ids.len() as u64)?;
+            for adjacency_id in adjacency_ids {
+                write_u64(&mut body, *adjacency_id as u64)?;
+            }
         }
+        write_index_file(path, NSW_INDEX_TAG, &body)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, NNSearchError> {
+        Self::read_from(&mut read_index_body(path, NSW_INDEX_TAG)?.as_slice())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self, NNSearchError> {
+        let distance = read_distance(reader)?;
+        let dim = read_u64(reader)? as usize;
+        let quantized = read_quantized(reader, dim)?;
+        let trial = read_u64(reader)? as usize;
+        let min_degree = read_u64(reader)? as usize;
+        let next_id = read_u64(reader)? as usize;
+        let num_nodes = read_u64(reader)? as usize;
+        let mut index = NSWIndex::new(dim, distance, trial, min_degree);
+        index.graph.quantized = quantized;
+        index.next_id = next_id;
+        for _ in 0..num_nodes {
+            let id = read_u64(reader)? as usize;
+            if index.graph.id2node.contains_key(&id) {
+                return Err(NNSearchError::ValueError(format!("Duplicate node id in the index file: {}", id)))
+            }
+            if read_u8(reader)? != 0 {
+                index.graph.tombstones.insert(id);
+            }
+            let vec = read_vector(reader, &mut index.graph.quantized, id, dim)?;
+            let metadata = read_metadata(reader)?;
+            let num_adjacency_ids = read_u64(reader)? as usize;
+            let mut adjacency_ids = Vec::with_capacity(num_adjacency_ids.min(MAX_RESERVED_ITEMS));
+            for _ in 0..num_adjacency_ids {
+                adjacency_ids.push(read_u64(reader)? as usize);
+            }
+            if !adjacency_ids.is_empty() {
+                index.graph.id2adjacency_ids.insert(id, adjacency_ids);
+            }
+            index.graph.id2node.insert(id, VectorNode::with_metadata(id, vec, metadata));
+        }
+        index.validate_graph()?;
+        index.graph.reset_live_ids();
+        Ok(index)
+    }
+
+    /// Checks that the edges and the tombstones refer to existing nodes and `next_id` exceeds every node id,
+    /// so that a broken file is rejected on load instead of failing on search or insertion.
+    fn validate_graph(&self) -> Result<(), NNSearchError> {
+        let id2node = &self.graph.id2node;
+        let dangling_edge = self.graph.id2adjacency_ids
+            .iter()
+            .any(|(id, adjacency_ids)| !id2node.contains_key(id) || adjacency_ids.iter().any(|adjacency_id| !id2node.contains_key(adjacency_id)));
+        if dangling_edge || self.graph.tombstones.iter().any(|id| !id2node.contains_key(id)) {
+            return Err(NNSearchError::ValueError("Invalid adjacency lists or tombstones in the index file".to_string()))
+        }
+        if id2node.keys().any(|&id| id >= self.next_id) {
+            return Err(NNSearchError::ValueError(format!("Next id {} must exceed the node ids in the index file", self.next_id)))
+        }
+        Ok(())
     }
 }
 
 impl VectorIndexOperator for NSWIndex {
-    fn add(&mut self, data: Vec<f32>) -> Result<(), ()> {
-        if data.len() != self.dim {
-            // TODO
-            panic!("invalid dim");
+    /// Adds the vectors, training the quantizer on them first if the index is empty and not trained.
+    fn add_batch(&mut self, data_batch: Vec<Vec<f32>>) -> Result<(), NNSearchError> {
+        for data in &data_batch {
+            validate_dim(self.dim, data)?;
+        }
+        if self.graph.quantized.as_ref().is_some_and(|quantized| !quantized.quantizer.is_trained()) {
+            self.train(&data_batch)?;
         }
+        let num_nodes = data_batch.len();
+        let nodes = data_batch
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| VectorNode::new(self.next_id + i, data))
+            .collect();
+        self.graph.add_nodes(nodes)?;
+        self.next_id += num_nodes;
+        Ok(())
+    }
+    /// Fails with `NotTrained` if the quantizer is not trained.
+    fn add_with_metadata(&mut self, data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError> {
+        validate_dim(self.dim, &data)?;
+        let id = self.next_id;
         self.graph.add_node(
-            VectorNode{id: self.graph.len(), vec: data}
-        )
+            VectorNode::with_metadata(id, data, metadata)
+        )?;
+        self.next_id += 1;
+        Ok(id)
+    }
+    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_with_params(query, k, &SearchParams::default())
+    }
+    fn search_with_params(&self, query: Vec<f32>, k: usize, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        let query = VectorNode::new(usize::MAX, query);
+        let neighbors = self.graph.search_with_params(&query, self.num_candidates(k), params)?;
+        self.rerank_neighbors(&query.vec, neighbors, k)
+    }
+    fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        let query = VectorNode::new(usize::MAX, query);
+        let neighbors = self.graph.search_filtered(&query, self.num_candidates(k), filter)?;
+        self.rerank_neighbors(&query.vec, neighbors, k)
+    }
+    fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_radius_query(self, &query, radius)?;
+        self.graph.search_radius(&VectorNode::new(usize::MAX, query), radius)
+    }
+    fn remove(&mut self, id: usize) -> Result<(), NNSearchError> {
+        self.graph.remove_node(id)
+    }
+    fn compact(&mut self) {
+        self.graph.compact()
+    }
+    /// Returns `None` for the items whose full precision vectors are not kept. Use `reconstruct` to decode them.
+    fn get_vector(&self, id: usize) -> Option<&[f32]> {
+        self.graph.get_node(&id).map(|node| node.vec.as_slice()).filter(|vec| !vec.is_empty())
+    }
+    fn get_metadata(&self, id: usize) -> Option<&Metadata> {
+        self.graph.get_node(&id).map(|node| &node.metadata)
+    }
+    fn get_distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &*self.graph.distance
+    }
+    fn dim(&self) -> usize {
+        self.dim
     }
-    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<usize>, ()> {
-        let knn = self.graph.search_nearest_neighbor(&VectorNode{id: usize::MAX, vec: query}, k);
-        Ok(knn)
+    fn len(&self) -> usize {
+        self.graph.len()
+    }
+}
+
+//#[derive(Debug)]
+pub struct HNSWIndex {
+    dim: usize,
+    graph: HierarchicalNavigableSmallWorldGraph,
+    next_id: usize,
+}
+
+impl HNSWIndex {
+    pub fn new(dim: usize, distance: Box<dyn PairwiseDistance<f32, f32>>, max_degree: usize, ef_construction: usize, ef_search: usize) -> Self {
+        HNSWIndex{
+            dim,
+            graph: HierarchicalNavigableSmallWorldGraph::new(distance, max_degree, ef_construction, ef_search),
+            next_id: 0,
+        }
+    }
+}
+
+impl VectorIndexOperator for HNSWIndex {
+    fn add_with_metadata(&mut self, data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError> {
+        validate_dim(self.dim, &data)?;
+        let id = self.next_id;
+        self.graph.add_node(
+            VectorNode::with_metadata(id, data, metadata)
+        )?;
+        self.next_id += 1;
+        Ok(id)
+    }
+    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_with_params(query, k, &SearchParams::default())
+    }
+    fn search_with_params(&self, query: Vec<f32>, k: usize, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        self.graph.search_with_params(&VectorNode::new(usize::MAX, query), k, params)
+    }
+    fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        self.graph.search_filtered(&VectorNode::new(usize::MAX, query), k, filter)
+    }
+    fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_radius_query(self, &query, radius)?;
+        self.graph.search_radius(&VectorNode::new(usize::MAX, query), radius)
+    }
+    fn remove(&mut self, id: usize) -> Result<(), NNSearchError> {
+        self.graph.remove_node(id)
+    }
+    fn compact(&mut self) {
+        self.graph.compact()
+    }
+    fn get_vector(&self, id: usize) -> Option<&[f32]> {
+        self.graph.get_node(&id).map(|node| node.vec.as_slice())
+    }
+    fn get_metadata(&self, id: usize) -> Option<&Metadata> {
+        self.graph.get_node(&id).map(|node| &node.metadata)
+    }
+    fn get_distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &*self.graph.distance
+    }
+    fn dim(&self) -> usize {
+        self.dim
+    }
+    fn len(&self) -> usize {
+        self.graph.len()
     }
 }
 
@@ -78,20 +724,29 @@ impl VectorIndexOperator for NSWIndex {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::linalg::distance::Euclidean;
+    use crate::filter::IdBitSet;
+    use crate::linalg::distance::{Euclidean, Minkowski};
+    use crate::linalg::utils::generate_matrix;
+    use crate::metadata::MetadataValue;
+
+    fn ids(neighbors: &[Neighbor]) -> Vec<usize> {
+        neighbors.iter().map(|nn| nn.id).collect()
+    }
 
     #[test]
     fn test_naive_index() {
         
-        let mut index = NaiveKnnIndex{ dim: 2, distance: Box::new(Euclidean{}), points: vec![] };
+        let mut index = NaiveKnnIndex::new(2, Box::new(Euclidean{}));
         index.add(vec![0.1, 0.2]).unwrap();
         index.add(vec![0.1, 0.1]).unwrap();
         let result = index.search(vec![0.1, 0.1], 2).unwrap();
-        assert_eq!(result, vec![1, 0]);
+        assert_eq!(ids(&result), vec![1, 0]);
+        assert_eq!(result[0].distance, 0.0);
+        assert!((result[1].distance - 0.1).abs() < 1e-6);
         let result = index.search(vec![1.0, 2.0], 2).unwrap();
-        assert_eq!(result, vec![0, 1]);
+        assert_eq!(ids(&result), vec![0, 1]);
         let result = index.search(vec![1.0, 2.0], 1).unwrap();
-        assert_eq!(result, vec![0]);
+        assert_eq!(ids(&result), vec![0]);
     }
 
     #[test]
@@ -101,11 +756,13 @@ mod tests {
         index.add(vec![0.1, 0.2]).unwrap();
         index.add(vec![0.1, 0.1]).unwrap();
         let result = index.search(vec![0.1, 0.1], 2).unwrap();
-        assert_eq!(result, vec![1, 0]);
+        assert_eq!(ids(&result), vec![1, 0]);
+        assert_eq!(result[0].distance, 0.0);
+        assert!((result[1].distance - 0.1).abs() < 1e-6);
         let result = index.search(vec![1.0, 2.0], 2).unwrap();
-        assert_eq!(result, vec![0, 1]);
+        assert_eq!(ids(&result), vec![0, 1]);
         let result = index.search(vec![1.0, 2.0], 1).unwrap();
-        assert_eq!(result, vec![0]);
+        assert_eq!(ids(&result), vec![0]);
     }
 
     #[test]
@@ -118,6 +775,558 @@ mod tests {
         index.add(vec![0.1, 0.5]).unwrap();
         index.add(vec![0.1, 0.6]).unwrap();
         index.add(vec![0.1, 0.7]).unwrap();
-        index.search(vec![0.1, 0.1], 2).unwrap();
+        let result = index.search(vec![0.1, 0.1], 2).unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(result[0].distance <= result[1].distance);
+    }
+
+    fn nsw_recall(index: &NSWIndex, k: usize) -> f32 {
+        let mut num_hit = 0;
+        let ids_in_index: Vec<usize> = index.graph.id2node.keys().cloned().collect();
+        let queries = generate_matrix(20, index.dim());
+        for query in &queries {
+            let cost = |id: &usize| index.get_distance().compute(query, index.get_vector(*id).unwrap()).unwrap();
+            let mut expected = ids_in_index.clone();
+            expected.sort_by(|a, b| cost(a).partial_cmp(&cost(b)).unwrap());
+            let result = index.search(query.clone(), k).unwrap();
+            num_hit += result.iter().filter(|nn| expected[..k].contains(&nn.id)).count();
+        }
+        num_hit as f32 / (queries.len() * k) as f32
+    }
+
+    #[test]
+    fn test_nsw_update() {
+        let mut index = NSWIndex::new(4, Box::new(Euclidean{}), 5, 8);
+        let mat = generate_matrix(600, 4);
+        index.add_batch(mat[..300].to_vec()).unwrap();
+        let recall_before = nsw_recall(&index, 10);
+        // replace vectors of two thirds of the items
+        for id in 0..200 {
+            index.update(id, mat[300 + id].clone()).unwrap();
+        }
+        assert_eq!(index.len(), 300);
+        assert_eq!(index.get_vector(10), Some(mat[310].as_slice()));
+        let recall_after = nsw_recall(&index, 10);
+        assert!(recall_after > 0.8 && recall_after > recall_before - 0.1, "before={}, after={}", recall_before, recall_after);
+        for (id, adjacency_ids) in &index.graph.id2adjacency_ids {
+            assert!(!adjacency_ids.contains(id));
+        }
+    }
+
+    #[test]
+    fn test_nsw_upsert() {
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 4);
+        index.add_batch(generate_matrix(10, 2)).unwrap();
+        assert_eq!(index.update(10, vec![0.5, 0.5]).unwrap_err(), NNSearchError::NotFound(10));
+        assert_eq!(index.update(3, vec![0.5]).unwrap_err(), NNSearchError::DimensionMismatch {expected: 2, actual: 1});
+        index.upsert(3, vec![0.5, 0.5]).unwrap();
+        assert_eq!(index.get_vector(3), Some(&[0.5, 0.5][..]));
+        index.upsert(20, vec![0.6, 0.6]).unwrap();
+        assert_eq!(index.len(), 11);
+        // ids are assigned after the upserted id
+        index.add(vec![0.7, 0.7]).unwrap();
+        assert_eq!(index.get_vector(21), Some(&[0.7, 0.7][..]));
+        // a removed item is restored by upsert but not by update
+        index.remove(5).unwrap();
+        assert_eq!(index.update(5, vec![0.8, 0.8]).unwrap_err(), NNSearchError::NotFound(5));
+        index.upsert(5, vec![0.8, 0.8]).unwrap();
+        assert_eq!(index.len(), 12);
+        assert_eq!(ids(&index.search(vec![0.8, 0.8], 12).unwrap())[0], 5);
+    }
+
+    #[test]
+    fn test_hnsw_index() {
+        let mut index = HNSWIndex::new(2, Box::new(Euclidean{}), 4, 16, 8);
+        index.add(vec![0.1, 0.2]).unwrap();
+        index.add(vec![0.1, 0.1]).unwrap();
+        index.add(vec![0.5, 0.5]).unwrap();
+        let result = index.search(vec![0.1, 0.1], 2).unwrap();
+        assert_eq!(ids(&result), vec![1, 0]);
+        let result = index.search(vec![1.0, 2.0], 3).unwrap();
+        assert_eq!(ids(&result), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn test_save_and_load_naive_index() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_naive_index.bin");
+        let mut index = NaiveKnnIndex::new(2, Box::new(Euclidean{}));
+        index.add(vec![0.1, 0.2]).unwrap();
+        index.add(vec![0.1, 0.1]).unwrap();
+        index.save(&path).unwrap();
+        let loaded = NaiveKnnIndex::load(&path).unwrap();
+        assert_eq!(loaded.points, index.points);
+        assert_eq!(ids(&loaded.search(vec![0.1, 0.1], 2).unwrap()), vec![1, 0]);
+        assert!(NSWIndex::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_and_load_nsw_index() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_nsw_index.bin");
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 2);
+        index.add_batch(generate_matrix(20, 2)).unwrap();
+        index.save(&path).unwrap();
+        let loaded = NSWIndex::load(&path).unwrap();
+        assert_eq!(loaded.graph.id2adjacency_ids, index.graph.id2adjacency_ids);
+        let loaded = load_index(&path).unwrap();
+        for id in 0..20 {
+            assert_eq!(loaded.get_vector(id), index.get_vector(id));
+        }
+        assert_eq!(loaded.search(vec![0.5, 0.5], 5).unwrap().len(), 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_index_with_untyped_distance() {
+        #[derive(Debug)]
+        struct Untyped;
+        impl PairwiseDistance<f32, f32> for Untyped {
+            fn compute_innter(&self, _p1: &[f32], _p2: &[f32]) -> f32 {
+                0.0
+            }
+        }
+        let path = std::env::temp_dir().join("nnsearch_test_save_index_with_untyped_distance.bin");
+        let index = NaiveKnnIndex::new(2, Box::new(Untyped{}));
+        assert!(index.save(&path).is_err());
+    }
+
+    #[test]
+    fn test_index_errors() {
+        let indexes: Vec<Box<dyn VectorIndexOperator>> = vec![
+            Box::new(NaiveKnnIndex::new(2, Box::new(Euclidean{}))),
+            Box::new(NSWIndex::new(2, Box::new(Euclidean{}), 3, 4)),
+            Box::new(HNSWIndex::new(2, Box::new(Euclidean{}), 4, 16, 8)),
+        ];
+        for mut index in indexes {
+            assert_eq!(index.search(vec![0.1, 0.1], 1).unwrap_err(), NNSearchError::EmptyIndex);
+            assert_eq!(index.add(vec![0.1]).unwrap_err(), NNSearchError::DimensionMismatch {expected: 2, actual: 1});
+            index.add_batch(vec![vec![0.1, 0.2], vec![0.1, 0.1]]).unwrap();
+            assert_eq!(index.search(vec![0.1, 0.1, 0.1], 1).unwrap_err(), NNSearchError::DimensionMismatch {expected: 2, actual: 3});
+            assert_eq!(index.search(vec![0.1, 0.1], 3).unwrap_err(), NNSearchError::KTooLarge {k: 3, len: 2});
+            assert_eq!(index.search(vec![0.1, 0.1], 2).unwrap().len(), 2);
+            assert!(matches!(index.add(vec![f32::NAN, 0.1]), Err(NNSearchError::ValueError(_))));
+            assert!(matches!(index.add(vec![0.1, f32::INFINITY]), Err(NNSearchError::ValueError(_))));
+            assert!(matches!(index.search(vec![f32::NAN, 0.1], 1), Err(NNSearchError::ValueError(_))));
+            assert!(matches!(index.search_radius(vec![f32::NAN, 0.1], 1.0), Err(NNSearchError::ValueError(_))));
+            assert_eq!(index.len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_remove() {
+        let indexes: Vec<Box<dyn VectorIndexOperator>> = vec![
+            Box::new(NaiveKnnIndex::new(2, Box::new(Euclidean{}))),
+            Box::new(NSWIndex::new(2, Box::new(Euclidean{}), 3, 4)),
+            Box::new(HNSWIndex::new(2, Box::new(Euclidean{}), 4, 16, 8)),
+        ];
+        for mut index in indexes {
+            index.add_batch(generate_matrix(30, 2)).unwrap();
+            let query = index.get_vector(7).unwrap().to_vec();
+            index.remove(7).unwrap();
+            assert_eq!(index.len(), 29);
+            assert_eq!(index.get_vector(7), None);
+            assert_eq!(index.remove(7).unwrap_err(), NNSearchError::NotFound(7));
+            assert!(!ids(&index.search(query.clone(), 29).unwrap()).contains(&7));
+            assert_eq!(index.search(query.clone(), 30).unwrap_err(), NNSearchError::KTooLarge {k: 30, len: 29});
+            index.compact();
+            // ids are not reused
+            index.add(query.clone()).unwrap();
+            assert_eq!(index.get_vector(30), Some(query.as_slice()));
+            assert_eq!(index.search(query, 30).unwrap()[0].id, 30);
+        }
+    }
+
+    #[test]
+    fn test_save_and_load_removed_items() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_removed_items.bin");
+        let mut index = NaiveKnnIndex::new(2, Box::new(Euclidean{}));
+        index.add_batch(vec![vec![0.1, 0.2], vec![0.1, 0.1], vec![0.3, 0.3]]).unwrap();
+        index.remove(1).unwrap();
+        index.save(&path).unwrap();
+        let loaded = NaiveKnnIndex::load(&path).unwrap();
+        assert_eq!(loaded.points, index.points);
+        assert_eq!(ids(&loaded.search(vec![0.1, 0.1], 2).unwrap()), vec![0, 2]);
+
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 2);
+        index.add_batch(generate_matrix(20, 2)).unwrap();
+        index.remove(19).unwrap();
+        index.remove(3).unwrap();
+        index.save(&path).unwrap();
+        let mut loaded = NSWIndex::load(&path).unwrap();
+        assert_eq!(loaded.graph.tombstones, index.graph.tombstones);
+        assert_eq!(loaded.len(), 18);
+        assert_eq!(loaded.get_vector(3), None);
+        loaded.add(vec![0.5, 0.5]).unwrap();
+        assert_eq!(loaded.get_vector(20), Some(&[0.5, 0.5][..]));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_search_radius() {
+        let mat = generate_matrix(300, 2);
+        let mut naive = NaiveKnnIndex::new(2, Box::new(Euclidean{}));
+        naive.add_batch(mat.clone()).unwrap();
+        let indexes: Vec<Box<dyn VectorIndexOperator>> = vec![
+            Box::new(NSWIndex::new(2, Box::new(Euclidean{}), 3, 8)),
+            Box::new(HNSWIndex::new(2, Box::new(Euclidean{}), 8, 32, 16)),
+        ];
+        let query = vec![0.5, 0.5];
+        let expected = naive.search_radius(query.clone(), 0.15).unwrap();
+        assert!(expected.len() > 10);
+        assert!(expected.iter().all(|nn| nn.distance <= 0.15));
+        assert!(expected.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+        assert_eq!(expected.len(), naive.search(query.clone(), 300).unwrap().iter().filter(|nn| nn.distance <= 0.15).count());
+        assert!(naive.search_radius(vec![10.0, 10.0], 0.15).unwrap().is_empty());
+        assert!(naive.search_radius(vec![0.5], 0.15).is_err());
+        assert!(naive.search_radius(query.clone(), f32::NAN).is_err());
+        for mut index in indexes {
+            index.add_batch(mat.clone()).unwrap();
+            assert_eq!(index.search_radius(query.clone(), 0.15).unwrap(), expected);
+            index.remove(expected[0].id).unwrap();
+            assert_eq!(index.search_radius(query.clone(), 0.15).unwrap(), expected[1..].to_vec());
+            assert!(index.search_radius(vec![10.0, 10.0], 0.15).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_search_filtered() {
+        let mat = generate_matrix(500, 4);
+        let indexes: Vec<Box<dyn VectorIndexOperator>> = vec![
+            Box::new(NaiveKnnIndex::new(4, Box::new(Euclidean{}))),
+            Box::new(NSWIndex::new(4, Box::new(Euclidean{}), 3, 8)),
+            Box::new(HNSWIndex::new(4, Box::new(Euclidean{}), 8, 32, 16)),
+        ];
+        // only 5% of the items are allowed
+        let allowed: IdBitSet = (0..500).filter(|id| id % 20 == 3).collect();
+        for mut index in indexes {
+            index.add_batch(mat.clone()).unwrap();
+            let result = index.search_filtered(mat[0].clone(), 10, &allowed).unwrap();
+            assert_eq!(result.len(), 10);
+            assert!(result.iter().all(|nn| allowed.contains(nn.id)));
+            assert!(result.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+            let result = index.search_filtered(mat[0].clone(), 10, &|id| id == 1 || id == 2).unwrap();
+            assert_eq!(ids(&result).into_iter().collect::<std::collections::HashSet<_>>(), vec![1, 2].into_iter().collect());
+            index.remove(3).unwrap();
+            assert!(!ids(&index.search_filtered(mat[3].clone(), 10, &allowed).unwrap()).contains(&3));
+        }
+    }
+
+    fn product(id: usize) -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.insert("tenant".to_string(), MetadataValue::STRING(if id % 2 == 1 { "acme" } else { "other" }.to_string()));
+        metadata.insert("stock".to_string(), MetadataValue::INT((id % 10) as i64));
+        metadata
+    }
+
+    #[test]
+    fn test_search_with_metadata() {
+        let mat = generate_matrix(300, 4);
+        let indexes: Vec<Box<dyn VectorIndexOperator>> = vec![
+            Box::new(NaiveKnnIndex::new(4, Box::new(Euclidean{}))),
+            Box::new(NSWIndex::new(4, Box::new(Euclidean{}), 3, 8)),
+        ];
+        let filter = AttributeFilter::AND(vec![
+            AttributeFilter::EQ("tenant".to_string(), MetadataValue::STRING("acme".to_string())),
+            AttributeFilter::RANGE {field: "stock".to_string(), min: Some(9.0), max: None},
+        ]);
+        for mut index in indexes {
+            for (id, vec) in mat.iter().enumerate() {
+                index.add_with_metadata(vec.clone(), product(id)).unwrap();
+            }
+            index.add(vec![0.5, 0.5, 0.5, 0.5]).unwrap();
+            assert_eq!(index.get_metadata(3), Some(&product(3)));
+            assert_eq!(index.get_metadata(300), Some(&Metadata::new()));
+            // only ids ending with 9 match
+            let result = index.search_with_metadata(mat[0].clone(), 10, Some(&filter)).unwrap();
+            assert_eq!(result.len(), 10);
+            assert!(result.iter().all(|(nn, metadata)| nn.id % 10 == 9 && **metadata == product(nn.id)));
+            let result = index.search_with_metadata(mat[0].clone(), 3, None).unwrap();
+            assert_eq!(result[0].0.id, 0);
+            assert_eq!(result[0].1, &product(0));
+            index.remove(9).unwrap();
+            assert_eq!(index.get_metadata(9), None);
+        }
+    }
+
+    #[test]
+    fn test_load_inconsistent_nsw_index() {
+        let path = std::env::temp_dir().join("nnsearch_test_load_inconsistent_nsw_index.bin");
+        let body = |index: &NSWIndex| {
+            index.save(&path).unwrap();
+            read_index_body(&path, NSW_INDEX_TAG).unwrap()
+        };
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 2);
+        let empty_body = body(&index);
+        index.add(vec![0.1, 0.2]).unwrap();
+        let node_body = body(&index);
+        assert!(NSWIndex::read_from(&mut node_body.as_slice()).is_ok());
+
+        // the same node is written twice
+        let header_size = empty_body.len() - 8;
+        let mut duplicate_body = node_body[..header_size].to_vec();
+        duplicate_body.extend(&2u64.to_le_bytes());
+        duplicate_body.extend(&node_body[empty_body.len()..]);
+        duplicate_body.extend(&node_body[empty_body.len()..]);
+        assert!(matches!(NSWIndex::read_from(&mut duplicate_body.as_slice()), Err(NNSearchError::ValueError(_))));
+
+        index.add(vec![0.3, 0.4]).unwrap();
+        index.graph.id2adjacency_ids.get_mut(&0).unwrap().push(5);
+        assert!(matches!(NSWIndex::read_from(&mut body(&index).as_slice()), Err(NNSearchError::ValueError(_))));
+        index.graph.id2adjacency_ids.get_mut(&0).unwrap().pop();
+
+        index.next_id = 1;
+        assert!(matches!(NSWIndex::read_from(&mut body(&index).as_slice()), Err(NNSearchError::ValueError(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_load_corrupt_counts() {
+        // counts which cannot be allocated fail on reading the missing items
+        let huge = (1u64 << 60).to_le_bytes();
+        let mut naive_body = vec![];
+        write_distance(&mut naive_body, &Euclidean{}).unwrap();
+        naive_body.extend(&2u64.to_le_bytes());
+        naive_body.push(0);
+        naive_body.extend(&huge);
+        assert!(matches!(NaiveKnnIndex::read_from(&mut naive_body.as_slice()), Err(NNSearchError::IoError(_))));
+
+        let path = std::env::temp_dir().join("nnsearch_test_load_corrupt_counts.bin");
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 2);
+        index.add(vec![0.1, 0.2]).unwrap();
+        index.save(&path).unwrap();
+        let mut nsw_body = read_index_body(&path, NSW_INDEX_TAG).unwrap();
+        // the number of adjacency ids of the only node is at the end
+        let len = nsw_body.len();
+        nsw_body[len - 8..].copy_from_slice(&huge);
+        assert!(matches!(NSWIndex::read_from(&mut nsw_body.as_slice()), Err(NNSearchError::IoError(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_and_load_metadata() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_metadata.bin");
+        let mut index = NaiveKnnIndex::new(2, Box::new(Euclidean{}));
+        index.add_with_metadata(vec![0.1, 0.2], product(1)).unwrap();
+        index.add(vec![0.1, 0.1]).unwrap();
+        index.save(&path).unwrap();
+        let loaded = NaiveKnnIndex::load(&path).unwrap();
+        assert_eq!(loaded.metadata, index.metadata);
+
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 2);
+        for (id, vec) in generate_matrix(10, 2).into_iter().enumerate() {
+            index.add_with_metadata(vec, product(id)).unwrap();
+        }
+        index.update(4, vec![0.5, 0.5]).unwrap();
+        assert_eq!(index.get_metadata(4), Some(&product(4)));
+        index.save(&path).unwrap();
+        let loaded = load_index(&path).unwrap();
+        for id in 0..10 {
+            assert_eq!(loaded.get_metadata(id), Some(&product(id)));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_search_batch() {
+        let mat = generate_matrix(200, 3);
+        let indexes: Vec<Box<dyn VectorIndexOperator>> = vec![
+            Box::new(NaiveKnnIndex::new(3, Box::new(Euclidean{}))),
+            Box::new(NSWIndex::new(3, Box::new(Euclidean{}), 3, 8)),
+        ];
+        for mut index in indexes {
+            index.add_batch(mat.clone()).unwrap();
+            let queries = mat[..20].to_vec();
+            let results = index.search_batch(&queries, 5).unwrap();
+            assert_eq!(results.len(), 20);
+            for (query, result) in queries.iter().zip(&results) {
+                assert_eq!(*result, index.search(query.clone(), 5).unwrap());
+            }
+            let array = Array2::from_shape_vec((20, 3), queries.concat()).unwrap();
+            assert_eq!(index.search_batch_array(&array, 5).unwrap(), results);
+            let mut queries = queries;
+            queries[3] = vec![0.1];
+            assert_eq!(index.search_batch(&queries, 5).unwrap_err(), NNSearchError::DimensionMismatch {expected: 3, actual: 1});
+        }
+    }
+
+    // Euclidean distance which counts the evaluations.
+    #[derive(Debug)]
+    struct CountingEuclidean {
+        count: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl PairwiseDistance<f32, f32> for CountingEuclidean {
+        fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
+            self.count.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Euclidean{}.compute_innter(p1, p2)
+        }
+    }
+
+    #[test]
+    fn test_search_with_params() {
+        let mat = generate_matrix(500, 3);
+        let count = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut naive = NaiveKnnIndex::new(3, Box::new(Euclidean{}));
+        naive.add_batch(mat.clone()).unwrap();
+        // NOTE: HNSW does not count the evaluations on the upper layers.
+        let indexes: Vec<(Box<dyn VectorIndexOperator>, bool)> = vec![
+            (Box::new(NSWIndex::new(3, Box::new(CountingEuclidean {count: count.clone()}), 3, 8)), true),
+            (Box::new(HNSWIndex::new(3, Box::new(CountingEuclidean {count: count.clone()}), 8, 32, 16)), false),
+        ];
+        let recall = |index: &dyn VectorIndexOperator, params: &SearchParams| {
+            let mut num_found = 0;
+            for query in &mat[..20] {
+                let expected = ids(&naive.search(query.clone(), 10).unwrap());
+                let result = index.search_with_params(query.clone(), 10, params).unwrap();
+                num_found += result.iter().filter(|nn| expected.contains(&nn.id)).count();
+            }
+            num_found as f32 / 200.0
+        };
+        for (mut index, counts_all_evals) in indexes {
+            index.add_batch(mat.clone()).unwrap();
+            // the default parameters search as `search` does
+            assert_eq!(index.search_with_params(mat[7].clone(), 10, &SearchParams::default()).unwrap(), index.search(mat[7].clone(), 10).unwrap());
+            let wide = SearchParams {ef: Some(100), ..SearchParams::default()};
+            assert!(recall(&*index, &wide) >= recall(&*index, &SearchParams::default()));
+            assert!(recall(&*index, &wide) > 0.9);
+            let result = index.search_with_params(mat[7].clone(), 10, &SearchParams {restarts: Some(5), seed: 7, ..SearchParams::default()}).unwrap();
+            assert_eq!(result.len(), 10);
+
+            let limited = SearchParams {max_distance_evals: Some(30), ..SearchParams::default()};
+            count.store(0, std::sync::atomic::Ordering::SeqCst);
+            let result = index.search_with_params(mat[7].clone(), 10, &limited).unwrap();
+            assert!(!result.is_empty() && result.len() <= 10);
+            if counts_all_evals {
+                assert!(count.load(std::sync::atomic::Ordering::SeqCst) <= 30);
+            }
+        }
+        // exhaustive indexes ignore the parameters
+        let params = SearchParams {ef: Some(1), max_distance_evals: Some(1), ..SearchParams::default()};
+        assert_eq!(naive.search_with_params(mat[7].clone(), 10, &params).unwrap(), naive.search(mat[7].clone(), 10).unwrap());
+    }
+
+    #[test]
+    fn test_save_and_load_parameterized_distance() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_parameterized_distance.bin");
+        let mut index = NaiveKnnIndex::new(2, Box::new(Minkowski{p: 3.0}));
+        index.add(vec![0.1, 0.2]).unwrap();
+        index.save(&path).unwrap();
+        let loaded = NaiveKnnIndex::load(&path).unwrap();
+        assert_eq!(loaded.get_distance().distance_type(), Some(DistanceType::MINKOWSKI(3.0)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn quantized_recall(index: &dyn VectorIndexOperator, mat: &[Vec<f32>], k: usize) -> f32 {
+        let exact = {
+            let mut naive = NaiveKnnIndex::new(mat[0].len(), Box::new(Euclidean{}));
+            naive.add_batch(mat.to_vec()).unwrap();
+            naive
+        };
+        let mut hits = 0;
+        for query in &mat[..20] {
+            let expected = ids(&exact.search(query.clone(), k).unwrap());
+            hits += ids(&index.search(query.clone(), k).unwrap()).iter().filter(|id| expected.contains(id)).count();
+        }
+        hits as f32 / (20 * k) as f32
+    }
+
+    #[test]
+    fn test_quantized_index() {
+        let mat = generate_matrix(300, 8);
+        for quantizer_type in [ScalarQuantizerType::INT8, ScalarQuantizerType::FP16] {
+            for rerank in [0, 30] {
+                let mut naive = NaiveKnnIndex::new_quantized(8, Box::new(Euclidean{}), quantizer_type, rerank);
+                let mut nsw = NSWIndex::new_quantized(8, Box::new(Euclidean{}), 3, 8, quantizer_type, rerank);
+                naive.add_batch(mat.clone()).unwrap();
+                nsw.add_batch(mat.clone()).unwrap();
+                assert!(quantized_recall(&naive, &mat, 10) > 0.9);
+                assert!(quantized_recall(&nsw, &mat, 10) > 0.8);
+                for index in [&naive as &dyn VectorIndexOperator, &nsw] {
+                    let neighbors = index.search(mat[5].clone(), 3).unwrap();
+                    assert_eq!(neighbors.len(), 3);
+                    // the distance to the vector itself is exact only after reranking
+                    assert_eq!(neighbors[0].distance == 0.0, rerank > 0);
+                    assert_eq!(index.get_vector(5).is_some(), rerank > 0);
+                    assert!(index.search_radius(mat[5].clone(), 0.1).unwrap().iter().any(|nn| nn.id == 5));
+                }
+                let reconstructed = naive.reconstruct(5).unwrap();
+                assert!(mat[5].iter().zip(&reconstructed).all(|(x, y)| (x - y).abs() < 0.01));
+                assert_eq!(nsw.reconstruct(5), naive.reconstruct(5));
+            }
+        }
+    }
+
+    #[test]
+    fn test_quantized_radius_search_uses_exact_distances() {
+        let mat = generate_matrix(200, 8);
+        let mut naive = NaiveKnnIndex::new_quantized(8, Box::new(Euclidean{}), ScalarQuantizerType::INT8, 10);
+        let mut nsw = NSWIndex::new_quantized(8, Box::new(Euclidean{}), 3, 8, ScalarQuantizerType::INT8, 10);
+        naive.add_batch(mat.clone()).unwrap();
+        nsw.add_batch(mat.clone()).unwrap();
+        for query in &mat[..20] {
+            // the radius is exactly the distance to the 5th neighbor, which its code may exceed
+            let radius = Euclidean{}.compute(query, &mat[naive.search(query.clone(), 5).unwrap()[4].id]).unwrap();
+            let expected = naive.search_radius(query.clone(), radius).unwrap();
+            assert_eq!(expected.len(), 5);
+            assert_eq!(nsw.search_radius(query.clone(), radius).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_quantized_index_training() {
+        let mat = generate_matrix(20, 2);
+        let mut naive = NaiveKnnIndex::new_quantized(2, Box::new(Euclidean{}), ScalarQuantizerType::INT8, 0);
+        assert!(matches!(naive.add(mat[0].clone()), Err(NNSearchError::NotTrained)));
+        let mut nsw = NSWIndex::new_quantized(2, Box::new(Euclidean{}), 3, 2, ScalarQuantizerType::INT8, 0);
+        assert!(matches!(nsw.add(mat[0].clone()), Err(NNSearchError::NotTrained)));
+        nsw.train(&mat).unwrap();
+        nsw.add(mat[0].clone()).unwrap();
+        assert!(nsw.train(&mat).is_err());
+        // FP16 needs no training
+        let mut nsw = NSWIndex::new_quantized(2, Box::new(Euclidean{}), 3, 2, ScalarQuantizerType::FP16, 0);
+        nsw.add(mat[0].clone()).unwrap();
+        assert!(nsw.add(vec![0.0; 3]).is_err());
+        // vectors out of the range of FP16 are rejected before being linked to the graph
+        nsw.add_batch(mat.clone()).unwrap();
+        assert!(matches!(nsw.add(vec![1e5, 0.0]), Err(NNSearchError::ValueError(_))));
+        assert!(matches!(nsw.add_batch(vec![vec![0.1, 0.2], vec![0.0, -1e5]]), Err(NNSearchError::ValueError(_))));
+        assert_eq!(nsw.len(), 21);
+        assert!(nsw.graph.id2adjacency_ids.values().flatten().all(|id| nsw.graph.id2node.contains_key(id)));
+        let mut naive = NaiveKnnIndex::new_quantized(2, Box::new(Euclidean{}), ScalarQuantizerType::FP16, 0);
+        assert!(matches!(naive.add(vec![1e5, 0.0]), Err(NNSearchError::ValueError(_))));
+        assert_eq!(naive.len(), 0);
+    }
+
+    #[test]
+    fn test_save_and_load_quantized_index() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_quantized_index.bin");
+        let mat = generate_matrix(30, 4);
+        for rerank in [0, 5] {
+            let mut index = NaiveKnnIndex::new_quantized(4, Box::new(Euclidean{}), ScalarQuantizerType::INT8, rerank);
+            index.add_batch(mat.clone()).unwrap();
+            index.remove(3).unwrap();
+            index.save(&path).unwrap();
+            let loaded = NaiveKnnIndex::load(&path).unwrap();
+            assert_eq!(loaded.points, index.points);
+            assert_eq!(loaded.reconstruct(7), index.reconstruct(7));
+            assert_eq!(loaded.search(mat[7].clone(), 5).unwrap(), index.search(mat[7].clone(), 5).unwrap());
+
+            let mut index = NSWIndex::new_quantized(4, Box::new(Euclidean{}), 3, 2, ScalarQuantizerType::FP16, rerank);
+            index.add_batch(mat.clone()).unwrap();
+            index.remove(3).unwrap();
+            index.save(&path).unwrap();
+            let loaded = load_index(&path).unwrap();
+            for id in 0..30 {
+                assert_eq!(loaded.get_vector(id), index.get_vector(id));
+            }
+            assert_eq!(loaded.search(mat[7].clone(), 29).unwrap(), index.search(mat[7].clone(), 29).unwrap());
+            let mut loaded = NSWIndex::load(&path).unwrap();
+            assert_eq!(loaded.reconstruct(7), index.reconstruct(7));
+            assert_eq!(loaded.reconstruct(3), None);
+            loaded.compact();
+            loaded.add(mat[0].clone()).unwrap();
+            assert_eq!(loaded.len(), 30);
+        }
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,258 @@
+use crate::error::NNSearchError;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"NNSR";
+/// Version of the index file format. Bump this when the layout changes.
+pub const FORMAT_VERSION: u32 = 4;
+/// Size of magic + version + tag.
+pub(crate) const INDEX_HEADER_SIZE: usize = 9;
+/// Maximum number of items reserved in advance for a count read from a file,
+/// so that a corrupt count fails on reading the items instead of aborting on allocation.
+pub(crate) const MAX_RESERVED_ITEMS: usize = 4096;
+
+impl From<std::io::Error> for NNSearchError {
+    fn from(err: std::io::Error) -> Self {
+        NNSearchError::IoError(err.to_string())
+    }
+}
+
+/// Reads vectors from a text file which has one vector per line.
+/// Values are separated by whitespaces or commas, and empty lines are skipped.
+pub fn read_vectors(path: &Path) -> Result<Vec<Vec<f32>>, NNSearchError> {
+    parse_vectors(BufReader::new(File::open(path)?))
+}
+
+pub fn parse_vectors<R: BufRead>(reader: R) -> Result<Vec<Vec<f32>>, NNSearchError> {
+    let mut vectors = vec![];
+    for (lineno, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let vec = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|token| !token.is_empty())
+            .map(|token| token.parse::<f32>().map_err(|_| NNSearchError::ValueError(
+                format!("Invalid value at line {}: {}", lineno + 1, token))))
+            .collect::<Result<Vec<f32>, _>>()?;
+        vectors.push(vec);
+    }
+    Ok(vectors)
+}
+
+/// Writes an index file which consists of the following fields.
+///
+/// | field    | size   | description                             |
+/// |----------|--------|-----------------------------------------|
+/// | magic    | 4      | `NNSR`                                  |
+/// | version  | 4      | `FORMAT_VERSION`                        |
+/// | tag      | 1      | kind of the index                       |
+/// | body     | -      | index specific payload                  |
+/// | checksum | 4      | CRC32 of all the preceding bytes        |
+pub(crate) fn write_index_file(path: &Path, tag: u8, body: &[u8]) -> Result<(), NNSearchError> {
+    let mut header = vec![];
+    header.extend_from_slice(MAGIC);
+    write_u32(&mut header, FORMAT_VERSION)?;
+    write_u8(&mut header, tag)?;
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header);
+    hasher.update(body);
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header)?;
+    writer.write_all(body)?;
+    write_u32(&mut writer, hasher.finalize())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads an index file written by `write_index_file` and returns the tag and the body
+/// after validating the magic, the version and the checksum.
+pub(crate) fn read_index_file(path: &Path) -> Result<(u8, Vec<u8>), NNSearchError> {
+    let mut bytes = vec![];
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let (tag, body) = parse_index_bytes(&bytes)?;
+    Ok((tag, body.to_vec()))
+}
+
+/// Reads only the tag of an index file without validating the checksum.
+pub(crate) fn read_index_tag(path: &Path) -> Result<u8, NNSearchError> {
+    let mut header = [0u8; INDEX_HEADER_SIZE];
+    File::open(path)?.read_exact(&mut header).map_err(|_| NNSearchError::ValueError("Not an index file".to_string()))?;
+    if &header[..4] != MAGIC {
+        return Err(NNSearchError::ValueError("Not an index file".to_string()))
+    }
+    Ok(header[INDEX_HEADER_SIZE - 1])
+}
+
+/// Same as `read_index_file` but validates the bytes of a whole index file in place.
+/// The body starts at `INDEX_HEADER_SIZE` of the given bytes.
+pub(crate) fn parse_index_bytes(bytes: &[u8]) -> Result<(u8, &[u8]), NNSearchError> {
+    let (tag, body) = parse_index_header(bytes)?;
+    verify_checksum(bytes)?;
+    Ok((tag, body))
+}
+
+/// Validates the checksum of the bytes of a whole index file.
+pub(crate) fn verify_checksum(bytes: &[u8]) -> Result<(), NNSearchError> {
+    if bytes.len() < INDEX_HEADER_SIZE + 4 {
+        return Err(NNSearchError::ValueError("Not an index file".to_string()))
+    }
+    let (content, checksum) = bytes.split_at(bytes.len() - 4);
+    if crc32fast::hash(content) != read_u32(&mut &checksum[..])? {
+        return Err(NNSearchError::ValueError("Checksum mismatch".to_string()))
+    }
+    Ok(())
+}
+
+/// Same as `parse_index_bytes` but validates only the magic and the version,
+/// so that the body is not read.
+pub(crate) fn parse_index_header(bytes: &[u8]) -> Result<(u8, &[u8]), NNSearchError> {
+    if bytes.len() < INDEX_HEADER_SIZE + 4 || &bytes[..4] != MAGIC {
+        return Err(NNSearchError::ValueError("Not an index file".to_string()))
+    }
+    let mut reader = &bytes[4..bytes.len() - 4];
+    let version = read_u32(&mut reader)?;
+    if version != FORMAT_VERSION {
+        return Err(NNSearchError::ValueError(format!("Unsupported format version: {}", version)))
+    }
+    let tag = read_u8(&mut reader)?;
+    Ok((tag, reader))
+}
+
+// NOTE: binary values are stored in little endian.
+pub(crate) fn write_u8<W: Write>(writer: &mut W, value: u8) -> Result<(), NNSearchError> {
+    writer.write_all(&[value])?;
+    Ok(())
+}
+
+pub(crate) fn write_u32<W: Write>(writer: &mut W, value: u32) -> Result<(), NNSearchError> {
+    writer.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn write_u64<W: Write>(writer: &mut W, value: u64) -> Result<(), NNSearchError> {
+    writer.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn write_f32s<W: Write>(writer: &mut W, values: &[f32]) -> Result<(), NNSearchError> {
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn write_bytes<W: Write>(writer: &mut W, values: &[u8]) -> Result<(), NNSearchError> {
+    writer.write_all(values)?;
+    Ok(())
+}
+
+// NOTE: strings are stored as the length in bytes followed by UTF-8 bytes.
+pub(crate) fn write_str<W: Write>(writer: &mut W, value: &str) -> Result<(), NNSearchError> {
+    write_u64(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn read_u8<R: Read>(reader: &mut R) -> Result<u8, NNSearchError> {
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub(crate) fn read_u32<R: Read>(reader: &mut R) -> Result<u32, NNSearchError> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64<R: Read>(reader: &mut R) -> Result<u64, NNSearchError> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub(crate) fn read_f32s<R: Read>(reader: &mut R, len: usize) -> Result<Vec<f32>, NNSearchError> {
+    let mut buf = [0u8; 4];
+    let mut values = Vec::with_capacity(len.min(MAX_RESERVED_ITEMS));
+    for _ in 0..len {
+        reader.read_exact(&mut buf)?;
+        values.push(f32::from_le_bytes(buf));
+    }
+    Ok(values)
+}
+
+pub(crate) fn read_bytes<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>, NNSearchError> {
+    let mut values = vec![];
+    reader.take(len as u64).read_to_end(&mut values)?;
+    if values.len() != len {
+        return Err(NNSearchError::IoError("Unexpected end of bytes".to_string()))
+    }
+    Ok(values)
+}
+
+pub(crate) fn read_str<R: Read>(reader: &mut R) -> Result<String, NNSearchError> {
+    let len = read_u64(reader)? as usize;
+    let mut buf = vec![];
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(NNSearchError::IoError("Unexpected end of string".to_string()))
+    }
+    String::from_utf8(buf).map_err(|_| NNSearchError::ValueError("Invalid UTF-8 string".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vectors() {
+        let text = "0.1 0.2 0.3\n\n0.4,0.5,0.6\n 0.7\t0.8  0.9 \n";
+        let vectors = parse_vectors(text.as_bytes()).unwrap();
+        assert_eq!(vectors, vec![vec![0.1, 0.2, 0.3], vec![0.4, 0.5, 0.6], vec![0.7, 0.8, 0.9]]);
+    }
+
+    #[test]
+    fn test_parse_vectors_invalid_value() {
+        let text = "0.1 0.2\n0.3 abc\n";
+        assert_eq!(parse_vectors(text.as_bytes()).unwrap_err(), NNSearchError::ValueError("Invalid value at line 2: abc".to_string()));
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let mut buf = vec![];
+        write_u8(&mut buf, 3).unwrap();
+        write_u32(&mut buf, 7).unwrap();
+        write_u64(&mut buf, 42).unwrap();
+        write_f32s(&mut buf, &[0.1, -0.2]).unwrap();
+        write_str(&mut buf, "タグ").unwrap();
+        write_bytes(&mut buf, &[1, 255]).unwrap();
+        let mut reader = buf.as_slice();
+        assert_eq!(read_u8(&mut reader).unwrap(), 3);
+        assert_eq!(read_u32(&mut reader).unwrap(), 7);
+        assert_eq!(read_u64(&mut reader).unwrap(), 42);
+        assert_eq!(read_f32s(&mut reader, 2).unwrap(), vec![0.1, -0.2]);
+        assert_eq!(read_str(&mut reader).unwrap(), "タグ");
+        assert_eq!(read_bytes(&mut reader, 2).unwrap(), vec![1, 255]);
+        assert!(read_u8(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_index_file() {
+        let path = std::env::temp_dir().join("nnsearch_test_index_file.bin");
+        write_index_file(&path, 2, &[1, 2, 3]).unwrap();
+        assert_eq!(read_index_file(&path).unwrap(), (2, vec![1, 2, 3]));
+
+        // corrupt the body
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[10] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(read_index_file(&path).unwrap_err(), NNSearchError::ValueError("Checksum mismatch".to_string()));
+
+        std::fs::write(&path, b"not an index file").unwrap();
+        assert_eq!(read_index_file(&path).unwrap_err(), NNSearchError::ValueError("Not an index file".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,809 @@
+// Inverted file indexes which partition vectors into the clusters of a k-means coarse quantizer.
+use std::io::Read;
+use std::path::Path;
+
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+
+use crate::error::NNSearchError;
+use crate::filter::{IdBitSet, IdFilter};
+use crate::graph::Neighbor;
+use crate::index::{read_distance, read_index_body, rerank_neighbors, validate_dim, validate_query, validate_radius_query, write_distance, VectorIndexOperator, IVF_FLAT_INDEX_TAG, IVF_PQ_INDEX_TAG};
+use crate::io::{read_bytes, read_f32s, read_u64, read_u8, write_bytes, write_f32s, write_index_file, write_u64, write_u8, MAX_RESERVED_ITEMS};
+use crate::linalg::distance::{DistanceType, Euclidean, PairwiseDistance};
+use crate::linalg::kmeans::{assign, kmeans, nearest_centroid};
+use crate::linalg::utils::get_rng;
+use crate::metadata::{read_metadata, write_metadata, Metadata};
+use crate::quantizer::ProductQuantizer;
+
+// NOTE: k-means is trained on a sample so that the training time does not grow with the collection.
+const MAX_TRAINING_VECTORS_PER_LIST: usize = 256;
+const NUM_KMEANS_ITERS: usize = 25;
+
+/// Samples at most 256 * `nlist` vectors from `data` to train the centroids.
+fn sample_training_vectors(data: &[Vec<f32>], nlist: usize, rng: &mut SmallRng) -> Vec<Vec<f32>> {
+    data.choose_multiple(rng, nlist.saturating_mul(MAX_TRAINING_VECTORS_PER_LIST)).cloned().collect()
+}
+
+/// Scales `vec` to the unit length. A zero vector is left as is.
+fn normalize(vec: &mut [f32]) {
+    let norm = vec.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vec.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// Returns the positions of the `nprobe` nearest centroids to `query`.
+fn probe(centroids: &[Vec<f32>], distance: &dyn PairwiseDistance<f32, f32>, query: &[f32], nprobe: usize) -> Result<Vec<usize>, NNSearchError> {
+    let mut costs = centroids
+        .iter()
+        .enumerate()
+        .map(|(pos, centroid)| Ok((pos, distance.compute(query, centroid)?)))
+        .collect::<Result<Vec<_>, NNSearchError>>()?;
+    costs.sort_by(|a, b| a.1.total_cmp(&b.1));
+    Ok(costs.into_iter().take(nprobe).map(|(pos, _)| pos).collect())
+}
+
+/// Parameters of a single search on IVF indexes.
+/// `None` falls back to the setting of the index, so `IVFSearchParams::default()` searches the same way as `search`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IVFSearchParams {
+    /// Number of inverted lists scanned (`nprobe` of the index by default).
+    pub nprobe: Option<usize>,
+}
+
+/// Index which assigns each vector to the inverted list of its nearest centroid,
+/// and scans only the `nprobe` lists whose centroids are the nearest to the query.
+/// The centroids are trained by k-means with `train`, or on the first batch given to `add_batch`.
+///
+/// The means of the clusters minimize the Euclidean distance, and also the cosine and angular distances
+/// since the training vectors are normalized for them. With the other distances the centroids are not optimal,
+/// so the recall for a given `nprobe` can be lower than with the Euclidean distance.
+#[derive(Debug)]
+pub struct IVFFlatIndex {
+    dim: usize,
+    distance: Box<dyn PairwiseDistance<f32, f32>>,
+    nlist: usize,
+    nprobe: usize,
+    centroids: Vec<Vec<f32>>,
+    // ids of the items in each inverted list
+    lists: Vec<Vec<usize>>,
+    // NOTE: removed points are left as None to keep the ids.
+    points: Vec<Option<Vec<f32>>>,
+    metadata: Vec<Metadata>,
+    // inverted list of each point
+    assignments: Vec<usize>,
+}
+
+impl IVFFlatIndex {
+    pub fn new(dim: usize, distance: Box<dyn PairwiseDistance<f32, f32>>, nlist: usize, nprobe: usize) -> Self {
+        IVFFlatIndex {
+            dim,
+            distance,
+            nlist,
+            nprobe,
+            centroids: vec![],
+            lists: vec![],
+            points: vec![],
+            metadata: vec![],
+            assignments: vec![],
+        }
+    }
+
+    pub fn nlist(&self) -> usize {
+        self.nlist
+    }
+
+    pub fn nprobe(&self) -> usize {
+        self.nprobe
+    }
+
+    /// Sets the number of inverted lists scanned by default.
+    pub fn set_nprobe(&mut self, nprobe: usize) {
+        self.nprobe = nprobe;
+    }
+
+    pub fn is_trained(&self) -> bool {
+        !self.centroids.is_empty()
+    }
+
+    /// Trains the centroids by k-means on at most 256 * `nlist` vectors sampled from `data`,
+    /// and reassigns the indexed items to the new centroids.
+    /// Fails with `ValueError` if `data` has less than `nlist` vectors.
+    pub fn train(&mut self, data: &[Vec<f32>]) -> Result<(), NNSearchError> {
+        for vec in data {
+            validate_dim(self.dim, vec)?;
+        }
+        let mut rng = get_rng(46);
+        let mut sample = sample_training_vectors(data, self.nlist, &mut rng);
+        // NOTE: the direction of the mean of unit vectors maximizes the sum of the cosine similarities in a cluster.
+        if let Some(DistanceType::COSINE) | Some(DistanceType::ANGULAR) = self.distance.distance_type() {
+            sample.iter_mut().for_each(|vec| normalize(vec));
+        }
+        self.centroids = kmeans(&sample, self.nlist, NUM_KMEANS_ITERS, &*self.distance, &mut rng)?;
+        self.lists = vec![vec![]; self.nlist];
+        for (id, point) in self.points.iter().enumerate() {
+            if let Some(vec) = point {
+                let pos = nearest_centroid(&self.centroids, vec, &*self.distance)?.0;
+                self.assignments[id] = pos;
+                self.lists[pos].push(id);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), NNSearchError> {
+        let mut body = vec![];
+        write_distance(&mut body, &*self.distance)?;
+        for size in &[self.dim, self.nlist, self.nprobe, self.centroids.len()] {
+            write_u64(&mut body, *size as u64)?;
+        }
+        for centroid in &self.centroids {
+            write_f32s(&mut body, centroid)?;
+        }
+        write_u64(&mut body, self.points.len() as u64)?;
+        for (id, point) in self.points.iter().enumerate() {
+            match point {
+                Some(vec) => {
+                    write_u8(&mut body, 0)?;
+                    write_f32s(&mut body, vec)?;
+                    write_metadata(&mut body, &self.metadata[id])?;
+                    write_u64(&mut body, self.assignments[id] as u64)?;
+                }
+                None => write_u8(&mut body, 1)?,
+            }
+        }
+        write_index_file(path, IVF_FLAT_INDEX_TAG, &body)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, NNSearchError> {
+        Self::read_from(&mut read_index_body(path, IVF_FLAT_INDEX_TAG)?.as_slice())
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Self, NNSearchError> {
+        let distance = read_distance(reader)?;
+        let dim = read_u64(reader)? as usize;
+        let nlist = read_u64(reader)? as usize;
+        let nprobe = read_u64(reader)? as usize;
+        let num_centroids = read_u64(reader)? as usize;
+        let mut index = IVFFlatIndex::new(dim, distance, nlist, nprobe);
+        for _ in 0..num_centroids {
+            index.centroids.push(read_f32s(reader, dim)?);
+        }
+        index.lists = vec![vec![]; num_centroids];
+        let num_points = read_u64(reader)? as usize;
+        for id in 0..num_points {
+            if read_u8(reader)? != 0 {
+                index.points.push(None);
+                index.metadata.push(Metadata::new());
+                index.assignments.push(0);
+                continue
+            }
+            index.points.push(Some(read_f32s(reader, dim)?));
+            index.metadata.push(read_metadata(reader)?);
+            let pos = read_u64(reader)? as usize;
+            match index.lists.get_mut(pos) {
+                Some(list) => list.push(id),
+                None => return Err(NNSearchError::ValueError(format!("Invalid inverted list: {}", pos))),
+            }
+            index.assignments.push(pos);
+        }
+        Ok(index)
+    }
+
+    /// Returns the items allowed by `filter` in the `nprobe` lists nearest to `query`.
+    fn scan(&self, query: &[f32], nprobe: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        let mut neighbors = vec![];
+        for pos in probe(&self.centroids, &*self.distance, query, nprobe)? {
+            for &id in self.lists[pos].iter().filter(|&&id| filter.allows(id)) {
+                let vec = self.points[id].as_ref().unwrap();
+                neighbors.push(Neighbor {id, distance: self.distance.compute(query, vec)?});
+            }
+        }
+        Ok(neighbors)
+    }
+
+    /// Same as `search` with the search parameters given by `params`.
+    pub fn search_with_ivf_params(&self, query: Vec<f32>, k: usize, params: &IVFSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_filtered_with_ivf_params(query, k, &|_| true, params)
+    }
+
+    /// Same as `search_filtered` with the search parameters given by `params`.
+    pub fn search_filtered_with_ivf_params(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter, params: &IVFSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        let mut neighbors = self.scan(&query, params.nprobe.unwrap_or(self.nprobe), filter)?;
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        neighbors.truncate(k);
+        Ok(neighbors)
+    }
+
+    /// Same as `search_radius` with the search parameters given by `params`.
+    pub fn search_radius_with_ivf_params(&self, query: Vec<f32>, radius: f32, params: &IVFSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_radius_query(self, &query, radius)?;
+        let mut neighbors = self.scan(&query, params.nprobe.unwrap_or(self.nprobe), &|_| true)?;
+        neighbors.retain(|nn| nn.distance <= radius);
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        Ok(neighbors)
+    }
+
+    fn push(&mut self, data: Vec<f32>, metadata: Metadata, pos: usize) -> usize {
+        let id = self.points.len();
+        self.points.push(Some(data));
+        self.metadata.push(metadata);
+        self.assignments.push(pos);
+        self.lists[pos].push(id);
+        id
+    }
+}
+
+impl VectorIndexOperator for IVFFlatIndex {
+    /// Adds the vectors, training the centroids on them first if the index is not trained.
+    fn add_batch(&mut self, data_batch: Vec<Vec<f32>>) -> Result<(), NNSearchError> {
+        for data in &data_batch {
+            validate_dim(self.dim, data)?;
+        }
+        if !self.is_trained() {
+            self.train(&data_batch)?;
+        }
+        let assignments = assign(&self.centroids, &data_batch, &*self.distance)?;
+        for (data, pos) in data_batch.into_iter().zip(assignments) {
+            self.push(data, Metadata::new(), pos);
+        }
+        Ok(())
+    }
+    /// Fails with `NotTrained` if the index is not trained.
+    fn add_with_metadata(&mut self, data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError> {
+        validate_dim(self.dim, &data)?;
+        if !self.is_trained() {
+            return Err(NNSearchError::NotTrained)
+        }
+        let pos = nearest_centroid(&self.centroids, &data, &*self.distance)?.0;
+        Ok(self.push(data, metadata, pos))
+    }
+    /// Use `search_with_ivf_params` to change the number of scanned lists per search.
+    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_with_ivf_params(query, k, &IVFSearchParams::default())
+    }
+    fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_filtered_with_ivf_params(query, k, filter, &IVFSearchParams::default())
+    }
+    /// Items outside of the `nprobe` lists nearest to `query` are not returned.
+    fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_radius_with_ivf_params(query, radius, &IVFSearchParams::default())
+    }
+    fn remove(&mut self, id: usize) -> Result<(), NNSearchError> {
+        match self.points.get_mut(id) {
+            Some(point) if point.is_some() => {
+                *point = None;
+                self.metadata[id] = Metadata::new();
+                self.lists[self.assignments[id]].retain(|&other| other != id);
+                Ok(())
+            }
+            _ => Err(NNSearchError::NotFound(id)),
+        }
+    }
+    fn get_vector(&self, id: usize) -> Option<&[f32]> {
+        self.points.get(id).and_then(|point| point.as_deref())
+    }
+    fn get_metadata(&self, id: usize) -> Option<&Metadata> {
+        self.get_vector(id).map(|_| &self.metadata[id])
+    }
+    fn get_distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &*self.distance
+    }
+    fn dim(&self) -> usize {
+        self.dim
+    }
+    fn len(&self) -> usize {
+        self.lists.iter().map(|list| list.len()).sum()
+    }
+}
+
+fn residual(vec: &[f32], centroid: &[f32]) -> Vec<f32> {
+    vec.iter().zip(centroid).map(|(x, c)| x - c).collect()
+}
+
+/// Index which stores only the product quantization codes of the residuals from the centroids of the inverted lists.
+/// Distances are Euclidean, and approximated with the distance tables of the quantizer.
+/// If `rerank` is positive, the original vectors are also kept to rerank the top `rerank` candidates by the exact distances.
+#[derive(Debug)]
+pub struct IVFPQIndex {
+    dim: usize,
+    distance: Euclidean,
+    nlist: usize,
+    nprobe: usize,
+    rerank: usize,
+    centroids: Vec<Vec<f32>>,
+    quantizer: ProductQuantizer,
+    // ids of the items in each inverted list
+    lists: Vec<Vec<usize>>,
+    // codes of the items concatenated in the order of ids
+    codes: Vec<u8>,
+    // NOTE: original vectors are kept only if `rerank` is positive.
+    vectors: Vec<Option<Vec<f32>>>,
+    removed: IdBitSet,
+    metadata: Vec<Metadata>,
+    // inverted list of each item
+    assignments: Vec<usize>,
+}
+
+impl IVFPQIndex {
+    /// Creates an index whose codes consist of `num_subspaces` bytes.
+    /// `num_centroids` (at most 256) centroids are trained in each subspace.
+    pub fn new(dim: usize, nlist: usize, nprobe: usize, num_subspaces: usize, num_centroids: usize, rerank: usize) -> Self {
+        IVFPQIndex {
+            dim,
+            distance: Euclidean{},
+            nlist,
+            nprobe,
+            rerank,
+            centroids: vec![],
+            quantizer: ProductQuantizer::new(dim, num_subspaces, num_centroids),
+            lists: vec![],
+            codes: vec![],
+            vectors: vec![],
+            removed: IdBitSet::new(),
+            metadata: vec![],
+            assignments: vec![],
+        }
+    }
+
+    pub fn nlist(&self) -> usize {
+        self.nlist
+    }
+
+    pub fn nprobe(&self) -> usize {
+        self.nprobe
+    }
+
+    /// Sets the number of inverted lists scanned by default.
+    pub fn set_nprobe(&mut self, nprobe: usize) {
+        self.nprobe = nprobe;
+    }
+
+    pub fn rerank(&self) -> usize {
+        self.rerank
+    }
+
+    pub fn is_trained(&self) -> bool {
+        !self.centroids.is_empty()
+    }
+
+    /// Trains the centroids by k-means and the product quantizer on the residuals
+    /// of at most 256 * `nlist` vectors sampled from `data`.
+    /// Fails with `ValueError` if items are already indexed, since their vectors cannot be encoded again.
+    pub fn train(&mut self, data: &[Vec<f32>]) -> Result<(), NNSearchError> {
+        if !self.metadata.is_empty() {
+            return Err(NNSearchError::ValueError("Index with items cannot be trained".to_string()))
+        }
+        for vec in data {
+            validate_dim(self.dim, vec)?;
+        }
+        let mut rng = get_rng(46);
+        let sample = sample_training_vectors(data, self.nlist, &mut rng);
+        let centroids = kmeans(&sample, self.nlist, NUM_KMEANS_ITERS, &self.distance, &mut rng)?;
+        let residuals: Vec<Vec<f32>> = sample
+            .iter()
+            .zip(assign(&centroids, &sample, &self.distance)?)
+            .map(|(vec, pos)| residual(vec, &centroids[pos]))
+            .collect();
+        self.quantizer.train(&residuals, &mut rng)?;
+        self.centroids = centroids;
+        self.lists = vec![vec![]; self.nlist];
+        Ok(())
+    }
+
+    /// Reconstructs the vector of the item from its code.
+    pub fn reconstruct(&self, id: usize) -> Option<Vec<f32>> {
+        if !self.contains(id) {
+            return None
+        }
+        let centroid = &self.centroids[self.assignments[id]];
+        Some(self.quantizer.decode(self.code(id)).iter().zip(centroid).map(|(r, c)| r + c).collect())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), NNSearchError> {
+        let mut body = vec![];
+        for size in &[self.dim, self.nlist, self.nprobe, self.rerank, self.centroids.len()] {
+            write_u64(&mut body, *size as u64)?;
+        }
+        for centroid in &self.centroids {
+            write_f32s(&mut body, centroid)?;
+        }
+        self.quantizer.write_to(&mut body)?;
+        write_u64(&mut body, self.metadata.len() as u64)?;
+        for id in 0..self.metadata.len() {
+            if !self.contains(id) {
+                write_u8(&mut body, 1)?;
+                continue
+            }
+            write_u8(&mut body, 0)?;
+            write_bytes(&mut body, self.code(id))?;
+            if let Some(Some(vec)) = self.vectors.get(id) {
+                write_f32s(&mut body, vec)?;
+            }
+            write_metadata(&mut body, &self.metadata[id])?;
+            write_u64(&mut body, self.assignments[id] as u64)?;
+        }
+        write_index_file(path, IVF_PQ_INDEX_TAG, &body)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, NNSearchError> {
+        Self::read_from(&mut read_index_body(path, IVF_PQ_INDEX_TAG)?.as_slice())
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Self, NNSearchError> {
+        let dim = read_u64(reader)? as usize;
+        let nlist = read_u64(reader)? as usize;
+        let nprobe = read_u64(reader)? as usize;
+        let rerank = read_u64(reader)? as usize;
+        let num_centroids = read_u64(reader)? as usize;
+        let mut centroids = Vec::with_capacity(num_centroids.min(MAX_RESERVED_ITEMS));
+        for _ in 0..num_centroids {
+            centroids.push(read_f32s(reader, dim)?);
+        }
+        let quantizer = ProductQuantizer::read_from(reader)?;
+        if quantizer.dim() != dim {
+            return Err(NNSearchError::ValueError("Inconsistent dimension of the product quantizer".to_string()))
+        }
+        let mut index = IVFPQIndex {
+            centroids,
+            quantizer,
+            lists: vec![vec![]; num_centroids],
+            ..IVFPQIndex::new(dim, nlist, nprobe, 0, 0, rerank)
+        };
+        let code_size = index.quantizer.code_size();
+        let num_items = read_u64(reader)? as usize;
+        for id in 0..num_items {
+            if read_u8(reader)? != 0 {
+                index.codes.resize(index.codes.len() + code_size, 0);
+                if rerank > 0 {
+                    index.vectors.push(None);
+                }
+                index.removed.insert(id);
+                index.metadata.push(Metadata::new());
+                index.assignments.push(0);
+                continue
+            }
+            index.codes.extend(read_bytes(reader, code_size)?);
+            if rerank > 0 {
+                index.vectors.push(Some(read_f32s(reader, dim)?));
+            }
+            index.metadata.push(read_metadata(reader)?);
+            let pos = read_u64(reader)? as usize;
+            match index.lists.get_mut(pos) {
+                Some(list) => list.push(id),
+                None => return Err(NNSearchError::ValueError(format!("Invalid inverted list: {}", pos))),
+            }
+            index.assignments.push(pos);
+        }
+        Ok(index)
+    }
+
+    fn contains(&self, id: usize) -> bool {
+        id < self.metadata.len() && !self.removed.contains(id)
+    }
+
+    fn code(&self, id: usize) -> &[u8] {
+        let code_size = self.quantizer.code_size();
+        &self.codes[id * code_size..(id + 1) * code_size]
+    }
+
+    /// Returns the items allowed by `filter` in the `nprobe` lists nearest to `query` with the approximate distances.
+    fn scan(&self, query: &[f32], nprobe: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        let mut neighbors = vec![];
+        for pos in probe(&self.centroids, &self.distance, query, nprobe)? {
+            let table = self.quantizer.distance_table(&residual(query, &self.centroids[pos]))?;
+            for &id in self.lists[pos].iter().filter(|&&id| filter.allows(id)) {
+                neighbors.push(Neighbor {id, distance: table.distance(self.code(id))});
+            }
+        }
+        Ok(neighbors)
+    }
+
+    /// Replaces the approximate distances with the exact ones if the original vectors are kept.
+    fn refine(&self, query: &[f32], neighbors: &mut [Neighbor]) -> Result<(), NNSearchError> {
+        if self.rerank == 0 {
+            return Ok(())
+        }
+        for nn in neighbors.iter_mut() {
+            nn.distance = self.distance.compute(query, self.vectors[nn.id].as_ref().unwrap())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the top `k` neighbors, reranking the top `rerank` candidates by the exact distances.
+    fn select(&self, query: &[f32], neighbors: Vec<Neighbor>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        rerank_neighbors(neighbors, k, self.rerank, |id| self.distance.compute(query, self.vectors[id].as_ref().unwrap()))
+    }
+
+    /// Same as `search` with the search parameters given by `params`.
+    pub fn search_with_ivf_params(&self, query: Vec<f32>, k: usize, params: &IVFSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_filtered_with_ivf_params(query, k, &|_| true, params)
+    }
+
+    /// Same as `search_filtered` with the search parameters given by `params`.
+    pub fn search_filtered_with_ivf_params(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter, params: &IVFSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        let neighbors = self.scan(&query, params.nprobe.unwrap_or(self.nprobe), filter)?;
+        self.select(&query, neighbors, k)
+    }
+
+    /// Same as `search_radius` with the search parameters given by `params`.
+    pub fn search_radius_with_ivf_params(&self, query: Vec<f32>, radius: f32, params: &IVFSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_radius_query(self, &query, radius)?;
+        let mut neighbors = self.scan(&query, params.nprobe.unwrap_or(self.nprobe), &|_| true)?;
+        self.refine(&query, &mut neighbors)?;
+        neighbors.retain(|nn| nn.distance <= radius);
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        Ok(neighbors)
+    }
+
+    fn push(&mut self, data: Vec<f32>, metadata: Metadata, pos: usize) -> Result<usize, NNSearchError> {
+        let code = self.quantizer.encode(&residual(&data, &self.centroids[pos]))?;
+        let id = self.metadata.len();
+        self.codes.extend(code);
+        if self.rerank > 0 {
+            self.vectors.push(Some(data));
+        }
+        self.metadata.push(metadata);
+        self.assignments.push(pos);
+        self.lists[pos].push(id);
+        Ok(id)
+    }
+}
+
+impl VectorIndexOperator for IVFPQIndex {
+    /// Adds the vectors, training the centroids and the quantizer on them first if the index is not trained.
+    fn add_batch(&mut self, data_batch: Vec<Vec<f32>>) -> Result<(), NNSearchError> {
+        for data in &data_batch {
+            validate_dim(self.dim, data)?;
+        }
+        if !self.is_trained() {
+            self.train(&data_batch)?;
+        }
+        let assignments = assign(&self.centroids, &data_batch, &self.distance)?;
+        for (data, pos) in data_batch.into_iter().zip(assignments) {
+            self.push(data, Metadata::new(), pos)?;
+        }
+        Ok(())
+    }
+    /// Fails with `NotTrained` if the index is not trained.
+    fn add_with_metadata(&mut self, data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError> {
+        validate_dim(self.dim, &data)?;
+        if !self.is_trained() {
+            return Err(NNSearchError::NotTrained)
+        }
+        let pos = nearest_centroid(&self.centroids, &data, &self.distance)?.0;
+        self.push(data, metadata, pos)
+    }
+    /// Use `search_with_ivf_params` to change the number of scanned lists per search.
+    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_with_ivf_params(query, k, &IVFSearchParams::default())
+    }
+    fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_filtered_with_ivf_params(query, k, filter, &IVFSearchParams::default())
+    }
+    /// Items outside of the `nprobe` lists nearest to `query` are not returned.
+    /// Distances are approximate unless the original vectors are kept for reranking.
+    fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_radius_with_ivf_params(query, radius, &IVFSearchParams::default())
+    }
+    fn remove(&mut self, id: usize) -> Result<(), NNSearchError> {
+        if !self.contains(id) {
+            return Err(NNSearchError::NotFound(id))
+        }
+        self.removed.insert(id);
+        self.metadata[id] = Metadata::new();
+        if let Some(vec) = self.vectors.get_mut(id) {
+            *vec = None;
+        }
+        self.lists[self.assignments[id]].retain(|&other| other != id);
+        Ok(())
+    }
+    /// Returns the original vector only if it is kept for reranking. Use `reconstruct` for the approximate one.
+    fn get_vector(&self, id: usize) -> Option<&[f32]> {
+        self.vectors.get(id).and_then(|vec| vec.as_deref())
+    }
+    fn get_metadata(&self, id: usize) -> Option<&Metadata> {
+        if self.contains(id) {
+            Some(&self.metadata[id])
+        } else {
+            None
+        }
+    }
+    fn get_distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &self.distance
+    }
+    fn dim(&self) -> usize {
+        self.dim
+    }
+    fn len(&self) -> usize {
+        self.lists.iter().map(|list| list.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{load_index, NaiveKnnIndex};
+    use crate::linalg::distance::{Cosine, Euclidean};
+    use crate::linalg::utils::generate_matrix;
+
+    type Search<'a> = &'a dyn Fn(Vec<f32>, usize) -> Result<Vec<Neighbor>, NNSearchError>;
+
+    fn recall(mat: &[Vec<f32>], k: usize, search: Search) -> f32 {
+        let mut naive = NaiveKnnIndex::new(mat[0].len(), Box::new(Euclidean{}));
+        naive.add_batch(mat.to_vec()).unwrap();
+        let mut num_found = 0;
+        for query in &mat[..50] {
+            let expected: Vec<usize> = naive.search(query.clone(), k).unwrap().iter().map(|nn| nn.id).collect();
+            let result = search(query.clone(), k).unwrap();
+            num_found += result.iter().filter(|nn| expected.contains(&nn.id)).count();
+        }
+        num_found as f32 / (50 * k) as f32
+    }
+
+    #[test]
+    fn test_ivf_flat_index() {
+        let mat = generate_matrix(1000, 4);
+        let mut index = IVFFlatIndex::new(4, Box::new(Euclidean{}), 16, 4);
+        assert_eq!(index.add(mat[0].clone()).unwrap_err(), NNSearchError::NotTrained);
+        index.add_batch(mat.clone()).unwrap();
+        assert!(index.is_trained());
+        assert_eq!(index.len(), 1000);
+        assert_eq!(index.search(mat[7].clone(), 10).unwrap()[0].id, 7);
+
+        let few = recall(&mat, 10, &|query, k| index.search_with_ivf_params(query, k, &IVFSearchParams {nprobe: Some(1)}));
+        let many = recall(&mat, 10, &|query, k| index.search(query, k));
+        assert!(many > 0.9);
+        assert!(few <= many);
+        let all_lists = IVFSearchParams {nprobe: Some(16)};
+        assert_eq!(recall(&mat, 10, &|query, k| index.search_with_ivf_params(query, k, &all_lists)), 1.0);
+
+        // the parameters apply to the filtered and the radius searches as well
+        let mut naive = NaiveKnnIndex::new(4, Box::new(Euclidean{}));
+        naive.add_batch(mat.clone()).unwrap();
+        for query in &mat[..20] {
+            let even = |id: usize| id.is_multiple_of(2);
+            let expected = naive.search_filtered(query.clone(), 10, &even).unwrap();
+            assert_eq!(index.search_filtered_with_ivf_params(query.clone(), 10, &even, &all_lists).unwrap(), expected);
+            let expected = naive.search_radius(query.clone(), 0.2).unwrap();
+            assert_eq!(index.search_radius_with_ivf_params(query.clone(), 0.2, &all_lists).unwrap(), expected);
+            let one_list = index.search_radius_with_ivf_params(query.clone(), 0.2, &IVFSearchParams {nprobe: Some(1)}).unwrap();
+            assert!(one_list.len() <= expected.len());
+        }
+
+        // items added after the training are assigned to the trained lists
+        let id = index.add(vec![0.5, 0.5, 0.5, 0.5]).unwrap();
+        assert_eq!(id, 1000);
+        assert_eq!(index.search(vec![0.5, 0.5, 0.5, 0.5], 1).unwrap()[0].id, 1000);
+
+        assert!(index.search_filtered(mat[7].clone(), 5, &|id| id != 7).unwrap().iter().all(|nn| nn.id != 7));
+        let result = index.search_radius(mat[7].clone(), 0.2).unwrap();
+        assert!(result.iter().any(|nn| nn.id == 7) && result.iter().all(|nn| nn.distance <= 0.2));
+
+        index.remove(7).unwrap();
+        assert_eq!(index.remove(7).unwrap_err(), NNSearchError::NotFound(7));
+        assert_eq!(index.len(), 1000);
+        assert_eq!(index.get_vector(7), None);
+        assert!(index.search(mat[7].clone(), 10).unwrap().iter().all(|nn| nn.id != 7));
+        assert_eq!(index.add(vec![0.5]).unwrap_err(), NNSearchError::DimensionMismatch {expected: 4, actual: 1});
+    }
+
+    #[test]
+    fn test_ivf_flat_index_with_other_distance() {
+        let mat = generate_matrix(300, 3);
+        let mut index = IVFFlatIndex::new(3, Box::new(Cosine{}), 8, 8);
+        index.add_batch(mat.clone()).unwrap();
+        let result = index.search(mat[3].clone(), 3).unwrap();
+        assert_eq!(result[0].id, 3);
+        assert!(result[0].distance.abs() < 1e-6);
+
+        // the centroids are trained on the directions of the vectors
+        let scaled: Vec<Vec<f32>> = mat.iter().enumerate().map(|(id, vec)| vec.iter().map(|x| x * (id % 7 + 1) as f32).collect()).collect();
+        let mut scaled_index = IVFFlatIndex::new(3, Box::new(Cosine{}), 8, 8);
+        scaled_index.add_batch(scaled).unwrap();
+        for (scaled_centroid, centroid) in scaled_index.centroids.iter().zip(&index.centroids) {
+            assert!(scaled_centroid.iter().zip(centroid).all(|(x, y)| (x - y).abs() < 1e-4));
+        }
+    }
+
+    #[test]
+    fn test_ivf_flat_index_train() {
+        let mat = generate_matrix(100, 2);
+        let mut index = IVFFlatIndex::new(2, Box::new(Euclidean{}), 200, 1);
+        assert!(index.train(&mat).is_err());
+        assert!(index.add_batch(mat.clone()).is_err());
+        assert!(index.is_empty());
+
+        // retraining reassigns the indexed items
+        let mut index = IVFFlatIndex::new(2, Box::new(Euclidean{}), 4, 1);
+        index.train(&mat[..50]).unwrap();
+        index.add_batch(mat.clone()).unwrap();
+        index.train(&mat).unwrap();
+        assert_eq!(index.len(), 100);
+        assert_eq!(index.search(mat[42].clone(), 1).unwrap()[0].id, 42);
+    }
+
+    #[test]
+    fn test_save_and_load_ivf_flat_index() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_ivf_flat_index.bin");
+        let mat = generate_matrix(200, 3);
+        let mut index = IVFFlatIndex::new(3, Box::new(Euclidean{}), 8, 2);
+        index.add_batch(mat.clone()).unwrap();
+        index.remove(3).unwrap();
+        index.save(&path).unwrap();
+
+        let loaded = IVFFlatIndex::load(&path).unwrap();
+        assert_eq!(loaded.nlist(), 8);
+        assert_eq!(loaded.nprobe(), 2);
+        assert_eq!(loaded.len(), 199);
+        assert_eq!(loaded.get_vector(3), None);
+        for query in &mat[..20] {
+            assert_eq!(loaded.search(query.clone(), 5).unwrap(), index.search(query.clone(), 5).unwrap());
+        }
+        let loaded = load_index(&path).unwrap();
+        assert_eq!(loaded.search(mat[5].clone(), 1).unwrap()[0].id, 5);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_ivf_pq_index() {
+        let mat = generate_matrix(2000, 8);
+        let mut index = IVFPQIndex::new(8, 8, 4, 4, 64, 0);
+        assert_eq!(index.add(mat[0].clone()).unwrap_err(), NNSearchError::NotTrained);
+        index.add_batch(mat.clone()).unwrap();
+        assert_eq!(index.len(), 2000);
+        // only the codes are kept
+        assert_eq!(index.get_vector(7), None);
+        let reconstructed = index.reconstruct(7).unwrap();
+        assert!(Euclidean{}.compute(&reconstructed, &mat[7]).unwrap() < 0.3);
+        assert!(index.train(&mat).is_err());
+        let approximate = recall(&mat, 10, &|query, k| index.search(query, k));
+        assert!(approximate > 0.4);
+
+        // reranking by the original vectors improves the recall
+        let mut reranked = IVFPQIndex::new(8, 8, 4, 4, 64, 50);
+        reranked.add_batch(mat.clone()).unwrap();
+        assert_eq!(reranked.get_vector(7), Some(mat[7].as_slice()));
+        let result = reranked.search(mat[7].clone(), 10).unwrap();
+        assert_eq!(result[0], Neighbor {id: 7, distance: 0.0});
+        assert!(recall(&mat, 10, &|query, k| reranked.search(query, k)) > approximate.max(0.9));
+
+        assert!(reranked.search_filtered(mat[7].clone(), 5, &|id| id != 7).unwrap().iter().all(|nn| nn.id != 7));
+        let result = reranked.search_radius(mat[7].clone(), 0.3).unwrap();
+        assert!(result.iter().any(|nn| nn.id == 7) && result.iter().all(|nn| nn.distance <= 0.3));
+        reranked.remove(7).unwrap();
+        assert_eq!(reranked.remove(7).unwrap_err(), NNSearchError::NotFound(7));
+        assert_eq!(reranked.len(), 1999);
+        assert_eq!(reranked.get_vector(7), None);
+        assert_eq!(reranked.reconstruct(7), None);
+        assert!(reranked.search(mat[7].clone(), 10).unwrap().iter().all(|nn| nn.id != 7));
+        assert_eq!(reranked.add(mat[7].clone()).unwrap(), 2000);
+    }
+
+    #[test]
+    fn test_save_and_load_ivf_pq_index() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_ivf_pq_index.bin");
+        let mat = generate_matrix(500, 4);
+        for rerank in [0, 20] {
+            let mut index = IVFPQIndex::new(4, 4, 2, 2, 16, rerank);
+            index.add_batch(mat.clone()).unwrap();
+            index.remove(3).unwrap();
+            index.save(&path).unwrap();
+
+            let loaded = IVFPQIndex::load(&path).unwrap();
+            assert_eq!(loaded.rerank(), rerank);
+            assert_eq!(loaded.len(), 499);
+            assert_eq!(loaded.reconstruct(3), None);
+            assert_eq!(loaded.reconstruct(5), index.reconstruct(5));
+            for query in &mat[..20] {
+                assert_eq!(loaded.search(query.clone(), 5).unwrap(), index.search(query.clone(), 5).unwrap());
+            }
+            let loaded = load_index(&path).unwrap();
+            assert_eq!(loaded.len(), 499);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,175 @@
+// Index addressed by keys given by the caller (e.g. u64 or String) instead of the ids assigned by the index.
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+
+use crate::error::NNSearchError;
+use crate::index::VectorIndexOperator;
+
+/// Behavior when an item is added with a key which is already indexed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Fails with `DuplicateKey`.
+    ERROR,
+    /// Removes the existing item and adds the new one.
+    OVERWRITE,
+}
+
+/// An item found by the search with its key and distance to the query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyedNeighbor<K> {
+    pub key: K,
+    pub distance: f32,
+}
+
+/// Wraps an index to map caller-supplied keys to the internal dense ids.
+pub struct KeyedIndex<K> {
+    index: Box<dyn VectorIndexOperator>,
+    policy: DuplicatePolicy,
+    key2id: HashMap<K, usize>,
+    id2key: HashMap<usize, K>,
+}
+
+impl<K: Hash + Eq + Clone + Display> KeyedIndex<K> {
+    /// Creates a keyed index on an index which has no items yet.
+    pub fn new(index: Box<dyn VectorIndexOperator>, policy: DuplicatePolicy) -> Result<Self, NNSearchError> {
+        if !index.is_empty() {
+            return Err(NNSearchError::ValueError("Index to be keyed must be empty".to_string()))
+        }
+        Ok(KeyedIndex {
+            index,
+            policy,
+            key2id: HashMap::new(),
+            id2key: HashMap::new(),
+        })
+    }
+
+    /// Adds the vector with the key. The existing item with the same key is handled by the `DuplicatePolicy`.
+    pub fn add(&mut self, key: K, data: Vec<f32>) -> Result<(), NNSearchError> {
+        let old_id = self.key2id.get(&key).cloned();
+        if old_id.is_some() && self.policy == DuplicatePolicy::ERROR {
+            return Err(NNSearchError::DuplicateKey(key.to_string()))
+        }
+        // NOTE: add the new item before removing the old one so that the old one is kept on failure.
+        let id = self.index.add(data)?;
+        if let Some(old_id) = old_id {
+            self.index.remove(old_id)?;
+            self.id2key.remove(&old_id);
+        }
+        self.key2id.insert(key.clone(), id);
+        self.id2key.insert(id, key);
+        Ok(())
+    }
+
+    pub fn add_batch(&mut self, data_batch: Vec<(K, Vec<f32>)>) -> Result<(), NNSearchError> {
+        for (key, data) in data_batch {
+            self.add(key, data)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the item with the key, failing with `KeyNotFound` if the key is not indexed.
+    pub fn remove(&mut self, key: &K) -> Result<(), NNSearchError> {
+        let id = match self.key2id.get(key) {
+            Some(id) => *id,
+            None => return Err(NNSearchError::KeyNotFound(key.to_string())),
+        };
+        self.index.remove(id)?;
+        self.key2id.remove(key);
+        self.id2key.remove(&id);
+        Ok(())
+    }
+
+    /// Returns `k` neighbors of `query` in ascending order of the distance.
+    pub fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<KeyedNeighbor<K>>, NNSearchError> {
+        Ok(self.index.search(query, k)?
+            .into_iter()
+            .map(|nn| KeyedNeighbor {key: self.id2key[&nn.id].clone(), distance: nn.distance})
+            .collect())
+    }
+
+    /// Returns all items within `radius` from `query` in ascending order of the distance.
+    pub fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<KeyedNeighbor<K>>, NNSearchError> {
+        Ok(self.index.search_radius(query, radius)?
+            .into_iter()
+            .map(|nn| KeyedNeighbor {key: self.id2key[&nn.id].clone(), distance: nn.distance})
+            .collect())
+    }
+
+    pub fn get_vector(&self, key: &K) -> Option<&[f32]> {
+        self.key2id.get(key).and_then(|id| self.index.get_vector(*id))
+    }
+
+    pub fn get_id(&self, key: &K) -> Option<usize> {
+        self.key2id.get(key).cloned()
+    }
+
+    pub fn get_key(&self, id: usize) -> Option<&K> {
+        self.id2key.get(&id)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.key2id.contains_key(key)
+    }
+
+    pub fn inner(&self) -> &dyn VectorIndexOperator {
+        &*self.index
+    }
+
+    pub fn len(&self) -> usize {
+        self.key2id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key2id.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{NSWIndex, NaiveKnnIndex};
+    use crate::linalg::distance::Euclidean;
+
+    #[test]
+    fn test_keyed_index_with_u64_keys() {
+        let mut index: KeyedIndex<u64> = KeyedIndex::new(Box::new(NaiveKnnIndex::new(2, Box::new(Euclidean{}))), DuplicatePolicy::ERROR).unwrap();
+        index.add_batch(vec![(1000, vec![0.1, 0.2]), (7, vec![0.1, 0.1]), (u64::MAX, vec![0.5, 0.5])]).unwrap();
+        let result = index.search(vec![0.1, 0.1], 3).unwrap();
+        assert_eq!(result.iter().map(|nn| nn.key).collect::<Vec<_>>(), vec![7, 1000, u64::MAX]);
+        assert_eq!(result[0].distance, 0.0);
+        assert_eq!(index.search_radius(vec![0.1, 0.1], 0.05).unwrap(), vec![KeyedNeighbor {key: 7, distance: 0.0}]);
+        assert_eq!(index.get_id(&7), Some(1));
+        assert_eq!(index.get_key(2), Some(&u64::MAX));
+        assert_eq!(index.add(7, vec![0.3, 0.3]).unwrap_err(), NNSearchError::DuplicateKey("7".to_string()));
+        assert_eq!(index.get_vector(&7), Some(&[0.1, 0.1][..]));
+
+        index.remove(&7).unwrap();
+        assert_eq!(index.remove(&7).unwrap_err(), NNSearchError::KeyNotFound("7".to_string()));
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.search(vec![0.1, 0.1], 1).unwrap()[0].key, 1000);
+    }
+
+    #[test]
+    fn test_keyed_index_with_string_keys() {
+        let mut index: KeyedIndex<String> = KeyedIndex::new(Box::new(NSWIndex::new(2, Box::new(Euclidean{}), 3, 4)), DuplicatePolicy::OVERWRITE).unwrap();
+        index.add("apple".to_string(), vec![0.1, 0.2]).unwrap();
+        index.add("banana".to_string(), vec![0.9, 0.9]).unwrap();
+        index.add("apple".to_string(), vec![0.8, 0.8]).unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.inner().len(), 2);
+        assert_eq!(index.get_vector(&"apple".to_string()), Some(&[0.8, 0.8][..]));
+        let result = index.search(vec![0.8, 0.8], 2).unwrap();
+        assert_eq!(result[0].key, "apple");
+        // the old item is kept if the new one cannot be added
+        assert!(index.add("banana".to_string(), vec![0.1]).is_err());
+        assert_eq!(index.get_vector(&"banana".to_string()), Some(&[0.9, 0.9][..]));
+    }
+
+    #[test]
+    fn test_keyed_index_requires_empty_index() {
+        let mut inner = NaiveKnnIndex::new(2, Box::new(Euclidean{}));
+        inner.add(vec![0.1, 0.2]).unwrap();
+        assert!(KeyedIndex::<u64>::new(Box::new(inner), DuplicatePolicy::ERROR).is_err());
+    }
+}
@@ -1,8 +1,16 @@
-#![feature(map_first_last)]
-
+pub mod concurrent;
 pub mod error;
+pub mod filter;
 pub mod graph;
 pub mod hasher;
 pub mod index;
+pub mod io;
+pub mod ivf;
+pub mod keyed;
 pub mod linalg;
+pub mod lsh;
+pub mod metadata;
+#[cfg(all(target_endian = "little", target_pointer_width = "64"))]
+pub mod mmap;
+pub mod quantizer;
 pub mod type_utils;
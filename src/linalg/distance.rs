@@ -1,14 +1,175 @@
 use crate::error::NNSearchError;
-use std::fmt::{Debug};
+use crate::type_utils::SetItem;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Display};
+use std::str::FromStr;
 
 /// Type of the distance between two objects.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum DistanceType {
     /// Euclidean distance
-    EUCLIDEAN
+    EUCLIDEAN,
+    /// Cosine distance
+    COSINE,
+    /// Negated inner product
+    INNERPRODUCT,
+    /// Angular distance
+    ANGULAR,
+    /// Manhattan (L1) distance
+    MANHATTAN,
+    /// Chebyshev (L-infinity) distance
+    CHEBYSHEV,
+    /// Minkowski distance with the order p
+    MINKOWSKI(f32),
+    /// Hamming distance between bit vectors
+    HAMMING,
+    /// Jaccard distance between sets
+    JACCARD,
+}
+
+impl DistanceType {
+    pub(crate) fn to_code(self) -> u8 {
+        match self {
+            DistanceType::EUCLIDEAN => 0,
+            DistanceType::COSINE => 1,
+            DistanceType::INNERPRODUCT => 2,
+            DistanceType::ANGULAR => 3,
+            DistanceType::MANHATTAN => 4,
+            DistanceType::CHEBYSHEV => 5,
+            DistanceType::MINKOWSKI(_) => 6,
+            DistanceType::HAMMING => 7,
+            DistanceType::JACCARD => 8,
+        }
+    }
+
+    /// Parameter of the distance stored next to the code.
+    pub(crate) fn param(self) -> Option<f32> {
+        match self {
+            DistanceType::MINKOWSKI(p) => Some(p),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn has_param(code: u8) -> bool {
+        code == 6
+    }
+
+    pub(crate) fn from_code(code: u8, param: Option<f32>) -> Result<Self, NNSearchError> {
+        match code {
+            0 => Ok(DistanceType::EUCLIDEAN),
+            1 => Ok(DistanceType::COSINE),
+            2 => Ok(DistanceType::INNERPRODUCT),
+            3 => Ok(DistanceType::ANGULAR),
+            4 => Ok(DistanceType::MANHATTAN),
+            5 => Ok(DistanceType::CHEBYSHEV),
+            6 => match param {
+                Some(p) if is_valid_minkowski_order(p) => Ok(DistanceType::MINKOWSKI(p)),
+                Some(p) => Err(NNSearchError::ValueError(format!("Invalid order of the Minkowski distance: {}", p))),
+                None => Err(NNSearchError::ValueError("Missing order of the Minkowski distance".to_string())),
+            },
+            7 => Ok(DistanceType::HAMMING),
+            8 => Ok(DistanceType::JACCARD),
+            _ => Err(NNSearchError::ValueError(format!("Unknown distance code: {}", code))),
+        }
+    }
+
+}
+
+// NOTE: the Minkowski distance is a metric only for finite p >= 1, which the graph indexes assume.
+fn is_valid_minkowski_order(p: f32) -> bool {
+    p >= 1.0 && p.is_finite()
+}
+
+/// Parses names such as `l2`, `cosine`, `ip`, `minkowski:3` or `hamming` case-insensitively.
+impl FromStr for DistanceType {
+    type Err = NNSearchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_lowercase();
+        match name.as_str() {
+            "euclidean" | "l2" => Ok(DistanceType::EUCLIDEAN),
+            "cosine" => Ok(DistanceType::COSINE),
+            "ip" | "inner-product" | "inner_product" | "innerproduct" | "dot" => Ok(DistanceType::INNERPRODUCT),
+            "angular" => Ok(DistanceType::ANGULAR),
+            "manhattan" | "l1" | "cityblock" => Ok(DistanceType::MANHATTAN),
+            "chebyshev" | "linf" => Ok(DistanceType::CHEBYSHEV),
+            "hamming" => Ok(DistanceType::HAMMING),
+            "jaccard" => Ok(DistanceType::JACCARD),
+            _ => match name.strip_prefix("minkowski:").map(|p| p.parse::<f32>()) {
+                Some(Ok(p)) if is_valid_minkowski_order(p) => Ok(DistanceType::MINKOWSKI(p)),
+                Some(_) => Err(NNSearchError::ValueError(format!("Invalid order of the Minkowski distance: {}", s))),
+                None => Err(NNSearchError::ValueError(format!("Unknown distance: {}", s))),
+            },
+        }
+    }
+}
+
+/// Displays the canonical name, which can be parsed back by `FromStr` unless it is `MINKOWSKI` with an order other than finite p >= 1.
+impl Display for DistanceType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DistanceType::EUCLIDEAN => write!(f, "euclidean"),
+            DistanceType::COSINE => write!(f, "cosine"),
+            DistanceType::INNERPRODUCT => write!(f, "ip"),
+            DistanceType::ANGULAR => write!(f, "angular"),
+            DistanceType::MANHATTAN => write!(f, "manhattan"),
+            DistanceType::CHEBYSHEV => write!(f, "chebyshev"),
+            DistanceType::MINKOWSKI(p) => write!(f, "minkowski:{}", p),
+            DistanceType::HAMMING => write!(f, "hamming"),
+            DistanceType::JACCARD => write!(f, "jaccard"),
+        }
+    }
+}
+
+/// Builds the distance of a `DistanceType` for the input type `T`.
+/// Fails if the distance is not defined for `T`, e.g. `HAMMING` for `f32`.
+pub trait DistanceFactory<T, U> {
+    fn build(&self) -> Result<Box<dyn PairwiseDistance<T, U>>, NNSearchError>;
+}
+
+fn unsupported_input<T>(distance_type: &DistanceType) -> NNSearchError {
+    NNSearchError::ValueError(format!("Distance {} is not defined for {}", distance_type, std::any::type_name::<T>()))
+}
+
+impl DistanceFactory<f32, f32> for DistanceType {
+    fn build(&self) -> Result<Box<dyn PairwiseDistance<f32, f32>>, NNSearchError> {
+        match *self {
+            DistanceType::EUCLIDEAN => Ok(Box::new(Euclidean{})),
+            DistanceType::COSINE => Ok(Box::new(Cosine{})),
+            DistanceType::INNERPRODUCT => Ok(Box::new(InnerProduct{})),
+            DistanceType::ANGULAR => Ok(Box::new(Angular{})),
+            DistanceType::MANHATTAN => Ok(Box::new(Manhattan{})),
+            DistanceType::CHEBYSHEV => Ok(Box::new(Chebyshev{})),
+            DistanceType::MINKOWSKI(p) => Ok(Box::new(Minkowski{p})),
+            _ => Err(unsupported_input::<f32>(self)),
+        }
+    }
+}
+
+impl DistanceFactory<bool, u32> for DistanceType {
+    fn build(&self) -> Result<Box<dyn PairwiseDistance<bool, u32>>, NNSearchError> {
+        match self {
+            DistanceType::HAMMING => Ok(Box::new(Hamming{})),
+            _ => Err(unsupported_input::<bool>(self)),
+        }
+    }
+}
+
+impl DistanceFactory<SetItem, f32> for DistanceType {
+    fn build(&self) -> Result<Box<dyn PairwiseDistance<SetItem, f32>>, NNSearchError> {
+        match self {
+            DistanceType::JACCARD => Ok(Box::new(Jaccard{})),
+            _ => Err(unsupported_input::<SetItem>(self)),
+        }
+    }
 }
 
-pub trait PairwiseDistance<T, U>: Debug {
+pub trait PairwiseDistance<T, U>: Debug + Send + Sync {
+    /// Returns the type of this distance, which is recorded in saved indexes.
+    /// Distances without the type cannot be saved.
+    fn distance_type(&self) -> Option<DistanceType> {
+        None
+    }
     fn compute(&self, p1: &[T], p2: &[T]) -> Result<U, NNSearchError> {
         if p1.len() != p2.len() {
             return Err(NNSearchError::ValueError(format!("Inconsistent length: {} != {}", p1.len(), p2.len())))
@@ -23,6 +184,9 @@ pub trait PairwiseDistance<T, U>: Debug {
 pub struct Euclidean;
 
 impl PairwiseDistance<f32, f32> for Euclidean {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::EUCLIDEAN)
+    }
     fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
         let mut val = 0.0;
         for i in 0..p1.len() {
@@ -32,10 +196,187 @@ impl PairwiseDistance<f32, f32> for Euclidean {
     }
 }
 
+fn dot(p1: &[f32], p2: &[f32]) -> f32 {
+    let mut val = 0.0;
+    for i in 0..p1.len() {
+        val += p1[i] * p2[i];
+    }
+    val
+}
+
+// NOTE: similarity is 0 if either of the vectors is zero.
+fn cosine_similarity(p1: &[f32], p2: &[f32]) -> f32 {
+    let norm = (dot(p1, p1) * dot(p2, p2)).sqrt();
+    if norm == 0.0 {
+        return 0.0
+    }
+    // clamp rounding errors
+    (dot(p1, p2) / norm).clamp(-1.0, 1.0)
+}
+
+/// 1 - cosine similarity, which ranges in [0, 2].
+#[derive(Debug)]
+pub struct Cosine;
+
+impl PairwiseDistance<f32, f32> for Cosine {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::COSINE)
+    }
+    fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
+        1.0 - cosine_similarity(p1, p2)
+    }
+}
+
+/// Negated inner product so that more similar vectors have smaller distances.
+/// Note that this is not a metric and can be negative.
+#[derive(Debug)]
+pub struct InnerProduct;
+
+impl PairwiseDistance<f32, f32> for InnerProduct {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::INNERPRODUCT)
+    }
+    fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
+        -dot(p1, p2)
+    }
+}
+
+/// Angle between two vectors normalized by pi, which ranges in [0, 1].
+/// Unlike `Cosine`, this satisfies the triangle inequality.
+#[derive(Debug)]
+pub struct Angular;
+
+impl PairwiseDistance<f32, f32> for Angular {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::ANGULAR)
+    }
+    fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
+        cosine_similarity(p1, p2).acos() / std::f32::consts::PI
+    }
+}
+
+#[derive(Debug)]
+pub struct Manhattan;
+
+impl PairwiseDistance<f32, f32> for Manhattan {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::MANHATTAN)
+    }
+    fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
+        let mut val = 0.0;
+        for i in 0..p1.len() {
+            val += (p1[i] - p2[i]).abs();
+        }
+        val
+    }
+}
+
+#[derive(Debug)]
+pub struct Chebyshev;
+
+impl PairwiseDistance<f32, f32> for Chebyshev {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::CHEBYSHEV)
+    }
+    fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
+        let mut val: f32 = 0.0;
+        for i in 0..p1.len() {
+            val = val.max((p1[i] - p2[i]).abs());
+        }
+        val
+    }
+}
+
+/// Minkowski distance of the order `p`, which is a metric when `p` >= 1.
+/// `p` = 1 and 2 are the same as `Manhattan` and `Euclidean`, and `p` = infinity is `Chebyshev`.
+#[derive(Debug)]
+pub struct Minkowski {
+    pub p: f32,
+}
+
+impl PairwiseDistance<f32, f32> for Minkowski {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::MINKOWSKI(self.p))
+    }
+    fn compute_innter(&self, p1: &[f32], p2: &[f32]) -> f32 {
+        if self.p.is_infinite() {
+            return Chebyshev{}.compute_innter(p1, p2)
+        }
+        let mut val = 0.0;
+        for i in 0..p1.len() {
+            val += (p1[i] - p2[i]).abs().powf(self.p);
+        }
+        val.powf(1.0 / self.p)
+    }
+}
+
+/// Exact Jaccard distance between sets, i.e. 1 - |A & B| / |A | B|.
+/// Items can be unsorted and duplicated, and the distance between empty sets is 0.
+///
+/// For weighted sets given as `(item, weight)` pairs, the weighted Jaccard distance
+/// 1 - sum(min(w_a, w_b)) / sum(max(w_a, w_b)) is computed. Weights of duplicated items are summed up.
+#[derive(Debug)]
+pub struct Jaccard;
+
+impl PairwiseDistance<SetItem, f32> for Jaccard {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::JACCARD)
+    }
+    // NOTE: sets can have different sizes.
+    fn compute(&self, p1: &[SetItem], p2: &[SetItem]) -> Result<f32, NNSearchError> {
+        Ok(self.compute_innter(p1, p2))
+    }
+    fn compute_innter(&self, p1: &[SetItem], p2: &[SetItem]) -> f32 {
+        let set1: HashSet<&SetItem> = p1.iter().collect();
+        let set2: HashSet<&SetItem> = p2.iter().collect();
+        let num_union = set1.union(&set2).count();
+        if num_union == 0 {
+            return 0.0
+        }
+        1.0 - set1.intersection(&set2).count() as f32 / num_union as f32
+    }
+}
+
+impl PairwiseDistance<(SetItem, f32), f32> for Jaccard {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::JACCARD)
+    }
+    fn compute(&self, p1: &[(SetItem, f32)], p2: &[(SetItem, f32)]) -> Result<f32, NNSearchError> {
+        if let Some((item, weight)) = p1.iter().chain(p2.iter()).find(|(_, weight)| weight.is_nan() || *weight < 0.0) {
+            return Err(NNSearchError::ValueError(format!("Invalid weight of item {}: {}", item, weight)))
+        }
+        Ok(self.compute_innter(p1, p2))
+    }
+    fn compute_innter(&self, p1: &[(SetItem, f32)], p2: &[(SetItem, f32)]) -> f32 {
+        let to_weights = |p: &[(SetItem, f32)]| {
+            p.iter().fold(HashMap::new(), |mut acc, (item, weight)| {
+                *acc.entry(*item).or_insert(0.0) += weight;
+                acc
+            })
+        };
+        let weights1: HashMap<SetItem, f32> = to_weights(p1);
+        let weights2: HashMap<SetItem, f32> = to_weights(p2);
+        let (mut num, mut denom) = (0.0, 0.0);
+        for item in weights1.keys().chain(weights2.keys().filter(|item| !weights1.contains_key(item))) {
+            let w1 = *weights1.get(item).unwrap_or(&0.0);
+            let w2 = *weights2.get(item).unwrap_or(&0.0);
+            num += w1.min(w2);
+            denom += w1.max(w2);
+        }
+        if denom == 0.0 {
+            return 0.0
+        }
+        1.0 - num / denom
+    }
+}
+
 #[derive(Debug)]
 pub struct Hamming;
 
 impl PairwiseDistance<bool, u32> for Hamming {
+    fn distance_type(&self) -> Option<DistanceType> {
+        Some(DistanceType::HAMMING)
+    }
     fn compute_innter(&self, p1: &[bool], p2: &[bool]) -> u32 {
         (0..p1.len()).fold(0, |dist, idx| {
             if p1[idx] == p2[idx] {
@@ -69,6 +410,81 @@ mod tests {
         assert_eq!(dist.compute(&v1, &v2).unwrap_err(), NNSearchError::ValueError("Inconsistent length: 2 != 1".to_string()));
     }
 
+    #[test]
+    fn test_compute_cosine_distance() {
+        let dist = Cosine{};
+        assert!(dist.compute(&[1.0, 0.0], &[2.0, 0.0]).unwrap().abs() < 1e-6);
+        assert!((dist.compute(&[1.0, 0.0], &[0.0, 3.0]).unwrap() - 1.0).abs() < 1e-6);
+        assert!((dist.compute(&[1.0, 1.0], &[-1.0, -1.0]).unwrap() - 2.0).abs() < 1e-6);
+        assert_eq!(dist.compute(&[0.0, 0.0], &[1.0, 1.0]).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn test_compute_inner_product_distance() {
+        let dist = InnerProduct{};
+        assert_eq!(dist.compute(&[1.0, 2.0], &[3.0, 4.0]).unwrap(), -11.0);
+        // larger inner product is nearer
+        assert!(dist.compute(&[1.0, 1.0], &[2.0, 2.0]).unwrap() < dist.compute(&[1.0, 1.0], &[1.0, 1.0]).unwrap());
+    }
+
+    #[test]
+    fn test_compute_angular_distance() {
+        let dist = Angular{};
+        assert!(dist.compute(&[1.0, 0.0], &[2.0, 0.0]).unwrap().abs() < 1e-6);
+        assert!((dist.compute(&[1.0, 0.0], &[0.0, 3.0]).unwrap() - 0.5).abs() < 1e-6);
+        assert!((dist.compute(&[1.0, 1.0], &[-1.0, -1.0]).unwrap() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_compute_manhattan_distance() {
+        let dist = Manhattan{};
+        assert_eq!(dist.compute(&[1.0, 2.0, 3.0], &[2.0, 0.0, 3.5]).unwrap(), 3.5);
+    }
+
+    #[test]
+    fn test_compute_chebyshev_distance() {
+        let dist = Chebyshev{};
+        assert_eq!(dist.compute(&[1.0, 2.0, 3.0], &[2.0, 0.0, 3.5]).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn test_compute_minkowski_distance() {
+        let v1 = vec![0.1, 0.2, 0.7];
+        let v2 = vec![0.3, 0.4, -0.1];
+        let expected = Manhattan{}.compute(&v1, &v2).unwrap();
+        assert!((Minkowski{p: 1.0}.compute(&v1, &v2).unwrap() - expected).abs() < 1e-6);
+        let expected = Euclidean{}.compute(&v1, &v2).unwrap();
+        assert!((Minkowski{p: 2.0}.compute(&v1, &v2).unwrap() - expected).abs() < 1e-6);
+        let expected = Chebyshev{}.compute(&v1, &v2).unwrap();
+        assert_eq!(Minkowski{p: f32::INFINITY}.compute(&v1, &v2).unwrap(), expected);
+        assert!((Minkowski{p: 3.0}.compute(&[0.0, 0.0], &[1.0, 1.0]).unwrap() - 2f32.powf(1.0 / 3.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_compute_jaccard_distance() {
+        let dist = Jaccard{};
+        let v1: Vec<SetItem> = vec![4, 1, 2, 2];
+        let v2: Vec<SetItem> = vec![3, 1];
+        assert_eq!(dist.compute(&v1, &v2).unwrap(), 0.75);
+        assert_eq!(dist.compute(&v1, &[2, 4, 1]).unwrap(), 0.0);
+        assert_eq!(dist.compute(&v1, &[]).unwrap(), 1.0);
+        let empty: Vec<SetItem> = vec![];
+        assert_eq!(dist.compute(&empty, &empty).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_compute_weighted_jaccard_distance() {
+        let dist = Jaccard{};
+        let v1: Vec<(SetItem, f32)> = vec![(1, 1.0), (2, 2.0), (4, 1.0)];
+        let v2: Vec<(SetItem, f32)> = vec![(2, 1.0), (1, 1.0), (3, 2.0)];
+        // min: 1 + 1 = 2, max: 1 + 2 + 1 + 2 = 6
+        assert!((dist.compute(&v1, &v2).unwrap() - (1.0 - 2.0 / 6.0)).abs() < 1e-6);
+        // unweighted sets are the special case of weight 1
+        let unweighted = |v: &[SetItem]| v.iter().map(|&item| (item, 1.0)).collect::<Vec<_>>();
+        assert_eq!(dist.compute(&unweighted(&[4, 1, 2]), &unweighted(&[3, 1])).unwrap(), 0.75);
+        assert!(dist.compute(&[(1, -1.0)], &v2).is_err());
+    }
+
     #[test]
     fn test_compute_hamming_distance() {
         let dist = Hamming{};
@@ -78,4 +494,48 @@ mod tests {
         let v2 = vec![true, false, true, true, true, true, false, false];
         assert_eq!(dist.compute(&v1, &v2).unwrap(), 5);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_parse_distance_type() {
+        assert_eq!("l2".parse::<DistanceType>().unwrap(), DistanceType::EUCLIDEAN);
+        assert_eq!("Cosine".parse::<DistanceType>().unwrap(), DistanceType::COSINE);
+        assert_eq!("ip".parse::<DistanceType>().unwrap(), DistanceType::INNERPRODUCT);
+        assert_eq!("minkowski:3".parse::<DistanceType>().unwrap(), DistanceType::MINKOWSKI(3.0));
+        assert_eq!("hamming".parse::<DistanceType>().unwrap(), DistanceType::HAMMING);
+        assert!("minkowski:0.5".parse::<DistanceType>().is_err());
+        assert!("minkowski:inf".parse::<DistanceType>().is_err());
+        assert_eq!("unknown".parse::<DistanceType>().unwrap_err(), NNSearchError::ValueError("Unknown distance: unknown".to_string()));
+    }
+
+    #[test]
+    fn test_display_distance_type() {
+        let distance_types = vec![
+            DistanceType::EUCLIDEAN, DistanceType::COSINE, DistanceType::INNERPRODUCT, DistanceType::ANGULAR,
+            DistanceType::MANHATTAN, DistanceType::CHEBYSHEV, DistanceType::MINKOWSKI(2.5),
+            DistanceType::HAMMING, DistanceType::JACCARD,
+        ];
+        for distance_type in distance_types {
+            assert_eq!(distance_type.to_string().parse::<DistanceType>().unwrap(), distance_type);
+            assert_eq!(DistanceType::from_code(distance_type.to_code(), distance_type.param()).unwrap(), distance_type);
+        }
+        assert_eq!(DistanceType::MINKOWSKI(3.0).to_string(), "minkowski:3");
+        // orders rejected by `FromStr` are not loaded from a file either
+        for p in [0.5, 0.0, -2.0, f32::INFINITY, f32::NAN] {
+            assert!(DistanceType::from_code(6, Some(p)).is_err());
+            assert!(DistanceType::MINKOWSKI(p).to_string().parse::<DistanceType>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_build_distance() {
+        let dist: Box<dyn PairwiseDistance<f32, f32>> = DistanceType::MINKOWSKI(1.0).build().unwrap();
+        assert_eq!(dist.distance_type(), Some(DistanceType::MINKOWSKI(1.0)));
+        assert_eq!(dist.compute(&[1.0, 2.0], &[2.0, 0.0]).unwrap(), 3.0);
+        let dist: Box<dyn PairwiseDistance<bool, u32>> = DistanceType::HAMMING.build().unwrap();
+        assert_eq!(dist.compute(&[true, false], &[false, false]).unwrap(), 1);
+        let dist: Box<dyn PairwiseDistance<SetItem, f32>> = DistanceType::JACCARD.build().unwrap();
+        assert_eq!(dist.compute(&[1, 2], &[2, 3]).unwrap(), 1.0 - 1.0 / 3.0);
+        let result: Result<Box<dyn PairwiseDistance<f32, f32>>, _> = DistanceType::HAMMING.build();
+        assert!(result.is_err());
+    }
+}
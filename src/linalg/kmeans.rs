@@ -0,0 +1,94 @@
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+use crate::error::NNSearchError;
+use crate::linalg::distance::PairwiseDistance;
+
+/// Returns the position of the nearest centroid to `vec` and its distance.
+pub fn nearest_centroid(centroids: &[Vec<f32>], vec: &[f32], distance: &dyn PairwiseDistance<f32, f32>) -> Result<(usize, f32), NNSearchError> {
+    let mut nearest = (0, f32::INFINITY);
+    for (pos, centroid) in centroids.iter().enumerate() {
+        let cost = distance.compute(vec, centroid)?;
+        if cost < nearest.1 {
+            nearest = (pos, cost);
+        }
+    }
+    Ok(nearest)
+}
+
+/// Returns the position of the nearest centroid to each vector.
+/// Vectors are assigned in parallel with the `parallel` feature.
+pub fn assign(centroids: &[Vec<f32>], data: &[Vec<f32>], distance: &dyn PairwiseDistance<f32, f32>) -> Result<Vec<usize>, NNSearchError> {
+    #[cfg(feature = "parallel")]
+    let data = data.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let data = data.iter();
+    data.map(|vec| Ok(nearest_centroid(centroids, vec, distance)?.0)).collect()
+}
+
+/// Clusters `data` into `k` clusters by Lloyd's algorithm and returns the centroids.
+///
+/// Centroids are initialized with `k` distinct vectors chosen by `rng`, and updated at most `num_iters` times
+/// until the assignment converges. Vectors are assigned by `distance`, while centroids are the means of the clusters.
+/// A centroid of an empty cluster is moved to a random vector.
+pub fn kmeans(data: &[Vec<f32>], k: usize, num_iters: usize, distance: &dyn PairwiseDistance<f32, f32>, rng: &mut SmallRng) -> Result<Vec<Vec<f32>>, NNSearchError> {
+    if k == 0 || data.len() < k {
+        return Err(NNSearchError::ValueError(format!("Number of vectors {} must be at least k={} (> 0)", data.len(), k)))
+    }
+    let dim = data[0].len();
+    let mut centroids: Vec<Vec<f32>> = data.choose_multiple(rng, k).cloned().collect();
+    let mut assignments = vec![usize::MAX; data.len()];
+    for _ in 0..num_iters {
+        let new_assignments = assign(&centroids, data, distance)?;
+        if new_assignments == assignments {
+            break
+        }
+        assignments = new_assignments;
+        let mut sums = vec![vec![0.0f64; dim]; k];
+        let mut counts = vec![0usize; k];
+        for (vec, &pos) in data.iter().zip(&assignments) {
+            counts[pos] += 1;
+            for (sum, &x) in sums[pos].iter_mut().zip(vec) {
+                *sum += x as f64;
+            }
+        }
+        for ((centroid, sum), &count) in centroids.iter_mut().zip(sums).zip(&counts) {
+            if count == 0 {
+                *centroid = data.choose(rng).unwrap().clone();
+            } else {
+                *centroid = sum.into_iter().map(|x| (x / count as f64) as f32).collect();
+            }
+        }
+    }
+    Ok(centroids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linalg::distance::Euclidean;
+    use crate::linalg::utils::get_rng;
+
+    #[test]
+    fn test_kmeans() {
+        // two well-separated clusters around (0, 0) and (10, 10)
+        let data: Vec<Vec<f32>> = (0..20)
+            .map(|i| {
+                let offset = if i % 2 == 0 { 0.0 } else { 10.0 };
+                vec![offset + (i / 2) as f32 * 0.01, offset]
+            })
+            .collect();
+        let mut centroids = kmeans(&data, 2, 20, &Euclidean{}, &mut get_rng(46)).unwrap();
+        centroids.sort_by(|a, b| a[0].partial_cmp(&b[0]).unwrap());
+        assert!((centroids[0][0] - 0.045).abs() < 1e-4 && centroids[0][1] == 0.0);
+        assert!((centroids[1][0] - 10.045).abs() < 1e-4 && centroids[1][1] == 10.0);
+        let assignments = assign(&centroids, &data, &Euclidean{}).unwrap();
+        assert!(assignments.iter().enumerate().all(|(i, &pos)| pos == i % 2));
+        assert_eq!(nearest_centroid(&centroids, &[9.0, 9.0], &Euclidean{}).unwrap().0, 1);
+
+        assert!(kmeans(&data, 21, 20, &Euclidean{}, &mut get_rng(46)).is_err());
+        assert!(kmeans(&data, 0, 20, &Euclidean{}, &mut get_rng(46)).is_err());
+    }
+}
@@ -1,3 +1,4 @@
 
 pub mod distance;
+pub mod kmeans;
 pub mod utils;
\ No newline at end of file
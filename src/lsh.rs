@@ -0,0 +1,538 @@
+// Locality sensitive hashing indexes which look up candidates in the hash buckets shared with the query.
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::Path;
+
+use crate::error::NNSearchError;
+use crate::filter::IdFilter;
+use crate::graph::Neighbor;
+use crate::hasher::{Hasher, MinHash, RandomProjection};
+use crate::index::{read_distance, read_index_body, validate_dim, validate_query, validate_radius_query, write_distance, VectorIndexOperator, SIMHASH_LSH_INDEX_TAG};
+use crate::io::{read_f32s, read_u64, read_u8, write_f32s, write_index_file, write_u64, write_u8};
+use crate::linalg::distance::PairwiseDistance;
+use crate::metadata::{read_metadata, write_metadata, Metadata};
+use crate::type_utils::SetItem;
+
+/// Parameters of a single search on `SimHashLSHIndex`.
+/// `None` falls back to the setting of the index, so `LSHSearchParams::default()` searches the same way as `search`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LSHSearchParams {
+    /// Number of neighboring buckets probed in each table (`num_probes` of the index by default).
+    pub num_probes: Option<usize>,
+}
+
+/// Index which hashes each vector into `num_tables` tables by the signs of `num_bits` random projections (SimHash).
+///
+/// A search looks up the bucket of the query in each table and the `num_probes` neighboring buckets,
+/// which differ in one of the bits whose projections are the closest to zero, and reranks the items found there
+/// by the exact distances. Sign random projections approximate the angle between vectors,
+/// so the index suits cosine-like distances. Items not sharing any probed bucket with the query are not returned.
+#[derive(Debug)]
+pub struct SimHashLSHIndex {
+    dim: usize,
+    distance: Box<dyn PairwiseDistance<f32, f32>>,
+    num_tables: usize,
+    num_bits: usize,
+    num_probes: usize,
+    // projections of all tables, of which each table uses `num_bits` consecutive ones
+    projection: RandomProjection<f32>,
+    // ids of the items in each bucket keyed by the hash bits
+    tables: Vec<HashMap<u64, Vec<usize>>>,
+    // NOTE: removed points are left as None to keep the ids.
+    points: Vec<Option<Vec<f32>>>,
+    metadata: Vec<Metadata>,
+}
+
+impl SimHashLSHIndex {
+    /// Fails with `ValueError` unless `num_tables` is positive and `num_bits` is between 1 and 64.
+    pub fn new(dim: usize, distance: Box<dyn PairwiseDistance<f32, f32>>, num_tables: usize, num_bits: usize, num_probes: usize) -> Result<Self, NNSearchError> {
+        if num_tables == 0 || !(1..=64).contains(&num_bits) {
+            return Err(NNSearchError::ValueError(format!("Invalid number of tables {} or bits {} (1..=64)", num_tables, num_bits)))
+        }
+        Ok(SimHashLSHIndex {
+            dim,
+            distance,
+            num_tables,
+            num_bits,
+            num_probes,
+            projection: RandomProjection::new(dim, num_tables * num_bits),
+            tables: vec![HashMap::new(); num_tables],
+            points: vec![],
+            metadata: vec![],
+        })
+    }
+
+    pub fn num_tables(&self) -> usize {
+        self.num_tables
+    }
+
+    pub fn num_bits(&self) -> usize {
+        self.num_bits
+    }
+
+    pub fn num_probes(&self) -> usize {
+        self.num_probes
+    }
+
+    /// Sets the number of neighboring buckets probed in each table by default.
+    pub fn set_num_probes(&mut self, num_probes: usize) {
+        self.num_probes = num_probes;
+    }
+
+    /// Returns the hash bits of `vec` in each table along with the projections.
+    fn hash(&self, vec: &[f32]) -> Result<Vec<(u64, Vec<f32>)>, NNSearchError> {
+        let projections = self.projection.to_hash(vec)?;
+        Ok(projections
+            .chunks(self.num_bits)
+            .map(|chunk| {
+                let bits = chunk.iter().enumerate().filter(|(_, x)| **x > 0.0).fold(0u64, |bits, (i, _)| bits | 1 << i);
+                (bits, chunk.to_vec())
+            })
+            .collect())
+    }
+
+    /// Returns the bucket of `vec` in each table.
+    fn buckets(&self, vec: &[f32]) -> Result<Vec<u64>, NNSearchError> {
+        Ok(self.hash(vec)?.into_iter().map(|(bits, _)| bits).collect())
+    }
+
+    /// Returns the items allowed by `filter` in the bucket of `query` and `num_probes` neighboring buckets of each table.
+    fn scan(&self, query: &[f32], num_probes: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        let mut visited = HashSet::new();
+        let mut neighbors = vec![];
+        for (table, (bits, projections)) in self.tables.iter().zip(self.hash(query)?) {
+            // NOTE: flipping the bits whose projections are the closest to zero leads to the most likely buckets.
+            let mut positions: Vec<usize> = (0..self.num_bits).collect();
+            positions.sort_by(|&a, &b| projections[a].abs().total_cmp(&projections[b].abs()));
+            let probes = std::iter::once(bits).chain(positions.into_iter().take(num_probes).map(|i| bits ^ 1 << i));
+            for bucket in probes {
+                for &id in table.get(&bucket).map(|ids| ids.as_slice()).unwrap_or(&[]) {
+                    if filter.allows(id) && visited.insert(id) {
+                        neighbors.push(Neighbor {id, distance: self.distance.compute(query, self.points[id].as_ref().unwrap())?});
+                    }
+                }
+            }
+        }
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        Ok(neighbors)
+    }
+
+    /// Same as `search` with the search parameters given by `params`.
+    pub fn search_with_lsh_params(&self, query: Vec<f32>, k: usize, params: &LSHSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_filtered_with_lsh_params(query, k, &|_| true, params)
+    }
+
+    /// Same as `search_filtered` with the search parameters given by `params`.
+    pub fn search_filtered_with_lsh_params(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter, params: &LSHSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        let mut neighbors = self.scan(&query, params.num_probes.unwrap_or(self.num_probes), filter)?;
+        neighbors.truncate(k);
+        Ok(neighbors)
+    }
+
+    /// Same as `search_radius` with the search parameters given by `params`.
+    pub fn search_radius_with_lsh_params(&self, query: Vec<f32>, radius: f32, params: &LSHSearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_radius_query(self, &query, radius)?;
+        let mut neighbors = self.scan(&query, params.num_probes.unwrap_or(self.num_probes), &|_| true)?;
+        neighbors.retain(|nn| nn.distance <= radius);
+        Ok(neighbors)
+    }
+
+    fn push(&mut self, data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError> {
+        let id = self.points.len();
+        let buckets = self.buckets(&data)?;
+        for (table, bucket) in self.tables.iter_mut().zip(buckets) {
+            table.entry(bucket).or_default().push(id);
+        }
+        self.points.push(Some(data));
+        self.metadata.push(metadata);
+        Ok(id)
+    }
+
+    // NOTE: the projections are generated from the fixed seed, so the buckets are rebuilt from the vectors on load.
+    pub fn save(&self, path: &Path) -> Result<(), NNSearchError> {
+        let mut body = vec![];
+        write_distance(&mut body, &*self.distance)?;
+        for size in &[self.dim, self.num_tables, self.num_bits, self.num_probes, self.points.len()] {
+            write_u64(&mut body, *size as u64)?;
+        }
+        for (point, metadata) in self.points.iter().zip(&self.metadata) {
+            match point {
+                Some(vec) => {
+                    write_u8(&mut body, 0)?;
+                    write_f32s(&mut body, vec)?;
+                    write_metadata(&mut body, metadata)?;
+                }
+                None => write_u8(&mut body, 1)?,
+            }
+        }
+        write_index_file(path, SIMHASH_LSH_INDEX_TAG, &body)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, NNSearchError> {
+        Self::read_from(&mut read_index_body(path, SIMHASH_LSH_INDEX_TAG)?.as_slice())
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Self, NNSearchError> {
+        let distance = read_distance(reader)?;
+        let dim = read_u64(reader)? as usize;
+        let num_tables = read_u64(reader)? as usize;
+        let num_bits = read_u64(reader)? as usize;
+        let num_probes = read_u64(reader)? as usize;
+        let num_points = read_u64(reader)? as usize;
+        let mut index = SimHashLSHIndex::new(dim, distance, num_tables, num_bits, num_probes)?;
+        for _ in 0..num_points {
+            if read_u8(reader)? != 0 {
+                index.points.push(None);
+                index.metadata.push(Metadata::new());
+            } else {
+                let vec = read_f32s(reader, dim)?;
+                let metadata = read_metadata(reader)?;
+                index.push(vec, metadata)?;
+            }
+        }
+        Ok(index)
+    }
+}
+
+impl VectorIndexOperator for SimHashLSHIndex {
+    fn add_with_metadata(&mut self, data: Vec<f32>, metadata: Metadata) -> Result<usize, NNSearchError> {
+        validate_dim(self.dim, &data)?;
+        self.push(data, metadata)
+    }
+    /// Fewer than `k` neighbors are returned if less than `k` items share the probed buckets with `query`.
+    /// Use `search_with_lsh_params` to change the number of probed buckets per search.
+    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_with_lsh_params(query, k, &LSHSearchParams::default())
+    }
+    fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_filtered_with_lsh_params(query, k, filter, &LSHSearchParams::default())
+    }
+    /// Items not sharing the probed buckets with `query` are not returned.
+    fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_radius_with_lsh_params(query, radius, &LSHSearchParams::default())
+    }
+    fn remove(&mut self, id: usize) -> Result<(), NNSearchError> {
+        let vec = match self.points.get_mut(id) {
+            Some(point) if point.is_some() => point.take().unwrap(),
+            _ => return Err(NNSearchError::NotFound(id)),
+        };
+        self.metadata[id] = Metadata::new();
+        let buckets = self.buckets(&vec)?;
+        for (table, bucket) in self.tables.iter_mut().zip(buckets) {
+            let ids = table.get_mut(&bucket).unwrap();
+            ids.retain(|&other| other != id);
+            if ids.is_empty() {
+                table.remove(&bucket);
+            }
+        }
+        Ok(())
+    }
+    fn get_vector(&self, id: usize) -> Option<&[f32]> {
+        self.points.get(id).and_then(|point| point.as_deref())
+    }
+    fn get_metadata(&self, id: usize) -> Option<&Metadata> {
+        self.get_vector(id).map(|_| &self.metadata[id])
+    }
+    fn get_distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &*self.distance
+    }
+    fn dim(&self) -> usize {
+        self.dim
+    }
+    fn len(&self) -> usize {
+        self.points.iter().filter(|point| point.is_some()).count()
+    }
+}
+
+// NOTE: the probabilities are integrated by the midpoint rule with this number of steps.
+const NUM_INTEGRATION_STEPS: usize = 100;
+
+/// Integrates `f` over [`start`, `end`] by the midpoint rule.
+fn integrate<F: Fn(f64) -> f64>(f: F, start: f64, end: f64) -> f64 {
+    let step = (end - start) / NUM_INTEGRATION_STEPS as f64;
+    (0..NUM_INTEGRATION_STEPS).map(|i| f(start + (i as f64 + 0.5) * step) * step).sum()
+}
+
+/// Chooses the number of bands b and rows r with b * r <= `num_hashes` for `MinHashLSHIndex`,
+/// which minimize the sum of the probabilities of false positives (pairs whose Jaccard similarity is below `threshold`
+/// but which share a band) and false negatives (pairs above `threshold` which share no band),
+/// assuming that the similarities are uniformly distributed.
+/// Fails with `ValueError` if `num_hashes` is 0 or `threshold` is not within [0, 1].
+pub fn optimal_bands(num_hashes: usize, threshold: f32) -> Result<(usize, usize), NNSearchError> {
+    if num_hashes == 0 || !(0.0..=1.0).contains(&threshold) {
+        return Err(NNSearchError::ValueError(format!("Invalid number of hashes {} or threshold {}", num_hashes, threshold)))
+    }
+    let threshold = threshold as f64;
+    let mut best = (1, 1, f64::INFINITY);
+    for num_bands in 1..=num_hashes {
+        for num_rows in 1..=num_hashes / num_bands {
+            // probability that a pair with the similarity s shares at least one band
+            let candidate = |s: f64| 1.0 - (1.0 - s.powi(num_rows as i32)).powi(num_bands as i32);
+            let false_positive = integrate(candidate, 0.0, threshold);
+            let false_negative = integrate(|s| 1.0 - candidate(s), threshold, 1.0);
+            if false_positive + false_negative < best.2 {
+                best = (num_bands, num_rows, false_positive + false_negative);
+            }
+        }
+    }
+    Ok((best.0, best.1))
+}
+
+/// Index of sets which splits the `MinHash` signature of each set into `num_bands` bands of `num_rows` hash values,
+/// and buckets the sets by each band. Sets sharing any band with the query are the candidates,
+/// whose Jaccard similarities are estimated by the fraction of the hash values equal to those of the query.
+///
+/// A pair with the Jaccard similarity s becomes a candidate with the probability 1 - (1 - s^r)^b for b bands of r rows,
+/// so the number of bands and rows sets the threshold of the similarity. See `optimal_bands` to choose them.
+/// Results are returned as neighbors whose distances are the estimated Jaccard distances, i.e. 1 - similarity.
+#[derive(Debug)]
+pub struct MinHashLSHIndex {
+    minhash: MinHash,
+    num_bands: usize,
+    num_rows: usize,
+    // ids of the sets in each bucket keyed by the hash values of the band
+    bands: Vec<HashMap<Vec<SetItem>, Vec<usize>>>,
+    // NOTE: signatures of removed sets are left as None to keep the ids.
+    signatures: Vec<Option<Vec<SetItem>>>,
+}
+
+impl MinHashLSHIndex {
+    /// Creates an index for sets of items less than `dim` with signatures of `num_bands` * `num_rows` hash values.
+    /// Fails with `ValueError` if `num_bands` or `num_rows` is 0.
+    pub fn new(dim: usize, num_bands: usize, num_rows: usize) -> Result<Self, NNSearchError> {
+        if num_bands == 0 || num_rows == 0 {
+            return Err(NNSearchError::ValueError(format!("Invalid number of bands {} or rows {}", num_bands, num_rows)))
+        }
+        Ok(MinHashLSHIndex {
+            minhash: MinHash::new(num_bands * num_rows, dim),
+            num_bands,
+            num_rows,
+            bands: vec![HashMap::new(); num_bands],
+            signatures: vec![],
+        })
+    }
+
+    /// Creates an index with the bands and rows chosen by `optimal_bands` for `threshold` within `num_hashes` hash values.
+    pub fn with_threshold(dim: usize, num_hashes: usize, threshold: f32) -> Result<Self, NNSearchError> {
+        let (num_bands, num_rows) = optimal_bands(num_hashes, threshold)?;
+        MinHashLSHIndex::new(dim, num_bands, num_rows)
+    }
+
+    pub fn num_bands(&self) -> usize {
+        self.num_bands
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.num_rows
+    }
+
+    pub fn dim(&self) -> usize {
+        self.minhash.dim()
+    }
+
+    /// Adds the set and returns the id assigned to it.
+    /// Fails with `ValueError` if an item is not less than `dim`.
+    pub fn add(&mut self, set: &[SetItem]) -> Result<usize, NNSearchError> {
+        let signature = self.minhash.to_hash(set)?;
+        let id = self.signatures.len();
+        for (band, key) in self.bands.iter_mut().zip(signature.chunks(self.num_rows)) {
+            band.entry(key.to_vec()).or_default().push(id);
+        }
+        self.signatures.push(Some(signature));
+        Ok(id)
+    }
+
+    pub fn add_batch(&mut self, sets: &[Vec<SetItem>]) -> Result<(), NNSearchError> {
+        for set in sets {
+            self.add(set)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the set, failing with `NotFound` if the id does not exist. Ids of the other sets are kept.
+    pub fn remove(&mut self, id: usize) -> Result<(), NNSearchError> {
+        let signature = match self.signatures.get_mut(id) {
+            Some(signature) if signature.is_some() => signature.take().unwrap(),
+            _ => return Err(NNSearchError::NotFound(id)),
+        };
+        for (band, key) in self.bands.iter_mut().zip(signature.chunks(self.num_rows)) {
+            let ids = band.get_mut(key).unwrap();
+            ids.retain(|&other| other != id);
+            if ids.is_empty() {
+                band.remove(key);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_signature(&self, id: usize) -> Option<&[SetItem]> {
+        self.signatures.get(id).and_then(|signature| signature.as_deref())
+    }
+
+    /// Returns the candidates sharing a band with `set` in ascending order of the estimated Jaccard distance.
+    fn candidates(&self, set: &[SetItem]) -> Result<Vec<Neighbor>, NNSearchError> {
+        let signature = self.minhash.to_hash(set)?;
+        let mut visited = HashSet::new();
+        let mut neighbors = vec![];
+        for (band, key) in self.bands.iter().zip(signature.chunks(self.num_rows)) {
+            for &id in band.get(key).map(|ids| ids.as_slice()).unwrap_or(&[]) {
+                if visited.insert(id) {
+                    let other = self.signatures[id].as_ref().unwrap();
+                    let num_matches = signature.iter().zip(other).filter(|(a, b)| a == b).count();
+                    neighbors.push(Neighbor {id, distance: 1.0 - num_matches as f32 / signature.len() as f32});
+                }
+            }
+        }
+        neighbors.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        Ok(neighbors)
+    }
+
+    /// Returns the sets whose estimated Jaccard similarities to `set` are at least `threshold`
+    /// in ascending order of the estimated Jaccard distance.
+    /// Sets sharing no band with `set` are not returned even if they are similar enough.
+    pub fn search_threshold(&self, set: &[SetItem], threshold: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        if threshold.is_nan() {
+            return Err(NNSearchError::ValueError("Threshold must not be NaN".to_string()))
+        }
+        let mut neighbors = self.candidates(set)?;
+        neighbors.retain(|nn| 1.0 - nn.distance >= threshold);
+        Ok(neighbors)
+    }
+
+    /// Returns at most `k` sets in descending order of the estimated Jaccard similarity to `set`.
+    /// Fewer sets are returned if less than `k` sets share a band with `set`.
+    /// Fails with `EmptyIndex` if nothing is indexed and `KTooLarge` if `k` exceeds the number of sets.
+    pub fn search(&self, set: &[SetItem], k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        if self.is_empty() {
+            return Err(NNSearchError::EmptyIndex)
+        }
+        if k > self.len() {
+            return Err(NNSearchError::KTooLarge {k, len: self.len()})
+        }
+        let mut neighbors = self.candidates(set)?;
+        neighbors.truncate(k);
+        Ok(neighbors)
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.iter().filter(|signature| signature.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{load_index, NaiveKnnIndex};
+    use crate::linalg::distance::Cosine;
+    use crate::linalg::utils::generate_matrix;
+
+    fn ids(neighbors: &[Neighbor]) -> Vec<usize> {
+        neighbors.iter().map(|nn| nn.id).collect()
+    }
+
+    fn recall(index: &SimHashLSHIndex, mat: &[Vec<f32>], k: usize, params: &LSHSearchParams) -> f32 {
+        let mut exact = NaiveKnnIndex::new(mat[0].len(), Box::new(Cosine{}));
+        exact.add_batch(mat.to_vec()).unwrap();
+        let mut hits = 0;
+        for query in &mat[..50] {
+            let expected = ids(&exact.search(query.clone(), k).unwrap());
+            hits += ids(&index.search_with_lsh_params(query.clone(), k, params).unwrap()).iter().filter(|id| expected.contains(id)).count();
+        }
+        hits as f32 / (50 * k) as f32
+    }
+
+    #[test]
+    fn test_simhash_lsh_index() {
+        let mat: Vec<Vec<f32>> = generate_matrix(500, 16).into_iter().map(|vec| vec.into_iter().map(|x| x - 0.5).collect()).collect();
+        let mut index = SimHashLSHIndex::new(16, Box::new(Cosine{}), 10, 8, 0).unwrap();
+        index.add_batch(mat.clone()).unwrap();
+        assert_eq!(index.len(), 500);
+        let neighbors = index.search(mat[3].clone(), 5).unwrap();
+        assert_eq!(neighbors[0].id, 3);
+        assert!(neighbors.windows(2).all(|pair| pair[0].distance <= pair[1].distance));
+
+        let probes = LSHSearchParams {num_probes: Some(4)};
+        let without_probes = recall(&index, &mat, 10, &LSHSearchParams::default());
+        let with_probes = recall(&index, &mat, 10, &probes);
+        assert!(with_probes > without_probes && with_probes > 0.8, "{} {}", without_probes, with_probes);
+        // the filtered and the radius searches find more items in the probed buckets
+        let even = |id: usize| id.is_multiple_of(2);
+        let filtered = index.search_filtered_with_lsh_params(mat[3].clone(), 400, &even, &probes).unwrap();
+        assert!(filtered.len() > index.search_filtered(mat[3].clone(), 400, &even).unwrap().len());
+        assert!(filtered.iter().all(|nn| even(nn.id)));
+        let within = index.search_radius_with_lsh_params(mat[3].clone(), 0.5, &probes).unwrap();
+        assert!(within.len() > index.search_radius(mat[3].clone(), 0.5).unwrap().len());
+
+        let filtered = index.search_filtered(mat[3].clone(), 5, &|id: usize| id.is_multiple_of(2)).unwrap();
+        assert!(filtered.iter().all(|nn| nn.id.is_multiple_of(2)));
+        assert!(index.search_radius(mat[3].clone(), 0.0).unwrap().iter().any(|nn| nn.id == 3));
+
+        index.remove(3).unwrap();
+        assert!(index.remove(3).is_err());
+        assert!(!ids(&index.search(mat[3].clone(), 5).unwrap()).contains(&3));
+        assert_eq!(index.add(mat[3].clone()).unwrap(), 500);
+        assert!(index.add(vec![0.0; 3]).is_err());
+
+        assert!(SimHashLSHIndex::new(16, Box::new(Cosine{}), 8, 65, 0).is_err());
+        assert!(SimHashLSHIndex::new(16, Box::new(Cosine{}), 0, 10, 0).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_simhash_lsh_index() {
+        let path = std::env::temp_dir().join("nnsearch_test_save_and_load_simhash_lsh_index.bin");
+        let mat = generate_matrix(50, 4);
+        let mut index = SimHashLSHIndex::new(4, Box::new(Cosine{}), 4, 6, 2).unwrap();
+        index.add_batch(mat.clone()).unwrap();
+        index.remove(7).unwrap();
+        index.save(&path).unwrap();
+        let loaded = SimHashLSHIndex::load(&path).unwrap();
+        assert_eq!(loaded.points, index.points);
+        assert_eq!(loaded.tables, index.tables);
+        let loaded = load_index(&path).unwrap();
+        assert_eq!(loaded.search(mat[1].clone(), 3).unwrap(), index.search(mat[1].clone(), 3).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_optimal_bands() {
+        for &(num_hashes, threshold) in &[(128, 0.5), (128, 0.8), (64, 0.3)] {
+            let (num_bands, num_rows) = optimal_bands(num_hashes, threshold).unwrap();
+            assert!(num_bands * num_rows <= num_hashes);
+            // the similarity at which the probability of being a candidate rises steeply
+            let steepest = (1.0 / num_bands as f32).powf(1.0 / num_rows as f32);
+            assert!((steepest - threshold).abs() < 0.1, "b={} r={}", num_bands, num_rows);
+        }
+        assert!(optimal_bands(0, 0.5).is_err());
+        assert!(optimal_bands(128, 1.5).is_err());
+    }
+
+    #[test]
+    fn test_minhash_lsh_index() {
+        let base: Vec<SetItem> = (0..40).collect();
+        // sets with the Jaccard similarities 1, 0.6, 0.2 and 0 to `base`
+        let sets = vec![base.clone(), (10..50).collect::<Vec<_>>(), (30..70).collect(), (100..140).collect()];
+        let mut index = MinHashLSHIndex::with_threshold(200, 128, 0.5).unwrap();
+        index.add_batch(&sets).unwrap();
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.num_bands() * index.num_rows(), index.get_signature(0).unwrap().len());
+
+        let neighbors = index.search_threshold(&base, 0.5).unwrap();
+        assert_eq!(neighbors.iter().map(|nn| nn.id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(neighbors[0].distance, 0.0);
+        assert!((neighbors[1].distance - 0.4).abs() < 0.1);
+        assert_eq!(index.search(&base, 1).unwrap()[0].id, 0);
+        assert!(index.search(&base, 4).unwrap().iter().all(|nn| nn.id != 3));
+        assert!(matches!(index.search(&base, 5), Err(NNSearchError::KTooLarge {k: 5, len: 4})));
+        assert!(index.add(&[200]).is_err());
+
+        index.remove(0).unwrap();
+        assert!(index.remove(0).is_err());
+        assert_eq!(index.search_threshold(&base, 0.5).unwrap().iter().map(|nn| nn.id).collect::<Vec<_>>(), vec![1]);
+        assert!(MinHashLSHIndex::new(200, 0, 4).is_err());
+    }
+}
@@ -1,30 +1,217 @@
 extern crate clap;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use nnsearch_rs::error::NNSearchError;
+use nnsearch_rs::index::{load_index, NSWIndex, NaiveKnnIndex, VectorIndexOperator};
+use nnsearch_rs::io::read_vectors;
+use nnsearch_rs::ivf::{IVFFlatIndex, IVFPQIndex};
+use nnsearch_rs::lsh::SimHashLSHIndex;
+use nnsearch_rs::linalg::distance::{DistanceFactory, DistanceType, PairwiseDistance};
+use nnsearch_rs::quantizer::ScalarQuantizerType;
+use std::path::Path;
 use std::process::exit;
 
+fn parse_distance(name: &str) -> Result<Box<dyn PairwiseDistance<f32, f32>>, NNSearchError> {
+    name.parse::<DistanceType>()?.build()
+}
+
+fn parse_usize(matches: &ArgMatches, name: &str) -> Result<usize, NNSearchError> {
+    let value = matches.value_of(name).unwrap();
+    value.parse::<usize>().map_err(|_| NNSearchError::ValueError(format!("Invalid {}: {}", name, value)))
+}
+
+// index types which use each of the options specific to index types
+const INDEX_OPTIONS: &[(&str, &[&str])] = &[
+    ("trial", &["nsw"]),
+    ("min_degree", &["nsw"]),
+    ("nlist", &["ivf", "ivfpq"]),
+    ("nprobe", &["ivf", "ivfpq", "lsh"]),
+    ("tables", &["lsh"]),
+    ("bits", &["lsh"]),
+    ("subspaces", &["ivfpq"]),
+    ("rerank", &["naive", "nsw", "ivfpq"]),
+    ("quantizer", &["naive", "nsw"]),
+    ("mmap", &["nsw"]),
+];
+
+/// Fails if an option is given explicitly to an index type which ignores it.
+fn validate_index_options(matches: &ArgMatches, index_type: &str) -> Result<(), NNSearchError> {
+    // NOTE: options left to their default values are not counted as occurrences.
+    for (name, index_types) in INDEX_OPTIONS {
+        if matches.occurrences_of(name) > 0 && !index_types.contains(&index_type) {
+            return Err(NNSearchError::ValueError(format!("--{} is not used by the {} index", name.replace('_', "-"), index_type)))
+        }
+    }
+    if matches.occurrences_of("rerank") > 0 && index_type != "ivfpq" && !matches.is_present("quantizer") {
+        return Err(NNSearchError::ValueError(format!("--rerank is used only with --quantizer by the {} index", index_type)))
+    }
+    Ok(())
+}
+
+fn run_index(matches: &ArgMatches) -> Result<(), NNSearchError> {
+    validate_index_options(matches, matches.value_of("type").unwrap())?;
+    let vectors = read_vectors(Path::new(matches.value_of("input").unwrap()))?;
+    let output = Path::new(matches.value_of("output").unwrap());
+    let dim = match vectors.first() {
+        Some(vec) => vec.len(),
+        None => return Err(NNSearchError::ValueError("No vectors in input file".to_string())),
+    };
+    if let Some(vec) = vectors.iter().find(|vec| vec.len() != dim) {
+        return Err(NNSearchError::ValueError(format!("Inconsistent length: {} != {}", vec.len(), dim)))
+    }
+    let distance = parse_distance(matches.value_of("distance").unwrap())?;
+    let quantizer_type = match matches.value_of("quantizer") {
+        Some(name) => Some(name.parse::<ScalarQuantizerType>()?),
+        None => None,
+    };
+    match matches.value_of("type").unwrap() {
+        "naive" => {
+            let mut index = match quantizer_type {
+                Some(quantizer_type) => NaiveKnnIndex::new_quantized(dim, distance, quantizer_type, parse_usize(matches, "rerank")?),
+                None => NaiveKnnIndex::new(dim, distance),
+            };
+            index.add_batch(vectors)?;
+            index.save(output)
+        }
+        "nsw" => {
+            let trial = parse_usize(matches, "trial")?;
+            let min_degree = parse_usize(matches, "min_degree")?;
+            let mut index = match quantizer_type {
+                Some(quantizer_type) => NSWIndex::new_quantized(dim, distance, trial, min_degree, quantizer_type, parse_usize(matches, "rerank")?),
+                None => NSWIndex::new(dim, distance, trial, min_degree),
+            };
+            index.add_batch(vectors)?;
+            if matches.is_present("mmap") {
+                index.save_mmap(output)
+            } else {
+                index.save(output)
+            }
+        }
+        "ivf" => {
+            let nlist = parse_usize(matches, "nlist")?;
+            let nprobe = parse_usize(matches, "nprobe")?;
+            let mut index = IVFFlatIndex::new(dim, distance, nlist, nprobe);
+            index.add_batch(vectors)?;
+            index.save(output)
+        }
+        "ivfpq" => {
+            if distance.distance_type() != Some(DistanceType::EUCLIDEAN) {
+                return Err(NNSearchError::ValueError("ivfpq index supports only euclidean distance".to_string()))
+            }
+            let nlist = parse_usize(matches, "nlist")?;
+            let nprobe = parse_usize(matches, "nprobe")?;
+            let num_subspaces = parse_usize(matches, "subspaces")?;
+            let rerank = parse_usize(matches, "rerank")?;
+            let mut index = IVFPQIndex::new(dim, nlist, nprobe, num_subspaces, 256, rerank);
+            index.add_batch(vectors)?;
+            index.save(output)
+        }
+        "lsh" => {
+            let num_tables = parse_usize(matches, "tables")?;
+            let num_bits = parse_usize(matches, "bits")?;
+            let num_probes = parse_usize(matches, "nprobe")?;
+            let mut index = SimHashLSHIndex::new(dim, distance, num_tables, num_bits, num_probes)?;
+            index.add_batch(vectors)?;
+            index.save(output)
+        }
+        index_type => Err(NNSearchError::ValueError(format!("Unknown index type: {}", index_type))),
+    }
+}
+
+// NOTE: JSON has no literal for NaN or infinity, so non-finite distances are written as null.
+fn to_json_number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn run_search(matches: &ArgMatches) -> Result<(), NNSearchError> {
+    let index = load_index(Path::new(matches.value_of("index").unwrap()))?;
+    let queries = read_vectors(Path::new(matches.value_of("query").unwrap()))?;
+    let k = parse_usize(matches, "k")?;
+    let radius = match matches.value_of("radius") {
+        Some(value) => Some(value.parse::<f32>().map_err(|_| NNSearchError::ValueError(format!("Invalid radius: {}", value)))?),
+        None => None,
+    };
+    let format = matches.value_of("format").unwrap();
+    let results = match radius {
+        Some(radius) => queries.into_iter().map(|query| index.search_radius(query, radius)).collect::<Result<Vec<_>, _>>()?,
+        // NOTE: k is capped by the number of indexed items.
+        None => index.search_batch(&queries, k.min(index.len()))?,
+    };
+    for (query_id, neighbors) in results.into_iter().enumerate() {
+        match format {
+            "tsv" => {
+                for (rank, nn) in neighbors.iter().enumerate() {
+                    println!("{}\t{}\t{}\t{}", query_id, rank, nn.id, nn.distance);
+                }
+            }
+            "json" => {
+                let ids = neighbors.iter().map(|nn| nn.id.to_string()).collect::<Vec<_>>();
+                let distances = neighbors.iter().map(|nn| to_json_number(nn.distance)).collect::<Vec<_>>();
+                println!("{{\"query\": {}, \"ids\": [{}], \"distances\": [{}]}}", query_id, ids.join(", "), distances.join(", "));
+            }
+            _ => return Err(NNSearchError::ValueError(format!("Unknown format: {}", format))),
+        }
+    }
+    Ok(())
+}
+
 fn main() {
     let matches = App::new("nnsearch")
                     .about("Nearest neighbor searcher for Rust")
                     .version("0.1.0")
                     .subcommand(SubCommand::with_name("index")
                                 .about("indexing objects")
-                                .arg(Arg::with_name("input").help("path to input vector file"))
-                                .arg(Arg::with_name("output").help("path to output file")))
+                                .arg(Arg::with_name("input").required(true).help("path to input vector file"))
+                                .arg(Arg::with_name("output").required(true).help("path to output file"))
+                                .arg(Arg::with_name("type").long("type").takes_value(true)
+                                     .possible_values(&["naive", "nsw", "ivf", "ivfpq", "lsh"]).default_value("nsw").help("index type"))
+                                .arg(Arg::with_name("distance").long("distance").takes_value(true)
+                                     .default_value("euclidean").help("distance between vectors (l2, cosine, ip, angular, l1, chebyshev or minkowski:<p>)"))
+                                .arg(Arg::with_name("trial").long("trial").takes_value(true)
+                                     .default_value("3").help("number of trials of the graph search"))
+                                .arg(Arg::with_name("min_degree").long("min-degree").takes_value(true)
+                                     .default_value("4").help("number of neighbors connected to an added node"))
+                                .arg(Arg::with_name("nlist").long("nlist").takes_value(true)
+                                     .default_value("100").help("number of inverted lists of the ivf index"))
+                                .arg(Arg::with_name("nprobe").long("nprobe").takes_value(true)
+                                     .default_value("8").help("number of inverted lists scanned on search, or neighboring buckets probed in each table of the lsh index"))
+                                .arg(Arg::with_name("tables").long("tables").takes_value(true)
+                                     .default_value("8").help("number of hash tables of the lsh index"))
+                                .arg(Arg::with_name("bits").long("bits").takes_value(true)
+                                     .default_value("16").help("number of hash bits (at most 64) in each table of the lsh index"))
+                                .arg(Arg::with_name("subspaces").long("subspaces").takes_value(true)
+                                     .default_value("8").help("number of subspaces (bytes of a code) of the ivfpq index"))
+                                .arg(Arg::with_name("rerank").long("rerank").takes_value(true)
+                                     .default_value("0").help("number of candidates reranked by the original vectors kept in the ivfpq or quantized index"))
+                                .arg(Arg::with_name("quantizer").long("quantizer").takes_value(true)
+                                     .possible_values(&["int8", "fp16"]).help("scalar quantization of the vectors in the naive or nsw index"))
+                                .arg(Arg::with_name("mmap").long("mmap")
+                                     .help("save the nsw index in the layout which is memory-mapped on search")))
                     .subcommand(SubCommand::with_name("search")
                                 .about("searching from indexed objects")
-                                .arg(Arg::with_name("index").help("index file"))
-                                .arg(Arg::with_name("query").help("query file")))
+                                .arg(Arg::with_name("index").required(true).help("index file"))
+                                .arg(Arg::with_name("query").required(true).help("query file"))
+                                .arg(Arg::with_name("k").short("k").takes_value(true)
+                                     .default_value("10").help("number of neighbors"))
+                                .arg(Arg::with_name("radius").long("radius").takes_value(true)
+                                     .help("return all neighbors within the distance instead of k neighbors"))
+                                .arg(Arg::with_name("format").long("format").takes_value(true)
+                                     .possible_values(&["tsv", "json"]).default_value("tsv").help("output format")))
                     .get_matches();
-    if let Some(_matches) = matches.subcommand_matches("index") {
-        println!("running indexing");
-        // TODO
-        exit(0);
-    }
-
-    if let Some(_matches) = matches.subcommand_matches("search") {
-        println!("running search");
-        // TODO
-        exit(0);
+    let result = match matches.subcommand() {
+        ("index", Some(matches)) => run_index(matches),
+        ("search", Some(matches)) => run_search(matches),
+        _ => {
+            eprintln!("{}", matches.usage());
+            exit(1);
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,174 @@
+// Metadata attached to vectors and filters over it.
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use crate::error::NNSearchError;
+use crate::io::{read_str, read_u64, read_u8, write_str, write_u64, write_u8, MAX_RESERVED_ITEMS};
+
+/// Value of a metadata field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataValue {
+    STRING(String),
+    INT(i64),
+    FLOAT(f64),
+    TAGS(Vec<String>),
+}
+
+impl MetadataValue {
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            MetadataValue::INT(value) => Some(*value as f64),
+            MetadataValue::FLOAT(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn to_code(&self) -> u8 {
+        match self {
+            MetadataValue::STRING(_) => 0,
+            MetadataValue::INT(_) => 1,
+            MetadataValue::FLOAT(_) => 2,
+            MetadataValue::TAGS(_) => 3,
+        }
+    }
+}
+
+/// Fields of metadata by name.
+pub type Metadata = BTreeMap<String, MetadataValue>;
+
+pub(crate) static EMPTY_METADATA: Metadata = BTreeMap::new();
+
+/// Filter expression evaluated against the metadata of each item.
+/// An item without the field referred by a condition does not match it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeFilter {
+    /// The field equals the value. INT and FLOAT are compared with each other as numbers.
+    EQ(String, MetadataValue),
+    /// The numeric field is in the range. Both bounds are inclusive and optional.
+    RANGE {field: String, min: Option<f64>, max: Option<f64>},
+    /// The TAGS field contains the tag.
+    HASTAG(String, String),
+    AND(Vec<AttributeFilter>),
+    OR(Vec<AttributeFilter>),
+    NOT(Box<AttributeFilter>),
+}
+
+impl AttributeFilter {
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        match self {
+            // NOTE: values of the same type are compared exactly, since INT beyond 2^53 loses precision as f64.
+            AttributeFilter::EQ(field, expected) => match (metadata.get(field), expected) {
+                (Some(value @ MetadataValue::INT(_)), MetadataValue::FLOAT(_)) | (Some(value @ MetadataValue::FLOAT(_)), MetadataValue::INT(_)) => {
+                    value.as_f64() == expected.as_f64()
+                }
+                (Some(value), _) => value == expected,
+                (None, _) => false,
+            },
+            AttributeFilter::RANGE {field, min, max} => match metadata.get(field).and_then(|value| value.as_f64()) {
+                Some(value) => min.is_none_or(|min| min <= value) && max.is_none_or(|max| value <= max),
+                None => false,
+            },
+            AttributeFilter::HASTAG(field, tag) => match metadata.get(field) {
+                Some(MetadataValue::TAGS(tags)) => tags.contains(tag),
+                _ => false,
+            },
+            AttributeFilter::AND(filters) => filters.iter().all(|filter| filter.matches(metadata)),
+            AttributeFilter::OR(filters) => filters.iter().any(|filter| filter.matches(metadata)),
+            AttributeFilter::NOT(filter) => !filter.matches(metadata),
+        }
+    }
+}
+
+pub(crate) fn write_metadata<W: Write>(writer: &mut W, metadata: &Metadata) -> Result<(), NNSearchError> {
+    write_u64(writer, metadata.len() as u64)?;
+    for (field, value) in metadata {
+        write_str(writer, field)?;
+        write_u8(writer, value.to_code())?;
+        match value {
+            MetadataValue::STRING(value) => write_str(writer, value)?,
+            MetadataValue::INT(value) => write_u64(writer, *value as u64)?,
+            MetadataValue::FLOAT(value) => write_u64(writer, value.to_bits())?,
+            MetadataValue::TAGS(tags) => {
+                write_u64(writer, tags.len() as u64)?;
+                for tag in tags {
+                    write_str(writer, tag)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn read_metadata<R: Read>(reader: &mut R) -> Result<Metadata, NNSearchError> {
+    let num_fields = read_u64(reader)? as usize;
+    let mut metadata = Metadata::new();
+    for _ in 0..num_fields {
+        let field = read_str(reader)?;
+        let value = match read_u8(reader)? {
+            0 => MetadataValue::STRING(read_str(reader)?),
+            1 => MetadataValue::INT(read_u64(reader)? as i64),
+            2 => MetadataValue::FLOAT(f64::from_bits(read_u64(reader)?)),
+            3 => {
+                let num_tags = read_u64(reader)? as usize;
+                let mut tags = Vec::with_capacity(num_tags.min(MAX_RESERVED_ITEMS));
+                for _ in 0..num_tags {
+                    tags.push(read_str(reader)?);
+                }
+                MetadataValue::TAGS(tags)
+            }
+            code => return Err(NNSearchError::ValueError(format!("Unknown metadata value code: {}", code))),
+        };
+        metadata.insert(field, value);
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item() -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.insert("tenant".to_string(), MetadataValue::STRING("acme".to_string()));
+        metadata.insert("stock".to_string(), MetadataValue::INT(3));
+        metadata.insert("price".to_string(), MetadataValue::FLOAT(9.5));
+        metadata.insert("tags".to_string(), MetadataValue::TAGS(vec!["red".to_string(), "sale".to_string()]));
+        metadata
+    }
+
+    #[test]
+    fn test_attribute_filter() {
+        let metadata = item();
+        assert!(AttributeFilter::EQ("tenant".to_string(), MetadataValue::STRING("acme".to_string())).matches(&metadata));
+        assert!(!AttributeFilter::EQ("tenant".to_string(), MetadataValue::STRING("other".to_string())).matches(&metadata));
+        assert!(AttributeFilter::EQ("stock".to_string(), MetadataValue::FLOAT(3.0)).matches(&metadata));
+        assert!(!AttributeFilter::EQ("missing".to_string(), MetadataValue::INT(3)).matches(&metadata));
+        let mut large = Metadata::new();
+        large.insert("user_id".to_string(), MetadataValue::INT(9007199254740992));
+        assert!(AttributeFilter::EQ("user_id".to_string(), MetadataValue::INT(9007199254740992)).matches(&large));
+        assert!(!AttributeFilter::EQ("user_id".to_string(), MetadataValue::INT(9007199254740993)).matches(&large));
+        assert!(!AttributeFilter::EQ("user_id".to_string(), MetadataValue::INT(i64::MAX)).matches(&large));
+        assert!(AttributeFilter::EQ("user_id".to_string(), MetadataValue::FLOAT(9007199254740992.0)).matches(&large));
+        assert!(AttributeFilter::RANGE {field: "price".to_string(), min: Some(5.0), max: Some(9.5)}.matches(&metadata));
+        assert!(!AttributeFilter::RANGE {field: "stock".to_string(), min: Some(4.0), max: None}.matches(&metadata));
+        assert!(!AttributeFilter::RANGE {field: "tenant".to_string(), min: None, max: None}.matches(&metadata));
+        assert!(AttributeFilter::HASTAG("tags".to_string(), "sale".to_string()).matches(&metadata));
+        assert!(!AttributeFilter::HASTAG("tags".to_string(), "blue".to_string()).matches(&metadata));
+        let in_stock = AttributeFilter::RANGE {field: "stock".to_string(), min: Some(1.0), max: None};
+        let blue = AttributeFilter::HASTAG("tags".to_string(), "blue".to_string());
+        assert!(AttributeFilter::AND(vec![in_stock.clone(), AttributeFilter::NOT(Box::new(blue.clone()))]).matches(&metadata));
+        assert!(!AttributeFilter::AND(vec![in_stock.clone(), blue.clone()]).matches(&metadata));
+        assert!(AttributeFilter::OR(vec![in_stock, blue]).matches(&metadata));
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let mut buf = vec![];
+        write_metadata(&mut buf, &item()).unwrap();
+        write_metadata(&mut buf, &Metadata::new()).unwrap();
+        let mut reader = buf.as_slice();
+        assert_eq!(read_metadata(&mut reader).unwrap(), item());
+        assert_eq!(read_metadata(&mut reader).unwrap(), Metadata::new());
+        assert!(reader.is_empty());
+    }
+}
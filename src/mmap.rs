@@ -0,0 +1,380 @@
+// Read-only NSW index whose vectors and adjacency lists are accessed through a memory-mapped file.
+// NOTE: the file is read in place, so this module is available only on 64-bit little endian platforms.
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use memmap2::Mmap;
+use rand::Rng;
+use rand::rngs::SmallRng;
+
+use crate::error::NNSearchError;
+use crate::filter::IdFilter;
+use crate::graph::{approx_knn_search, approx_radius_search, NSWGraphView, Neighbor, SearchParams};
+use crate::index::{read_distance, validate_query, validate_radius_query, write_distance, NSWIndex, VectorIndexOperator, MMAP_NSW_INDEX_TAG};
+use crate::io::{parse_index_header, read_u64, verify_checksum, write_f32s, write_index_file, write_u64, INDEX_HEADER_SIZE};
+use crate::linalg::distance::PairwiseDistance;
+use crate::metadata::{Metadata, EMPTY_METADATA};
+
+// distance (at most 5 bytes) + padding so that the following sections are aligned to 8 bytes in the file
+const DISTANCE_FIELD_SIZE: usize = 7;
+// dim, trial, min_degree, num_nodes, num_edges
+const NUM_SIZE_FIELDS: usize = 5;
+
+fn cast_slice<T>(bytes: &[u8]) -> &[T] {
+    // SAFETY: T is only instantiated with plain numeric types (usize or f32) for which any bit pattern is valid.
+    let (head, body, tail) = unsafe { bytes.align_to::<T>() };
+    assert!(head.is_empty() && tail.is_empty(), "misaligned section");
+    body
+}
+
+impl NSWIndex {
+    /// Saves the index in the layout readable by `MmapNSWIndex`.
+    ///
+    /// Nodes are stored in ascending order of ids, and edges refer to the position of nodes.
+    /// Removed nodes and the edges to them are not saved. Metadata is not saved either.
+    /// Quantized vectors are saved as the decoded ones unless the full precision vectors are kept.
+    /// The body consists of the distance, the sizes, the node ids, the offsets of adjacency lists (CSR),
+    /// the adjacency lists and the vectors.
+    pub fn save_mmap(&self, path: &Path) -> Result<(), NNSearchError> {
+        let graph = &self.graph;
+        let mut ids: Vec<usize> = graph.id2node.keys().cloned().filter(|id| !graph.is_removed(*id)).collect();
+        ids.sort_unstable();
+        let id2pos: HashMap<usize, usize> = ids.iter().enumerate().map(|(pos, &id)| (id, pos)).collect();
+        let adjacency_positions: Vec<Vec<usize>> = ids
+            .iter()
+            .map(|id| graph.adjacency_ids(*id).iter().filter_map(|adjacency_id| id2pos.get(adjacency_id).cloned()).collect())
+            .collect();
+
+        let mut body = vec![];
+        write_distance(&mut body, &*graph.distance)?;
+        body.resize(DISTANCE_FIELD_SIZE, 0);
+        let num_edges: usize = adjacency_positions.iter().map(|positions| positions.len()).sum();
+        for size in &[self.dim, graph.trial, graph.min_degree, ids.len(), num_edges] {
+            write_u64(&mut body, *size as u64)?;
+        }
+        for id in &ids {
+            write_u64(&mut body, *id as u64)?;
+        }
+        let mut offset = 0;
+        write_u64(&mut body, offset)?;
+        for positions in &adjacency_positions {
+            offset += positions.len() as u64;
+            write_u64(&mut body, offset)?;
+        }
+        for positions in &adjacency_positions {
+            for pos in positions {
+                write_u64(&mut body, *pos as u64)?;
+            }
+        }
+        for id in &ids {
+            write_f32s(&mut body, &graph.vector(*id).unwrap())?;
+        }
+        write_index_file(path, MMAP_NSW_INDEX_TAG, &body)
+    }
+}
+
+/// Read-only NSW index saved by `NSWIndex::save_mmap`.
+/// Vectors and adjacency lists are not deserialized but read from the memory-mapped file.
+#[derive(Debug)]
+pub struct MmapNSWIndex {
+    mmap: Mmap,
+    dim: usize,
+    trial: usize,
+    min_degree: usize,
+    num_nodes: usize,
+    distance: Box<dyn PairwiseDistance<f32, f32>>,
+    // byte offsets of each section in the file
+    ids_offset: usize,
+    offsets_offset: usize,
+    edges_offset: usize,
+    vectors_offset: usize,
+}
+
+impl MmapNSWIndex {
+    /// Opens the index file, validating only the header, the sizes and the offsets of adjacency lists
+    /// so that the rest of the file is not read.
+    /// Call `verify` as well for a file which may be corrupted.
+    pub fn open(path: &Path) -> Result<Self, NNSearchError> {
+        let file = File::open(path)?;
+        // SAFETY: the index file must not be modified while it is mapped.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let (tag, body) = parse_index_header(&mmap)?;
+        if tag != MMAP_NSW_INDEX_TAG {
+            return Err(NNSearchError::ValueError(format!("Unexpected index tag: {} != {}", tag, MMAP_NSW_INDEX_TAG)))
+        }
+        let mut reader = body;
+        let distance = read_distance(&mut reader)?;
+        let mut reader = body.get(DISTANCE_FIELD_SIZE..).unwrap_or(&[]);
+        let mut sizes = [0usize; NUM_SIZE_FIELDS];
+        for size in sizes.iter_mut() {
+            *size = read_u64(&mut reader)? as usize;
+        }
+        let [dim, trial, min_degree, num_nodes, num_edges] = sizes;
+        // NOTE: the sizes are read from the file, so the layout is computed with overflow checks.
+        let section_end = |start: usize, count: Option<usize>, item_size: usize| {
+            count.and_then(|count| count.checked_mul(item_size)).and_then(|size| start.checked_add(size))
+        };
+        let ids_offset = INDEX_HEADER_SIZE + DISTANCE_FIELD_SIZE + NUM_SIZE_FIELDS * 8;
+        let layout = section_end(ids_offset, Some(num_nodes), 8).and_then(|offsets_offset| {
+            let edges_offset = section_end(offsets_offset, num_nodes.checked_add(1), 8)?;
+            let vectors_offset = section_end(edges_offset, Some(num_edges), 8)?;
+            let end = section_end(vectors_offset, num_nodes.checked_mul(dim), 4)?;
+            Some((offsets_offset, edges_offset, vectors_offset, end))
+        });
+        let (offsets_offset, edges_offset, vectors_offset) = match layout {
+            Some((offsets_offset, edges_offset, vectors_offset, end)) if end == INDEX_HEADER_SIZE + body.len() => {
+                (offsets_offset, edges_offset, vectors_offset)
+            }
+            _ => return Err(NNSearchError::ValueError("Inconsistent size of the index file".to_string())),
+        };
+        let index = MmapNSWIndex {
+            mmap, dim, trial, min_degree, num_nodes, distance,
+            ids_offset, offsets_offset, edges_offset, vectors_offset,
+        };
+        // NOTE: adjacency lists are sliced by the offsets, so they must be non-decreasing from 0 to num_edges.
+        let offsets = index.offsets();
+        if offsets[0] != 0 || offsets[num_nodes] != num_edges || offsets.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(NNSearchError::ValueError("Invalid adjacency lists in the index file".to_string()))
+        }
+        Ok(index)
+    }
+
+    /// Opens the index file and validates the whole file with `verify`.
+    pub fn open_verified(path: &Path) -> Result<Self, NNSearchError> {
+        let index = MmapNSWIndex::open(path)?;
+        index.verify()?;
+        Ok(index)
+    }
+
+    /// Validates the checksum and the adjacency lists, which reads every page of the file.
+    pub fn verify(&self) -> Result<(), NNSearchError> {
+        verify_checksum(&self.mmap)?;
+        if self.edges().iter().any(|&pos| pos >= self.num_nodes) {
+            return Err(NNSearchError::ValueError("Invalid adjacency lists in the index file".to_string()))
+        }
+        Ok(())
+    }
+
+    pub fn min_degree(&self) -> usize {
+        self.min_degree
+    }
+
+    fn ids(&self) -> &[usize] {
+        cast_slice(&self.mmap[self.ids_offset..self.offsets_offset])
+    }
+
+    fn offsets(&self) -> &[usize] {
+        cast_slice(&self.mmap[self.offsets_offset..self.edges_offset])
+    }
+
+    fn edges(&self) -> &[usize] {
+        cast_slice(&self.mmap[self.edges_offset..self.vectors_offset])
+    }
+
+    fn vectors(&self) -> &[f32] {
+        cast_slice(&self.mmap[self.vectors_offset..self.mmap.len() - 4])
+    }
+}
+
+// NOTE: ids of the view are the positions of nodes in the file.
+impl NSWGraphView for MmapNSWIndex {
+    fn trial(&self) -> usize {
+        self.trial
+    }
+    fn distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &*self.distance
+    }
+    fn num_nodes(&self) -> usize {
+        self.num_nodes
+    }
+    fn node_ids(&self) -> Box<dyn Iterator<Item = usize> + '_> {
+        Box::new(0..self.num_nodes)
+    }
+    fn random_entry(&self, rng: &mut SmallRng) -> usize {
+        rng.gen_range(0..self.num_nodes)
+    }
+    fn node_vec(&self, id: usize) -> &[f32] {
+        &self.vectors()[id * self.dim..(id + 1) * self.dim]
+    }
+    fn adjacency_ids(&self, id: usize) -> Cow<'_, [usize]> {
+        let offsets = self.offsets();
+        Cow::Borrowed(&self.edges()[offsets[id]..offsets[id + 1]])
+    }
+}
+
+impl VectorIndexOperator for MmapNSWIndex {
+    fn add_with_metadata(&mut self, _data: Vec<f32>, _metadata: Metadata) -> Result<usize, NNSearchError> {
+        Err(NNSearchError::ReadOnly)
+    }
+    fn remove(&mut self, _id: usize) -> Result<(), NNSearchError> {
+        Err(NNSearchError::ReadOnly)
+    }
+    fn search(&self, query: Vec<f32>, k: usize) -> Result<Vec<Neighbor>, NNSearchError> {
+        self.search_with_params(query, k, &SearchParams::default())
+    }
+    fn search_with_params(&self, query: Vec<f32>, k: usize, params: &SearchParams) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        let ids = self.ids();
+        let knn = approx_knn_search(self, &query, k, None, params)?;
+        Ok(knn.into_iter().map(|nn| Neighbor {id: ids[nn.id], ..nn}).collect())
+    }
+    fn search_filtered(&self, query: Vec<f32>, k: usize, filter: &dyn IdFilter) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_query(self, &query, k)?;
+        let ids = self.ids();
+        let knn = approx_knn_search(self, &query, k, Some(&|pos: usize| filter.allows(ids[pos])), &SearchParams::default())?;
+        Ok(knn.into_iter().map(|nn| Neighbor {id: ids[nn.id], ..nn}).collect())
+    }
+    fn search_radius(&self, query: Vec<f32>, radius: f32) -> Result<Vec<Neighbor>, NNSearchError> {
+        validate_radius_query(self, &query, radius)?;
+        if self.num_nodes == 0 {
+            return Ok(vec![])
+        }
+        let ids = self.ids();
+        let result = approx_radius_search(self, &query, radius, |id| self.cost(&query, id))?;
+        Ok(result.into_iter().map(|nn| Neighbor {id: ids[nn.id], ..nn}).collect())
+    }
+    fn get_vector(&self, id: usize) -> Option<&[f32]> {
+        self.ids().binary_search(&id).ok().map(|pos| self.node_vec(pos))
+    }
+    fn get_metadata(&self, id: usize) -> Option<&Metadata> {
+        self.get_vector(id).map(|_| &EMPTY_METADATA)
+    }
+    fn get_distance(&self) -> &dyn PairwiseDistance<f32, f32> {
+        &*self.distance
+    }
+    fn dim(&self) -> usize {
+        self.dim
+    }
+    fn len(&self) -> usize {
+        self.num_nodes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::load_index;
+    use crate::linalg::distance::Euclidean;
+    use crate::linalg::utils::generate_matrix;
+
+    #[test]
+    fn test_mmap_nsw_index() {
+        let path = std::env::temp_dir().join("nnsearch_test_mmap_nsw_index.bin");
+        let mat = generate_matrix(50, 3);
+        let mut index = NSWIndex::new(3, Box::new(Euclidean{}), 3, 4);
+        index.add_batch(mat.clone()).unwrap();
+        index.save_mmap(&path).unwrap();
+
+        let mmap_index = MmapNSWIndex::open(&path).unwrap();
+        assert_eq!(mmap_index.len(), 50);
+        assert_eq!(mmap_index.dim(), 3);
+        for (id, vec) in mat.iter().enumerate() {
+            assert_eq!(mmap_index.get_vector(id), Some(vec.as_slice()));
+            let mut expected = index.graph.adjacency_ids(id).to_vec();
+            let mut actual = mmap_index.adjacency_ids(id).iter().map(|&pos| mmap_index.ids()[pos]).collect::<Vec<_>>();
+            expected.sort_unstable();
+            actual.sort_unstable();
+            assert_eq!(actual, expected);
+        }
+        assert_eq!(mmap_index.get_vector(50), None);
+        let mut mmap_index = mmap_index;
+        assert_eq!(mmap_index.add(vec![0.1, 0.2, 0.3]).unwrap_err(), NNSearchError::ReadOnly);
+        assert_eq!(mmap_index.remove(7).unwrap_err(), NNSearchError::ReadOnly);
+        assert_eq!(mmap_index.search(mat[7].clone(), 3).unwrap()[0].id, 7);
+        assert!(mmap_index.search_filtered(mat[7].clone(), 3, &|id| id != 7).unwrap().iter().all(|nn| nn.id != 7));
+        assert_eq!(mmap_index.search_radius(mat[7].clone(), 0.0).unwrap(), vec![Neighbor {id: 7, distance: 0.0}]);
+
+        let loaded = load_index(&path).unwrap();
+        assert_eq!(loaded.search(mat[7].clone(), 3).unwrap()[0].id, 7);
+        assert!(NSWIndex::load(&path).is_err());
+
+        // removed nodes are not saved
+        index.remove(7).unwrap();
+        index.save_mmap(&path).unwrap();
+        let mmap_index = MmapNSWIndex::open(&path).unwrap();
+        assert_eq!(mmap_index.len(), 49);
+        assert_eq!(mmap_index.get_vector(7), None);
+        assert!(mmap_index.search(mat[7].clone(), 5).unwrap().iter().all(|nn| nn.id != 7));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_corrupt_offsets() {
+        let path = std::env::temp_dir().join("nnsearch_test_mmap_corrupt_offsets.bin");
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 4);
+        index.add_batch(generate_matrix(10, 2)).unwrap();
+        index.save_mmap(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let offsets_offset = INDEX_HEADER_SIZE + DISTANCE_FIELD_SIZE + NUM_SIZE_FIELDS * 8 + 10 * 8;
+        let num_edges = read_u64(&mut &bytes[offsets_offset + 80..]).unwrap() as usize;
+        for (pos, offset) in [(0, 1), (5, 0), (5, num_edges + 1)].iter() {
+            let mut corrupted = bytes.clone();
+            corrupted[offsets_offset + pos * 8..offsets_offset + (pos + 1) * 8].copy_from_slice(&offset.to_le_bytes());
+            // keep the checksum valid so that only the offsets are invalid
+            let len = corrupted.len();
+            let checksum = crc32fast::hash(&corrupted[..len - 4]);
+            corrupted[len - 4..].copy_from_slice(&checksum.to_le_bytes());
+            std::fs::write(&path, &corrupted).unwrap();
+            assert_eq!(
+                MmapNSWIndex::open(&path).unwrap_err(),
+                NNSearchError::ValueError("Invalid adjacency lists in the index file".to_string()));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_verify() {
+        let path = std::env::temp_dir().join("nnsearch_test_mmap_verify.bin");
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 4);
+        index.add_batch(generate_matrix(10, 2)).unwrap();
+        index.save_mmap(&path).unwrap();
+        assert!(MmapNSWIndex::open_verified(&path).is_ok());
+        let bytes = std::fs::read(&path).unwrap();
+        let edges_offset = INDEX_HEADER_SIZE + DISTANCE_FIELD_SIZE + NUM_SIZE_FIELDS * 8 + 10 * 8 + 11 * 8;
+
+        // an edge to a missing node with the valid checksum
+        let mut corrupted = bytes.clone();
+        corrupted[edges_offset..edges_offset + 8].copy_from_slice(&10usize.to_le_bytes());
+        let len = corrupted.len();
+        let checksum = crc32fast::hash(&corrupted[..len - 4]);
+        corrupted[len - 4..].copy_from_slice(&checksum.to_le_bytes());
+        std::fs::write(&path, &corrupted).unwrap();
+        let mmap_index = MmapNSWIndex::open(&path).unwrap();
+        assert_eq!(mmap_index.verify().unwrap_err(), NNSearchError::ValueError("Invalid adjacency lists in the index file".to_string()));
+
+        // a modified vector is detected only by the checksum
+        let mut corrupted = bytes.clone();
+        let len = corrupted.len();
+        corrupted[len - 5] ^= 0xff;
+        std::fs::write(&path, &corrupted).unwrap();
+        let mmap_index = MmapNSWIndex::open(&path).unwrap();
+        assert_eq!(mmap_index.verify().unwrap_err(), NNSearchError::ValueError("Checksum mismatch".to_string()));
+        assert_eq!(MmapNSWIndex::open_verified(&path).unwrap_err(), NNSearchError::ValueError("Checksum mismatch".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_open_overflowing_sizes() {
+        let path = std::env::temp_dir().join("nnsearch_test_mmap_overflowing_sizes.bin");
+        let mut index = NSWIndex::new(2, Box::new(Euclidean{}), 3, 4);
+        index.add_batch(generate_matrix(10, 2)).unwrap();
+        index.save_mmap(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let sizes_offset = INDEX_HEADER_SIZE + DISTANCE_FIELD_SIZE;
+        // dim, num_nodes and num_edges whose products wrap around
+        for (field, size) in [(0, 1usize << 62), (3, usize::MAX), (3, 1usize << 61), (4, 1usize << 61)].iter() {
+            let mut corrupted = bytes.clone();
+            corrupted[sizes_offset + field * 8..sizes_offset + (field + 1) * 8].copy_from_slice(&size.to_le_bytes());
+            let len = corrupted.len();
+            let checksum = crc32fast::hash(&corrupted[..len - 4]);
+            corrupted[len - 4..].copy_from_slice(&checksum.to_le_bytes());
+            std::fs::write(&path, &corrupted).unwrap();
+            assert_eq!(
+                MmapNSWIndex::open(&path).unwrap_err(),
+                NNSearchError::ValueError("Inconsistent size of the index file".to_string()));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
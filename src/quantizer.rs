@@ -0,0 +1,591 @@
+// Quantizers which compress vectors into compact codes.
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use half::f16;
+use rand::rngs::SmallRng;
+
+use crate::error::NNSearchError;
+use crate::index::validate_dim;
+use crate::io::{read_f32s, read_u64, read_u8, write_f32s, write_u64, write_u8};
+use crate::linalg::distance::{DistanceType, Euclidean, PairwiseDistance};
+use crate::linalg::kmeans::{kmeans, nearest_centroid};
+
+const NUM_KMEANS_ITERS: usize = 25;
+
+/// Product quantizer which splits a vector into `num_subspaces` subvectors
+/// and encodes each subvector into the id of its nearest centroid in the subspace.
+/// A vector is encoded into `num_subspaces` bytes, so at most 256 centroids are trained in each subspace.
+#[derive(Debug, Clone)]
+pub struct ProductQuantizer {
+    dim: usize,
+    num_subspaces: usize,
+    num_centroids: usize,
+    // centroids of each subspace
+    codebooks: Vec<Vec<Vec<f32>>>,
+}
+
+impl ProductQuantizer {
+    pub fn new(dim: usize, num_subspaces: usize, num_centroids: usize) -> Self {
+        ProductQuantizer {
+            dim,
+            num_subspaces,
+            num_centroids,
+            codebooks: vec![],
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// Returns the number of bytes of a code.
+    pub fn code_size(&self) -> usize {
+        self.num_subspaces
+    }
+
+    pub fn num_centroids(&self) -> usize {
+        self.num_centroids
+    }
+
+    pub fn is_trained(&self) -> bool {
+        !self.codebooks.is_empty()
+    }
+
+    fn sub_dim(&self) -> usize {
+        self.dim / self.num_subspaces
+    }
+
+    /// Trains the centroids of each subspace by k-means on `data`.
+    /// Fails with `ValueError` if `dim` is not divisible by `num_subspaces`, `num_centroids` is not in 1..=256
+    /// or `data` has less than `num_centroids` vectors.
+    pub fn train(&mut self, data: &[Vec<f32>], rng: &mut SmallRng) -> Result<(), NNSearchError> {
+        if self.num_subspaces == 0 || !self.dim.is_multiple_of(self.num_subspaces) {
+            return Err(NNSearchError::ValueError(format!("Dimension {} is not divisible by the number of subspaces {}", self.dim, self.num_subspaces)))
+        }
+        if !(1..=256).contains(&self.num_centroids) {
+            return Err(NNSearchError::ValueError(format!("Number of centroids must be in 1..=256: {}", self.num_centroids)))
+        }
+        for vec in data {
+            validate_dim(self.dim, vec)?;
+        }
+        let sub_dim = self.sub_dim();
+        self.codebooks = (0..self.num_subspaces)
+            .map(|s| {
+                let subvecs: Vec<Vec<f32>> = data.iter().map(|vec| vec[s * sub_dim..(s + 1) * sub_dim].to_vec()).collect();
+                kmeans(&subvecs, self.num_centroids, NUM_KMEANS_ITERS, &Euclidean{}, rng)
+            })
+            .collect::<Result<Vec<_>, NNSearchError>>()?;
+        Ok(())
+    }
+
+    /// Encodes `vec` into the ids of the nearest centroids of its subvectors.
+    pub fn encode(&self, vec: &[f32]) -> Result<Vec<u8>, NNSearchError> {
+        if !self.is_trained() {
+            return Err(NNSearchError::NotTrained)
+        }
+        validate_dim(self.dim, vec)?;
+        self.codebooks
+            .iter()
+            .zip(vec.chunks(self.sub_dim()))
+            .map(|(codebook, subvec)| Ok(nearest_centroid(codebook, subvec, &Euclidean{})?.0 as u8))
+            .collect()
+    }
+
+    /// Reconstructs the vector from `code` by concatenating the centroids.
+    pub fn decode(&self, code: &[u8]) -> Vec<f32> {
+        self.codebooks
+            .iter()
+            .zip(code)
+            .flat_map(|(codebook, &c)| codebook[c as usize].iter().cloned())
+            .collect()
+    }
+
+    /// Computes the distances from the subvectors of `query` to the centroids of each subspace,
+    /// with which the distances to encoded vectors are approximated without decoding them.
+    pub fn distance_table(&self, query: &[f32]) -> Result<DistanceTable, NNSearchError> {
+        if !self.is_trained() {
+            return Err(NNSearchError::NotTrained)
+        }
+        validate_dim(self.dim, query)?;
+        let mut table = Vec::with_capacity(self.num_subspaces * self.num_centroids);
+        for (codebook, subvec) in self.codebooks.iter().zip(query.chunks(self.sub_dim())) {
+            for centroid in codebook {
+                table.push(subvec.iter().zip(centroid).map(|(x, y)| (x - y) * (x - y)).sum::<f32>());
+            }
+        }
+        Ok(DistanceTable {num_centroids: self.num_centroids, table})
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), NNSearchError> {
+        for size in &[self.dim, self.num_subspaces, self.num_centroids, self.is_trained() as usize] {
+            write_u64(writer, *size as u64)?;
+        }
+        for centroid in self.codebooks.iter().flatten() {
+            write_f32s(writer, centroid)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Self, NNSearchError> {
+        let dim = read_u64(reader)? as usize;
+        let num_subspaces = read_u64(reader)? as usize;
+        let num_centroids = read_u64(reader)? as usize;
+        let mut quantizer = ProductQuantizer::new(dim, num_subspaces, num_centroids);
+        if read_u64(reader)? != 0 {
+            if num_subspaces == 0 || !dim.is_multiple_of(num_subspaces) || num_centroids > 256 {
+                return Err(NNSearchError::ValueError("Invalid product quantizer".to_string()))
+            }
+            let sub_dim = quantizer.sub_dim();
+            for _ in 0..num_subspaces {
+                let codebook = (0..num_centroids).map(|_| read_f32s(reader, sub_dim)).collect::<Result<Vec<_>, NNSearchError>>()?;
+                quantizer.codebooks.push(codebook);
+            }
+        }
+        Ok(quantizer)
+    }
+}
+
+/// Squared Euclidean distances from the subvectors of a query to the centroids of each subspace.
+#[derive(Debug, Clone)]
+pub struct DistanceTable {
+    num_centroids: usize,
+    table: Vec<f32>,
+}
+
+impl DistanceTable {
+    /// Returns the approximate Euclidean distance from the query to the vector encoded into `code`.
+    pub fn distance(&self, code: &[u8]) -> f32 {
+        code.iter()
+            .enumerate()
+            .map(|(s, &c)| self.table[s * self.num_centroids + c as usize])
+            .sum::<f32>()
+            .sqrt()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarQuantizerType {
+    /// 8-bit integer scaled by the minimum and the maximum of each dimension
+    INT8,
+    /// IEEE 754 half-precision float
+    FP16,
+}
+
+impl ScalarQuantizerType {
+    fn to_code(self) -> u8 {
+        match self {
+            ScalarQuantizerType::INT8 => 0,
+            ScalarQuantizerType::FP16 => 1,
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Self, NNSearchError> {
+        match code {
+            0 => Ok(ScalarQuantizerType::INT8),
+            1 => Ok(ScalarQuantizerType::FP16),
+            _ => Err(NNSearchError::ValueError(format!("Unknown quantizer code: {}", code))),
+        }
+    }
+}
+
+impl FromStr for ScalarQuantizerType {
+    type Err = NNSearchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "int8" => Ok(ScalarQuantizerType::INT8),
+            "fp16" | "f16" | "half" => Ok(ScalarQuantizerType::FP16),
+            _ => Err(NNSearchError::ValueError(format!("Unknown quantizer: {}", s))),
+        }
+    }
+}
+
+/// Quantizer which encodes each dimension of a vector into 1 byte (`INT8`) or 2 bytes (`FP16`).
+/// `INT8` maps the range between the minimum and the maximum of each dimension in the training vectors to 0..=255,
+/// and clamps values out of the range. `FP16` rejects values which overflow to infinity in half precision, i.e. |x| > 65504.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScalarQuantizer {
+    quantizer_type: ScalarQuantizerType,
+    dim: usize,
+    // minimum and step between the quantized values of each dimension for INT8
+    mins: Vec<f32>,
+    steps: Vec<f32>,
+}
+
+impl ScalarQuantizer {
+    pub fn new(quantizer_type: ScalarQuantizerType, dim: usize) -> Self {
+        ScalarQuantizer {
+            quantizer_type,
+            dim,
+            mins: vec![],
+            steps: vec![],
+        }
+    }
+
+    pub fn quantizer_type(&self) -> ScalarQuantizerType {
+        self.quantizer_type
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    /// Returns the number of bytes of a code.
+    pub fn code_size(&self) -> usize {
+        match self.quantizer_type {
+            ScalarQuantizerType::INT8 => self.dim,
+            ScalarQuantizerType::FP16 => self.dim * 2,
+        }
+    }
+
+    /// `FP16` needs no training.
+    pub fn is_trained(&self) -> bool {
+        self.quantizer_type == ScalarQuantizerType::FP16 || !self.mins.is_empty()
+    }
+
+    /// Trains the range of each dimension for `INT8`, failing with `ValueError` if `data` is empty.
+    pub fn train(&mut self, data: &[Vec<f32>]) -> Result<(), NNSearchError> {
+        for vec in data {
+            validate_dim(self.dim, vec)?;
+        }
+        if self.quantizer_type == ScalarQuantizerType::FP16 {
+            return Ok(())
+        }
+        if data.is_empty() {
+            return Err(NNSearchError::ValueError("No vectors to train the quantizer".to_string()))
+        }
+        let mut mins = vec![f32::INFINITY; self.dim];
+        let mut maxs = vec![f32::NEG_INFINITY; self.dim];
+        for vec in data {
+            for (i, &x) in vec.iter().enumerate() {
+                mins[i] = mins[i].min(x);
+                maxs[i] = maxs[i].max(x);
+            }
+        }
+        self.steps = mins.iter().zip(&maxs).map(|(min, max)| (max - min) / 255.0).collect();
+        self.mins = mins;
+        Ok(())
+    }
+
+    /// Checks that `vec` can be encoded, failing with `ValueError` if a value is out of the range of `FP16`.
+    pub fn validate(&self, vec: &[f32]) -> Result<(), NNSearchError> {
+        validate_dim(self.dim, vec)?;
+        if self.quantizer_type == ScalarQuantizerType::FP16 {
+            if let Some(x) = vec.iter().find(|&&x| f16::from_f32(x).is_infinite()) {
+                return Err(NNSearchError::ValueError(format!("Value out of the range of FP16: {}", x)))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encode(&self, vec: &[f32]) -> Result<Vec<u8>, NNSearchError> {
+        if !self.is_trained() {
+            return Err(NNSearchError::NotTrained)
+        }
+        self.validate(vec)?;
+        Ok(match self.quantizer_type {
+            ScalarQuantizerType::INT8 => vec
+                .iter()
+                .enumerate()
+                .map(|(i, &x)| if self.steps[i] > 0.0 { ((x - self.mins[i]) / self.steps[i]).round().clamp(0.0, 255.0) as u8 } else { 0 })
+                .collect(),
+            ScalarQuantizerType::FP16 => vec.iter().flat_map(|&x| f16::from_f32(x).to_le_bytes()).collect(),
+        })
+    }
+
+    pub fn decode(&self, code: &[u8]) -> Vec<f32> {
+        self.values(code).collect()
+    }
+
+    /// Returns the decoded values of `code` lazily.
+    fn values<'a>(&'a self, code: &'a [u8]) -> impl Iterator<Item = f32> + 'a {
+        (0..self.dim).map(move |i| match self.quantizer_type {
+            ScalarQuantizerType::INT8 => self.mins[i] + self.steps[i] * code[i] as f32,
+            ScalarQuantizerType::FP16 => f16::from_le_bytes([code[2 * i], code[2 * i + 1]]).to_f32(),
+        })
+    }
+
+    /// Computes `distance` between `query` and the vector encoded into `code` without decoding it into a vector.
+    /// Distances other than Euclidean, cosine, inner product, Manhattan and Chebyshev are computed on the decoded vector.
+    pub fn compute(&self, distance: &dyn PairwiseDistance<f32, f32>, query: &[f32], code: &[u8]) -> Result<f32, NNSearchError> {
+        validate_dim(self.dim, query)?;
+        let pairs = query.iter().zip(self.values(code));
+        Ok(match distance.distance_type() {
+            Some(DistanceType::EUCLIDEAN) => pairs.map(|(q, x)| (q - x) * (q - x)).sum::<f32>().sqrt(),
+            Some(DistanceType::MANHATTAN) => pairs.map(|(q, x)| (q - x).abs()).sum(),
+            Some(DistanceType::CHEBYSHEV) => pairs.fold(0.0, |val: f32, (q, x)| val.max((q - x).abs())),
+            Some(DistanceType::INNERPRODUCT) => -pairs.map(|(q, x)| q * x).sum::<f32>(),
+            Some(DistanceType::COSINE) => {
+                let (dot, qq, xx) = pairs.fold((0.0, 0.0, 0.0), |(dot, qq, xx), (q, x)| (dot + q * x, qq + q * q, xx + x * x));
+                let norm = (qq * xx).sqrt();
+                // NOTE: similarity is 0 if either of the vectors is zero as in `Cosine`.
+                if norm == 0.0 { 1.0 } else { 1.0 - (dot / norm).clamp(-1.0, 1.0) }
+            }
+            _ => distance.compute(query, &self.decode(code))?,
+        })
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), NNSearchError> {
+        write_u8(writer, self.quantizer_type.to_code())?;
+        write_u64(writer, self.dim as u64)?;
+        write_u8(writer, !self.mins.is_empty() as u8)?;
+        if !self.mins.is_empty() {
+            write_f32s(writer, &self.mins)?;
+            write_f32s(writer, &self.steps)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Self, NNSearchError> {
+        let quantizer_type = ScalarQuantizerType::from_code(read_u8(reader)?)?;
+        let dim = read_u64(reader)? as usize;
+        let mut quantizer = ScalarQuantizer::new(quantizer_type, dim);
+        if read_u8(reader)? != 0 {
+            quantizer.mins = read_f32s(reader, dim)?;
+            quantizer.steps = read_f32s(reader, dim)?;
+        }
+        Ok(quantizer)
+    }
+}
+
+/// Codes of the vectors encoded by a scalar quantizer, which are addressed by ids.
+/// Indexes keep the full precision vectors along with the codes only if `rerank` is positive.
+///
+/// The codes are stored in slots of `code_size` bytes in one buffer, and the slots of removed codes are reused.
+#[derive(Debug, Clone)]
+pub struct ScalarQuantizedVectors {
+    pub quantizer: ScalarQuantizer,
+    /// Number of the candidates found with the codes which are reranked by the full precision vectors.
+    pub rerank: usize,
+    codes: Vec<u8>,
+    id2slot: HashMap<usize, usize>,
+    free_slots: Vec<usize>,
+}
+
+impl ScalarQuantizedVectors {
+    pub fn new(quantizer_type: ScalarQuantizerType, dim: usize, rerank: usize) -> Self {
+        ScalarQuantizedVectors {
+            quantizer: ScalarQuantizer::new(quantizer_type, dim),
+            rerank,
+            codes: vec![],
+            id2slot: HashMap::new(),
+            free_slots: vec![],
+        }
+    }
+
+    pub fn keeps_vectors(&self) -> bool {
+        self.rerank > 0
+    }
+
+    /// Encodes `vec` and stores the code with `id`, replacing the existing one.
+    pub fn insert(&mut self, id: usize, vec: &[f32]) -> Result<(), NNSearchError> {
+        let code = self.quantizer.encode(vec)?;
+        self.store(id, &code);
+        Ok(())
+    }
+
+    fn store(&mut self, id: usize, code: &[u8]) {
+        let code_size = code.len();
+        let slot = match self.id2slot.get(&id) {
+            Some(&slot) => slot,
+            None => {
+                // NOTE: every slot is occupied if none is free, so a new slot is appended.
+                let slot = self.free_slots.pop().unwrap_or_else(|| {
+                    let slot = self.id2slot.len();
+                    self.codes.resize((slot + 1) * code_size, 0);
+                    slot
+                });
+                self.id2slot.insert(id, slot);
+                slot
+            }
+        };
+        self.codes[slot * code_size..(slot + 1) * code_size].copy_from_slice(code);
+    }
+
+    pub fn remove(&mut self, id: usize) {
+        if let Some(slot) = self.id2slot.remove(&id) {
+            self.free_slots.push(slot);
+        }
+    }
+
+    pub fn retain<F: FnMut(usize) -> bool>(&mut self, mut f: F) {
+        let free_slots = &mut self.free_slots;
+        self.id2slot.retain(|&id, &mut slot| {
+            let keeps = f(id);
+            if !keeps {
+                free_slots.push(slot);
+            }
+            keeps
+        });
+    }
+
+    pub fn code(&self, id: usize) -> Option<&[u8]> {
+        let code_size = self.quantizer.code_size();
+        self.id2slot.get(&id).map(|&slot| &self.codes[slot * code_size..(slot + 1) * code_size])
+    }
+
+    pub fn decode(&self, id: usize) -> Option<Vec<f32>> {
+        self.code(id).map(|code| self.quantizer.decode(code))
+    }
+
+    /// Computes `distance` between `query` and the vector of `id` on its code, failing with `NotFound` if the code does not exist.
+    pub fn compute(&self, distance: &dyn PairwiseDistance<f32, f32>, query: &[f32], id: usize) -> Result<f32, NNSearchError> {
+        match self.code(id) {
+            Some(code) => self.quantizer.compute(distance, query, code),
+            None => Err(NNSearchError::NotFound(id)),
+        }
+    }
+
+    /// Writes the quantizer and `rerank` but not the codes, which are written by the index along with each item.
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), NNSearchError> {
+        self.quantizer.write_to(writer)?;
+        write_u64(writer, self.rerank as u64)
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Self, NNSearchError> {
+        let quantizer = ScalarQuantizer::read_from(reader)?;
+        let rerank = read_u64(reader)? as usize;
+        Ok(ScalarQuantizedVectors {quantizer, rerank, codes: vec![], id2slot: HashMap::new(), free_slots: vec![]})
+    }
+
+    /// Stores `code` read from a file as it is.
+    pub(crate) fn insert_code(&mut self, id: usize, code: &[u8]) {
+        self.store(id, code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::linalg::distance::PairwiseDistance;
+    use crate::linalg::distance::{Angular, Chebyshev, Cosine, Euclidean, InnerProduct, Manhattan, Minkowski};
+    use crate::linalg::utils::{generate_matrix, get_rng};
+
+    #[test]
+    fn test_product_quantizer() {
+        let mat = generate_matrix(500, 8);
+        let mut pq = ProductQuantizer::new(8, 4, 16);
+        assert_eq!(pq.encode(&mat[0]).unwrap_err(), NNSearchError::NotTrained);
+        pq.train(&mat, &mut get_rng(46)).unwrap();
+        assert_eq!(pq.code_size(), 4);
+
+        let table = pq.distance_table(&mat[0]).unwrap();
+        let mut total_error = 0.0;
+        for vec in &mat[..50] {
+            let code = pq.encode(vec).unwrap();
+            let decoded = pq.decode(&code);
+            let error = Euclidean{}.compute(vec, &decoded).unwrap();
+            total_error += error;
+            // the asymmetric distance equals the distance to the reconstructed vector
+            let expected = Euclidean{}.compute(&mat[0], &decoded).unwrap();
+            assert!((table.distance(&code) - expected).abs() < 1e-4);
+        }
+        // the reconstructed vectors are close to the original ones in [0, 1)^8
+        assert!(total_error / 50.0 < 0.5);
+        assert_eq!(pq.encode(&[0.1]).unwrap_err(), NNSearchError::DimensionMismatch {expected: 8, actual: 1});
+
+        let mut buf = vec![];
+        pq.write_to(&mut buf).unwrap();
+        let loaded = ProductQuantizer::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(loaded.encode(&mat[3]).unwrap(), pq.encode(&mat[3]).unwrap());
+    }
+
+    #[test]
+    fn test_product_quantizer_invalid_parameters() {
+        let mat = generate_matrix(100, 6);
+        assert!(ProductQuantizer::new(6, 4, 16).train(&mat, &mut get_rng(46)).is_err());
+        assert!(ProductQuantizer::new(6, 0, 16).train(&mat, &mut get_rng(46)).is_err());
+        assert!(ProductQuantizer::new(6, 3, 257).train(&mat, &mut get_rng(46)).is_err());
+        assert!(ProductQuantizer::new(6, 3, 101).train(&mat, &mut get_rng(46)).is_err());
+    }
+
+    #[test]
+    fn test_scalar_quantizer() {
+        let data = generate_matrix(100, 8);
+        let mut quantizer = ScalarQuantizer::new(ScalarQuantizerType::INT8, 8);
+        assert!(!quantizer.is_trained());
+        assert!(matches!(quantizer.encode(&data[0]), Err(NNSearchError::NotTrained)));
+        assert!(quantizer.train(&[]).is_err());
+        quantizer.train(&data).unwrap();
+        assert_eq!(quantizer.code_size(), 8);
+        for vec in &data {
+            let decoded = quantizer.decode(&quantizer.encode(vec).unwrap());
+            for (i, (x, y)) in vec.iter().zip(&decoded).enumerate() {
+                assert!((x - y).abs() <= quantizer.steps[i] / 2.0 + 1e-6);
+            }
+        }
+        // values out of the trained range are clamped
+        assert_eq!(quantizer.encode(&[10.0; 8]).unwrap(), vec![255; 8]);
+        assert!(quantizer.encode(&[0.0; 3]).is_err());
+
+        let quantizer = ScalarQuantizer::new(ScalarQuantizerType::FP16, 8);
+        assert!(quantizer.is_trained());
+        assert_eq!(quantizer.code_size(), 16);
+        let decoded = quantizer.decode(&quantizer.encode(&data[0]).unwrap());
+        assert!(data[0].iter().zip(&decoded).all(|(x, y)| (x - y).abs() < 1e-3));
+        // values which overflow in half precision are rejected instead of being encoded into infinity
+        assert_eq!(quantizer.decode(&quantizer.encode(&[65504.0, -65504.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]).unwrap())[..2], [65504.0, -65504.0]);
+        assert_eq!(
+            quantizer.encode(&[0.0, 0.0, 1e5, 0.0, 0.0, 0.0, 0.0, 0.0]).unwrap_err(),
+            NNSearchError::ValueError("Value out of the range of FP16: 100000".to_string()));
+        assert!(quantizer.validate(&[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, -70000.0]).is_err());
+
+        assert_eq!("int8".parse::<ScalarQuantizerType>().unwrap(), ScalarQuantizerType::INT8);
+        assert_eq!("F16".parse::<ScalarQuantizerType>().unwrap(), ScalarQuantizerType::FP16);
+        assert!("int4".parse::<ScalarQuantizerType>().is_err());
+    }
+
+    #[test]
+    fn test_scalar_quantizer_kernels() {
+        let data = generate_matrix(50, 8);
+        let distances: Vec<Box<dyn PairwiseDistance<f32, f32>>> = vec![
+            Box::new(Euclidean{}), Box::new(Cosine{}), Box::new(InnerProduct{}), Box::new(Manhattan{}),
+            Box::new(Chebyshev{}), Box::new(Angular{}), Box::new(Minkowski{p: 3.0}),
+        ];
+        for quantizer_type in [ScalarQuantizerType::INT8, ScalarQuantizerType::FP16] {
+            let mut quantizer = ScalarQuantizer::new(quantizer_type, 8);
+            quantizer.train(&data).unwrap();
+            for distance in &distances {
+                for vec in &data[..10] {
+                    let code = quantizer.encode(vec).unwrap();
+                    let expected = distance.compute(&data[10], &quantizer.decode(&code)).unwrap();
+                    assert!((quantizer.compute(&**distance, &data[10], &code).unwrap() - expected).abs() < 1e-4);
+                }
+            }
+        }
+
+        let mut vectors = ScalarQuantizedVectors::new(ScalarQuantizerType::INT8, 8, 0);
+        assert!(matches!(vectors.insert(0, &data[0]), Err(NNSearchError::NotTrained)));
+        vectors.quantizer.train(&data).unwrap();
+        vectors.insert(3, &data[3]).unwrap();
+        assert!(!vectors.keeps_vectors());
+        assert!(vectors.compute(&Euclidean{}, &data[3], 3).unwrap() < 0.01);
+        assert!(matches!(vectors.compute(&Euclidean{}, &data[3], 0), Err(NNSearchError::NotFound(0))));
+        vectors.remove(3);
+        assert_eq!(vectors.code(3), None);
+
+        // the codes share one buffer whose slots are reused after removal
+        for (id, vec) in data.iter().enumerate().take(10) {
+            vectors.insert(id, vec).unwrap();
+        }
+        assert_eq!(vectors.codes.len(), 10 * 8);
+        vectors.retain(|id| id % 2 == 0);
+        vectors.insert(3, &data[9]).unwrap();
+        vectors.insert(10, &data[8]).unwrap();
+        vectors.insert(0, &data[7]).unwrap();
+        assert_eq!(vectors.codes.len(), 10 * 8);
+        assert_eq!(vectors.code(3).unwrap(), vectors.quantizer.encode(&data[9]).unwrap().as_slice());
+        assert_eq!(vectors.code(10).unwrap(), vectors.quantizer.encode(&data[8]).unwrap().as_slice());
+        assert_eq!(vectors.code(0).unwrap(), vectors.quantizer.encode(&data[7]).unwrap().as_slice());
+        assert_eq!(vectors.code(2).unwrap(), vectors.quantizer.encode(&data[2]).unwrap().as_slice());
+        assert_eq!(vectors.code(1), None);
+
+        let mut bytes = vec![];
+        vectors.write_to(&mut bytes).unwrap();
+        let loaded = ScalarQuantizedVectors::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(loaded.quantizer, vectors.quantizer);
+        assert_eq!(loaded.rerank, 0);
+    }
+}
@@ -0,0 +1,124 @@
+// Round trips of the `index` and `search` subcommands through the built binary.
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Directory in the temporary directory which is unique to a test and removed on drop.
+struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("nnsearch_cli_{}_{}", std::process::id(), name));
+        // NOTE: a directory left by a crashed run with the same pid is replaced.
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir {path}
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn nnsearch(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nnsearch-rs")).args(args).output().unwrap()
+}
+
+fn stdout_lines(output: &Output) -> Vec<String> {
+    assert!(output.status.success(), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout.clone()).unwrap().lines().map(|line| line.to_string()).collect()
+}
+
+#[test]
+fn test_index_and_search() {
+    let dir = TempDir::new("index_and_search");
+    let input = dir.path("input.txt");
+    let query = dir.path("query.txt");
+    let output = dir.path("index.bin");
+    fs::write(&input, "0.0 0.0\n1.0 0.0\n0.0,1.0\n\n5.0 5.0\n").unwrap();
+    fs::write(&query, "0.9 0.1\n4.0 4.0\n").unwrap();
+    let extra_args: &[(&str, &[&str])] = &[
+        ("naive", &[]),
+        ("naive", &["--quantizer", "fp16"]),
+        ("nsw", &[]),
+        ("ivf", &["--nlist", "2", "--nprobe", "2"]),
+        ("lsh", &["--tables", "4", "--bits", "2", "--nprobe", "2"]),
+    ];
+    for (index_type, extra) in extra_args {
+        let mut args = vec!["index", input.to_str().unwrap(), output.to_str().unwrap(), "--type", index_type];
+        args.extend(extra.iter());
+        stdout_lines(&nnsearch(&args));
+
+        let lines = stdout_lines(&nnsearch(&["search", output.to_str().unwrap(), query.to_str().unwrap(), "-k", "2"]));
+        let rows: Vec<Vec<&str>> = lines.iter().map(|line| line.split('\t').collect()).collect();
+        assert!(rows.iter().all(|row| row.len() == 4), "{}: {:?}", index_type, lines);
+        // the nearest neighbor of each query comes first
+        assert_eq!(&rows[0][..3], &["0", "0", "1"], "{}: {:?}", index_type, lines);
+        assert!(rows.iter().any(|row| row[..3] == ["1", "0", "3"]), "{}: {:?}", index_type, lines);
+
+        let lines = stdout_lines(&nnsearch(&["search", output.to_str().unwrap(), query.to_str().unwrap(), "--radius", "0.2", "--format", "json"]));
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"query\": 0, \"ids\": [1], \"distances\": ["), "{}: {:?}", index_type, lines);
+        assert_eq!(lines[1], "{\"query\": 1, \"ids\": [], \"distances\": []}");
+    }
+}
+
+#[test]
+fn test_search_json_with_non_finite_distances() {
+    let dir = TempDir::new("non_finite");
+    let input = dir.path("input.txt");
+    let output = dir.path("index.bin");
+    // the inner products of these vectors overflow to infinity or NaN
+    fs::write(&input, "1e30 1e30\n-1e30 1e30\n").unwrap();
+    stdout_lines(&nnsearch(&["index", input.to_str().unwrap(), output.to_str().unwrap(), "--type", "naive", "--distance", "ip"]));
+    let lines = stdout_lines(&nnsearch(&["search", output.to_str().unwrap(), input.to_str().unwrap(), "-k", "2", "--format", "json"]));
+    assert_eq!(lines[0], "{\"query\": 0, \"ids\": [0, 1], \"distances\": [null, null]}");
+    assert!(lines.iter().all(|line| !line.contains("inf") && !line.contains("NaN")));
+}
+
+#[test]
+fn test_invalid_arguments() {
+    let dir = TempDir::new("invalid_arguments");
+    let input = dir.path("input.txt");
+    let output = dir.path("index.bin");
+    fs::write(&input, "0.0 0.0\n1.0\n").unwrap();
+    let result = nnsearch(&["index", input.to_str().unwrap(), output.to_str().unwrap()]);
+    assert!(!result.status.success());
+    assert!(String::from_utf8_lossy(&result.stderr).contains("Inconsistent length"));
+    let result = nnsearch(&["search", output.to_str().unwrap(), input.to_str().unwrap()]);
+    assert!(!result.status.success());
+}
+
+#[test]
+fn test_unused_index_options() {
+    let dir = TempDir::new("unused_index_options");
+    let input = dir.path("input.txt");
+    let output = dir.path("index.bin");
+    fs::write(&input, "0.0 0.0\n1.0 0.0\n").unwrap();
+    let cases: &[(&str, &[&str], &str)] = &[
+        ("naive", &["--trial", "5"], "--trial is not used by the naive index"),
+        ("ivf", &["--min-degree", "8"], "--min-degree is not used by the ivf index"),
+        ("lsh", &["--quantizer", "int8"], "--quantizer is not used by the lsh index"),
+        ("ivf", &["--rerank", "10"], "--rerank is not used by the ivf index"),
+        ("nsw", &["--rerank", "10"], "--rerank is used only with --quantizer by the nsw index"),
+        ("naive", &["--mmap"], "--mmap is not used by the naive index"),
+    ];
+    for (index_type, extra, message) in cases {
+        let mut args = vec!["index", input.to_str().unwrap(), output.to_str().unwrap(), "--type", index_type];
+        args.extend(extra.iter());
+        let result = nnsearch(&args);
+        assert!(!result.status.success(), "{} {:?}", index_type, extra);
+        assert!(String::from_utf8_lossy(&result.stderr).contains(message), "{}", String::from_utf8_lossy(&result.stderr));
+        assert!(!output.exists());
+    }
+    // the options used by the index type are accepted
+    stdout_lines(&nnsearch(&["index", input.to_str().unwrap(), output.to_str().unwrap(), "--type", "nsw", "--trial", "2", "--quantizer", "int8", "--rerank", "2", "--mmap"]));
+}